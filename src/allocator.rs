@@ -0,0 +1,529 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{AlreadyExists, InvalidInput, NotFound, Other};
+
+use {Device, TargetLine};
+use types::Sectors;
+
+/// How `Allocator::allocate()` may place a volume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// In one extent, on one device.
+    Contiguous,
+    /// In as many extents, on as many devices, as needed.
+    Anywhere,
+    /// Striped across several devices, in one extent on each.
+    Striped {
+        /// The number of devices to stripe across.
+        stripes: usize,
+        /// The size of each chunk written to a stripe before moving on
+        /// to the next.
+        chunk_size: Sectors,
+    },
+}
+
+/// A contiguous part of an allocated volume.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// Mapped onto one device.
+    Linear {
+        /// The device.
+        device: Device,
+        /// Where on the device the segment starts.
+        start: Sectors,
+        /// The segment's length.
+        length: Sectors,
+    },
+    /// Striped across devices, an equal part on each.
+    Striped {
+        /// The chunk size.
+        chunk_size: Sectors,
+        /// Each stripe's device and where on it the stripe starts.
+        stripes: Vec<(Device, Sectors)>,
+        /// The segment's total length, over all stripes.
+        length: Sectors,
+    },
+}
+
+impl Segment {
+    /// The segment's length.
+    pub fn length(&self) -> Sectors {
+        match *self {
+            Segment::Linear { length, .. } |
+            Segment::Striped { length, .. } => length,
+        }
+    }
+
+    /// The extents the segment uses, as (device, start, length).
+    pub fn extents(&self) -> Vec<(Device, Sectors, Sectors)> {
+        match *self {
+            Segment::Linear { device, start, length } => vec![(device, start, length)],
+            Segment::Striped { ref stripes, length, .. } => {
+                let per_stripe = Sectors(*length / stripes.len() as u64);
+                stripes.iter().map(|&(dev, start)| (dev, start, per_stripe)).collect()
+            }
+        }
+    }
+
+    fn target(&self) -> (&'static str, String) {
+        match *self {
+            Segment::Linear { device, start, .. } => {
+                ("linear", format!("{} {}", device.dstr(), *start))
+            }
+            Segment::Striped { chunk_size, ref stripes, .. } => {
+                let mut params = format!("{} {}", stripes.len(), *chunk_size);
+                for &(dev, start) in stripes {
+                    params.push_str(&format!(" {} {}", dev.dstr(), *start));
+                }
+                ("striped", params)
+            }
+        }
+    }
+}
+
+/// Space allocated for a volume: its segments, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    /// The volume's segments.
+    pub segments: Vec<Segment>,
+}
+
+impl Allocation {
+    /// The volume's size.
+    pub fn size(&self) -> Sectors {
+        Sectors(self.segments.iter().map(|s| *s.length()).sum())
+    }
+
+    /// A table mapping the volume, for `DM::table_load()`.
+    pub fn table(&self) -> Vec<TargetLine> {
+        let mut next = 0;
+        self.segments
+            .iter()
+            .map(|s| {
+                let (target_type, params) = s.target();
+                let line = (next, *s.length(), target_type.to_owned(), params);
+                next += *s.length();
+                line
+            })
+            .collect()
+    }
+}
+
+// A backing device's size and used extents, as (start, length),
+// sorted and not overlapping.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Space {
+    size: u64,
+    used: Vec<(u64, u64)>,
+}
+
+impl Space {
+    // Free extents, as (start, length), with each start rounded up to
+    // `align`.
+    fn free(&self, align: u64) -> Vec<(u64, u64)> {
+        let mut free = Vec::new();
+        let mut pos = 0u64;
+        for &(start, len) in self.used.iter().chain(Some(&(self.size, 0))) {
+            let aligned = pos.div_ceil(align) * align;
+            if aligned < start {
+                free.push((aligned, start - aligned));
+            }
+            pos = start + len;
+        }
+        free
+    }
+
+    fn insert(&mut self, start: u64, len: u64) -> io::Result<()> {
+        if start + len > self.size {
+            return Err(Error::new(InvalidInput,
+                                  format!("extent {}+{} is past the end of the device",
+                                          start,
+                                          len)));
+        }
+        if self.used.iter().any(|&(s, l)| start < s + l && s < start + len) {
+            return Err(Error::new(AlreadyExists,
+                                  format!("extent {}+{} overlaps a used extent", start, len)));
+        }
+        let pos = self.used.iter().position(|&(s, _)| s > start).unwrap_or(self.used.len());
+        self.used.insert(pos, (start, len));
+        Ok(())
+    }
+
+    fn remove(&mut self, start: u64, len: u64) -> io::Result<()> {
+        match self.used.iter().position(|&e| e == (start, len)) {
+            Some(pos) => {
+                self.used.remove(pos);
+                Ok(())
+            }
+            None => {
+                Err(Error::new(NotFound,
+                               format!("extent {}+{} is not allocated", start, len)))
+            }
+        }
+    }
+}
+
+/// Tracks the free and used space on a set of backing devices, and
+/// allocates space from them for linear and striped volumes.
+///
+/// Allocated extents start on multiples of the allocator's alignment,
+/// counted from the start of each device. Nothing is read from or
+/// written to the devices; existing volumes can be accounted for with
+/// `mark_used()`.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::allocator::{Allocator, Policy};
+/// use devicemapper::types::Sectors;
+///
+/// let sdb = Device { major: 8, minor: 16 };
+/// let sdc = Device { major: 8, minor: 32 };
+///
+/// let mut alloc = Allocator::new(Sectors(2048));
+/// alloc.add_device(sdb, Sectors(1 << 20)).unwrap();
+/// alloc.add_device(sdc, Sectors(1 << 20)).unwrap();
+///
+/// let lv = alloc.allocate(Sectors(4096), Policy::Contiguous).unwrap();
+/// assert_eq!(lv.table(), vec![(0, 4096, "linear".to_owned(), "8:16 0".to_owned())]);
+///
+/// let striped = Policy::Striped { stripes: 2, chunk_size: Sectors(128) };
+/// let lv2 = alloc.allocate(Sectors(8192), striped).unwrap();
+/// assert_eq!(lv2.table()[0].3, "2 128 8:16 4096 8:32 0");
+///
+/// alloc.release(&lv).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocator {
+    alignment: u64,
+    devices: BTreeMap<Device, Space>,
+}
+
+impl Allocator {
+    /// Create an allocator with no devices, which starts extents on
+    /// multiples of `alignment`.
+    pub fn new(alignment: Sectors) -> Allocator {
+        Allocator {
+            alignment: if *alignment == 0 { 1 } else { *alignment },
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Add a backing device with `size` sectors, all free.
+    pub fn add_device(&mut self, device: Device, size: Sectors) -> io::Result<()> {
+        if self.devices.contains_key(&device) {
+            return Err(Error::new(AlreadyExists,
+                                  format!("device {} already added", device.dstr())));
+        }
+        self.devices.insert(device,
+                            Space {
+                                size: *size,
+                                used: Vec::new(),
+                            });
+        Ok(())
+    }
+
+    /// Stop using a backing device. Fails if any of it is allocated.
+    pub fn remove_device(&mut self, device: Device) -> io::Result<()> {
+        match self.devices.get(&device).map(|s| s.used.is_empty()) {
+            None => Err(Error::new(NotFound, format!("unknown device {}", device.dstr()))),
+            Some(false) => {
+                Err(Error::new(Other, format!("device {} is in use", device.dstr())))
+            }
+            Some(true) => {
+                self.devices.remove(&device);
+                Ok(())
+            }
+        }
+    }
+
+    fn space(&mut self, device: Device) -> io::Result<&mut Space> {
+        self.devices
+            .get_mut(&device)
+            .ok_or_else(|| Error::new(NotFound, format!("unknown device {}", device.dstr())))
+    }
+
+    /// Record that an extent is already in use, e.g. by an existing
+    /// volume.
+    pub fn mark_used(&mut self, device: Device, start: Sectors, length: Sectors) -> io::Result<()> {
+        try!(self.space(device)).insert(*start, *length)
+    }
+
+    /// The backing devices.
+    pub fn devices(&self) -> Vec<Device> {
+        self.devices.keys().cloned().collect()
+    }
+
+    /// The used extents of a device, as (start, length).
+    pub fn used_extents(&self, device: Device) -> Vec<(Sectors, Sectors)> {
+        self.devices
+            .get(&device)
+            .map_or_else(Vec::new,
+                         |s| s.used.iter().map(|&(st, l)| (Sectors(st), Sectors(l))).collect())
+    }
+
+    /// The free extents of a device that could be allocated, as
+    /// (start, length). Starts are aligned.
+    pub fn free_extents(&self, device: Device) -> Vec<(Sectors, Sectors)> {
+        self.devices
+            .get(&device)
+            .map_or_else(Vec::new, |s| {
+                s.free(self.alignment).into_iter().map(|(st, l)| (Sectors(st), Sectors(l))).collect()
+            })
+    }
+
+    /// The total allocatable space on all devices.
+    pub fn free_space(&self) -> Sectors {
+        Sectors(self.devices
+            .values()
+            .flat_map(|s| s.free(self.alignment))
+            .map(|(_, l)| l)
+            .sum())
+    }
+
+    /// Allocate `length` sectors according to `policy`, and mark the
+    /// space used.
+    ///
+    /// Contiguous and striped allocations use the smallest free
+    /// extents that fit. A striped allocation's length must be a whole
+    /// number of chunks on each stripe, and each stripe is on a
+    /// different device.
+    pub fn allocate(&mut self, length: Sectors, policy: Policy) -> io::Result<Allocation> {
+        if *length == 0 {
+            return Err(Error::new(InvalidInput, "cannot allocate zero sectors"));
+        }
+
+        let no_space = || {
+            Error::new(Other,
+                       format!("not enough free space for {} sectors with policy {:?}",
+                               *length,
+                               policy))
+        };
+
+        let segments = match policy {
+            Policy::Contiguous => {
+                let (device, start) = try!(self.best_fit(*length, &[]).ok_or_else(&no_space));
+                vec![Segment::Linear {
+                         device: device,
+                         start: Sectors(start),
+                         length: length,
+                     }]
+            }
+            Policy::Anywhere => {
+                if self.free_space() < length {
+                    return Err(no_space());
+                }
+                let mut segments = Vec::new();
+                let mut left = *length;
+                for (device, space) in &self.devices {
+                    for (start, len) in space.free(self.alignment) {
+                        if left == 0 {
+                            break;
+                        }
+                        let take = ::std::cmp::min(len, left);
+                        segments.push(Segment::Linear {
+                            device: *device,
+                            start: Sectors(start),
+                            length: Sectors(take),
+                        });
+                        left -= take;
+                    }
+                }
+                segments
+            }
+            Policy::Striped { stripes, chunk_size } => {
+                if stripes == 0 || *chunk_size == 0 {
+                    return Err(Error::new(InvalidInput,
+                                          "striped allocation needs stripes and a chunk size"));
+                }
+                let unit = stripes as u64 * *chunk_size;
+                if !length.is_multiple_of(unit) {
+                    return Err(Error::new(InvalidInput,
+                                          format!("length {} is not a multiple of {} stripes \
+                                                   of {} sector chunks",
+                                                  *length,
+                                                  stripes,
+                                                  *chunk_size)));
+                }
+
+                let per_stripe = *length / stripes as u64;
+                let mut chosen: Vec<(Device, Sectors)> = Vec::new();
+                for _ in 0..stripes {
+                    let exclude: Vec<_> = chosen.iter().map(|c| c.0).collect();
+                    let (device, start) =
+                        try!(self.best_fit(per_stripe, &exclude).ok_or_else(&no_space));
+                    chosen.push((device, Sectors(start)));
+                }
+                vec![Segment::Striped {
+                         chunk_size: chunk_size,
+                         stripes: chosen,
+                         length: length,
+                     }]
+            }
+        };
+
+        for seg in &segments {
+            for (device, start, len) in seg.extents() {
+                try!(self.mark_used(device, start, len));
+            }
+        }
+
+        Ok(Allocation { segments: segments })
+    }
+
+    // The smallest free extent, on a device not in `exclude`, that
+    // holds `length` sectors.
+    fn best_fit(&self, length: u64, exclude: &[Device]) -> Option<(Device, u64)> {
+        self.devices
+            .iter()
+            .filter(|&(dev, _)| !exclude.contains(dev))
+            .flat_map(|(dev, space)| {
+                space.free(self.alignment).into_iter().map(move |(s, l)| (*dev, s, l))
+            })
+            .filter(|&(_, _, l)| l >= length)
+            .min_by_key(|&(_, _, l)| l)
+            .map(|(dev, s, _)| (dev, s))
+    }
+
+    /// Return an allocation's space to the free pool.
+    pub fn release(&mut self, allocation: &Allocation) -> io::Result<()> {
+        for seg in &allocation.segments {
+            for (device, start, len) in seg.extents() {
+                try!(try!(self.space(device)).remove(*start, *len));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dev(minor: u8) -> Device {
+        Device {
+            major: 8,
+            minor: minor,
+        }
+    }
+
+    fn linear(device: Device, start: u64, length: u64) -> Segment {
+        Segment::Linear {
+            device: device,
+            start: Sectors(start),
+            length: Sectors(length),
+        }
+    }
+
+    #[test]
+    fn alignment() {
+        let mut alloc = Allocator::new(Sectors(8));
+        alloc.add_device(dev(16), Sectors(100)).unwrap();
+        alloc.mark_used(dev(16), Sectors(0), Sectors(3)).unwrap();
+        alloc.mark_used(dev(16), Sectors(20), Sectors(10)).unwrap();
+
+        // The free space after each used extent starts at the next
+        // multiple of 8, and the tail is cut at the device's end.
+        assert_eq!(alloc.free_extents(dev(16)),
+                   vec![(Sectors(8), Sectors(12)), (Sectors(32), Sectors(68))]);
+        assert_eq!(alloc.free_space(), Sectors(80));
+
+        let lv = alloc.allocate(Sectors(5), Policy::Contiguous).unwrap();
+        assert_eq!(lv.segments, vec![linear(dev(16), 8, 5)]);
+        assert_eq!(alloc.free_extents(dev(16))[0], (Sectors(16), Sectors(4)));
+
+        // An alignment of 0 means no alignment.
+        let mut alloc = Allocator::new(Sectors(0));
+        alloc.add_device(dev(16), Sectors(10)).unwrap();
+        alloc.mark_used(dev(16), Sectors(0), Sectors(3)).unwrap();
+        assert_eq!(alloc.free_extents(dev(16)), vec![(Sectors(3), Sectors(7))]);
+    }
+
+    #[test]
+    fn best_fit() {
+        let mut alloc = Allocator::new(Sectors(1));
+        alloc.add_device(dev(16), Sectors(100)).unwrap();
+        alloc.add_device(dev(32), Sectors(30)).unwrap();
+        alloc.mark_used(dev(16), Sectors(10), Sectors(20)).unwrap();
+
+        // Free: 8:16 has 0+10 and 30+70, 8:32 has 0+30.
+        let lv = alloc.allocate(Sectors(10), Policy::Contiguous).unwrap();
+        assert_eq!(lv.segments, vec![linear(dev(16), 0, 10)]);
+
+        let lv = alloc.allocate(Sectors(25), Policy::Contiguous).unwrap();
+        assert_eq!(lv.segments, vec![linear(dev(32), 0, 25)]);
+
+        let lv = alloc.allocate(Sectors(6), Policy::Contiguous).unwrap();
+        assert_eq!(lv.segments, vec![linear(dev(16), 30, 6)]);
+
+        assert!(alloc.allocate(Sectors(65), Policy::Contiguous).is_err());
+        assert!(alloc.allocate(Sectors(0), Policy::Contiguous).is_err());
+    }
+
+    #[test]
+    fn fragmentation() {
+        let mut alloc = Allocator::new(Sectors(1));
+        alloc.add_device(dev(16), Sectors(30)).unwrap();
+        alloc.add_device(dev(32), Sectors(10)).unwrap();
+        alloc.mark_used(dev(16), Sectors(10), Sectors(10)).unwrap();
+
+        // 30 sectors are free, but in pieces of 10.
+        assert_eq!(alloc.free_space(), Sectors(30));
+        assert!(alloc.allocate(Sectors(15), Policy::Contiguous).is_err());
+
+        let lv = alloc.allocate(Sectors(25), Policy::Anywhere).unwrap();
+        assert_eq!(lv.segments,
+                   vec![linear(dev(16), 0, 10), linear(dev(16), 20, 10), linear(dev(32), 0, 5)]);
+        assert_eq!(lv.table()[2], (20, 5, "linear".to_owned(), "8:32 0".to_owned()));
+        assert_eq!(alloc.free_space(), Sectors(5));
+        assert!(alloc.allocate(Sectors(6), Policy::Anywhere).is_err());
+
+        // Releasing the volume frees every fragment.
+        alloc.release(&lv).unwrap();
+        assert_eq!(alloc.free_space(), Sectors(30));
+        assert!(alloc.release(&lv).is_err());
+    }
+
+    #[test]
+    fn striped_shortfall() {
+        let striped = |stripes| {
+            Policy::Striped {
+                stripes: stripes,
+                chunk_size: Sectors(4),
+            }
+        };
+
+        let mut alloc = Allocator::new(Sectors(1));
+        alloc.add_device(dev(16), Sectors(100)).unwrap();
+        alloc.add_device(dev(32), Sectors(100)).unwrap();
+        alloc.add_device(dev(48), Sectors(20)).unwrap();
+
+        // Three stripes of 32 don't fit: only two devices have room,
+        // and stripes may not share a device.
+        assert!(alloc.allocate(Sectors(96), striped(3)).is_err());
+        assert_eq!(alloc.free_space(), Sectors(220));
+        assert!(alloc.used_extents(dev(16)).is_empty());
+
+        // Not a whole number of chunks per stripe.
+        assert!(alloc.allocate(Sectors(20), striped(2)).is_err());
+        assert!(alloc.allocate(Sectors(8), striped(0)).is_err());
+
+        let lv = alloc.allocate(Sectors(48), striped(3)).unwrap();
+        assert_eq!(lv.segments,
+                   vec![Segment::Striped {
+                            chunk_size: Sectors(4),
+                            stripes: vec![(dev(48), Sectors(0)),
+                                          (dev(16), Sectors(0)),
+                                          (dev(32), Sectors(0))],
+                            length: Sectors(48),
+                        }]);
+        assert_eq!(lv.table()[0].3, "3 4 8:48 0 8:16 0 8:32 0");
+        assert_eq!(alloc.free_extents(dev(48)), vec![(Sectors(16), Sectors(4))]);
+    }
+}
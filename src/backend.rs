@@ -0,0 +1,408 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+
+use dm_ioctl as dmi;
+use {DM, DevId, Device, DeviceInfo, DmFlags, TargetLine};
+use {DM_ACTIVE_PRESENT, DM_INACTIVE_PRESENT, DM_QUERY_INACTIVE_TABLE, DM_READONLY,
+     DM_STATUS_TABLE, DM_SUSPEND, DM_UUID};
+use table;
+
+/// The device operations of `DM`, so code can run against either the
+/// kernel or a `SimBackend`.
+///
+/// Each method behaves as the `DM` method of the same name.
+pub trait Backend {
+    /// See `DM::list_devices()`.
+    fn list_devices(&self) -> io::Result<Vec<(String, Device)>>;
+    /// See `DM::device_create()`.
+    fn device_create(&self, name: &str, uuid: Option<&str>, flags: DmFlags)
+                     -> io::Result<DeviceInfo>;
+    /// See `DM::device_remove()`.
+    fn device_remove(&self, name: &DevId, flags: DmFlags) -> io::Result<DeviceInfo>;
+    /// See `DM::device_rename()`.
+    fn device_rename(&self, old_name: &str, new_name: &str, flags: DmFlags)
+                     -> io::Result<DeviceInfo>;
+    /// See `DM::device_suspend()`.
+    fn device_suspend(&self, name: &DevId, flags: DmFlags) -> io::Result<DeviceInfo>;
+    /// See `DM::device_status()`.
+    fn device_status(&self, name: &DevId) -> io::Result<DeviceInfo>;
+    /// See `DM::table_load()`.
+    fn table_load(&self, name: &DevId, targets: &[TargetLine]) -> io::Result<DeviceInfo>;
+    /// See `DM::table_clear()`.
+    fn table_clear(&self, name: &DevId) -> io::Result<DeviceInfo>;
+    /// See `DM::table_deps()`.
+    fn table_deps(&self, dev: Device, flags: DmFlags) -> io::Result<Vec<Device>>;
+    /// See `DM::table_status()`.
+    fn table_status(&self, name: &DevId, flags: DmFlags)
+                    -> io::Result<(DeviceInfo, Vec<TargetLine>)>;
+}
+
+impl Backend for DM {
+    fn list_devices(&self) -> io::Result<Vec<(String, Device)>> {
+        DM::list_devices(self)
+    }
+
+    fn device_create(&self, name: &str, uuid: Option<&str>, flags: DmFlags)
+                     -> io::Result<DeviceInfo> {
+        DM::device_create(self, name, uuid, flags)
+    }
+
+    fn device_remove(&self, name: &DevId, flags: DmFlags) -> io::Result<DeviceInfo> {
+        DM::device_remove(self, name, flags)
+    }
+
+    fn device_rename(&self, old_name: &str, new_name: &str, flags: DmFlags)
+                     -> io::Result<DeviceInfo> {
+        DM::device_rename(self, old_name, new_name, flags)
+    }
+
+    fn device_suspend(&self, name: &DevId, flags: DmFlags) -> io::Result<DeviceInfo> {
+        DM::device_suspend(self, name, flags)
+    }
+
+    fn device_status(&self, name: &DevId) -> io::Result<DeviceInfo> {
+        DM::device_status(self, name)
+    }
+
+    fn table_load(&self, name: &DevId, targets: &[TargetLine]) -> io::Result<DeviceInfo> {
+        DM::table_load(self, name, targets)
+    }
+
+    fn table_clear(&self, name: &DevId) -> io::Result<DeviceInfo> {
+        DM::table_clear(self, name)
+    }
+
+    fn table_deps(&self, dev: Device, flags: DmFlags) -> io::Result<Vec<Device>> {
+        DM::table_deps(self, dev, flags)
+    }
+
+    fn table_status(&self, name: &DevId, flags: DmFlags)
+                    -> io::Result<(DeviceInfo, Vec<TargetLine>)> {
+        DM::table_status(self, name, flags)
+    }
+}
+
+// The major number SimBackend gives its devices.
+const SIM_MAJOR: u32 = 253;
+
+#[derive(Debug, Clone)]
+struct SimDevice {
+    name: String,
+    uuid: String,
+    device: Device,
+    read_only: bool,
+    suspended: bool,
+    event_nr: u32,
+    active: Option<Vec<TargetLine>>,
+    inactive: Option<Vec<TargetLine>>,
+}
+
+/// An in-memory stand-in for the kernel's device-mapper, for trying
+/// out changes and reproducing problems without touching real devices.
+///
+/// Devices get major number 253 and the lowest free minor. Tables are
+/// checked with `table::validate()` and stored with "/dev/mapper/<name>"
+/// paths of simulated devices replaced by "<major>:<minor>", as the
+/// kernel would. Target params are not otherwise interpreted, and
+/// `table_status()` without DM_STATUS_TABLE reports empty status
+/// params.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::{DevId, DmFlags, DM_SUSPEND};
+/// use devicemapper::backend::{Backend, SimBackend};
+///
+/// let sim = SimBackend::new();
+/// let id = DevId::Name("example-dev");
+///
+/// sim.device_create("example-dev", None, DmFlags::empty()).unwrap();
+/// sim.table_load(&id, &[(0, 2048, "linear".to_owned(), "8:16 0".to_owned())]).unwrap();
+/// sim.device_suspend(&id, DmFlags::empty()).unwrap();
+///
+/// let info = sim.device_status(&id).unwrap();
+/// assert!(!info.flags().contains(DM_SUSPEND));
+/// assert_eq!(sim.table_deps(info.device(), DmFlags::empty()).unwrap().len(), 1);
+/// ```
+#[derive(Debug, Default)]
+pub struct SimBackend {
+    devices: RefCell<BTreeMap<Device, SimDevice>>,
+}
+
+fn no_device() -> Error {
+    Error::from_raw_os_error(::libc::ENXIO)
+}
+
+impl SimBackend {
+    /// Create a backend with no devices.
+    pub fn new() -> SimBackend {
+        SimBackend { devices: RefCell::new(BTreeMap::new()) }
+    }
+
+    fn find(&self, id: &DevId) -> io::Result<Device> {
+        self.devices
+            .borrow()
+            .values()
+            .find(|d| match *id {
+                DevId::Name(name) => d.name == name,
+                DevId::Uuid(uuid) => !d.uuid.is_empty() && d.uuid == uuid,
+            })
+            .map(|d| d.device)
+            .ok_or_else(no_device)
+    }
+
+    fn with_device<F, T>(&self, id: &DevId, f: F) -> io::Result<T>
+        where F: FnOnce(&mut SimDevice) -> io::Result<T>
+    {
+        let dev = try!(self.find(id));
+        let mut devices = self.devices.borrow_mut();
+        f(devices.get_mut(&dev).expect("found above"))
+    }
+
+    fn info(&self, dev: Device) -> DeviceInfo {
+        let devices = self.devices.borrow();
+        let d = &devices[&dev];
+
+        let mut flags = DmFlags::empty();
+        if d.read_only {
+            flags = flags | DM_READONLY;
+        }
+        if d.suspended {
+            flags = flags | DM_SUSPEND;
+        }
+        if d.active.is_some() {
+            flags = flags | DM_ACTIVE_PRESENT;
+        }
+        if d.inactive.is_some() {
+            flags = flags | DM_INACTIVE_PRESENT;
+        }
+
+        let open_count = devices.values()
+            .filter(|other| {
+                other.active.iter().chain(other.inactive.iter()).any(|t| {
+                    self.deps_of(&devices, t).contains(&dev)
+                })
+            })
+            .count();
+
+        let mut hdr: dmi::Struct_dm_ioctl = Default::default();
+        DM::initialize_hdr(&mut hdr, flags);
+        DM::hdr_set_name(&mut hdr, &d.name);
+        DM::hdr_set_uuid(&mut hdr, &d.uuid);
+        hdr.dev = d.device.into();
+        hdr.open_count = open_count as i32;
+        hdr.event_nr = d.event_nr;
+        DeviceInfo { hdr: hdr }
+    }
+
+    // The devices a table refers to, in the order first referred to.
+    fn deps_of(&self, devices: &BTreeMap<Device, SimDevice>, targets: &[TargetLine])
+               -> Vec<Device> {
+        let mut deps = Vec::new();
+        for t in targets {
+            for token in t.3.split_whitespace() {
+                let dev = if token.starts_with('/') {
+                    let name = token.trim_start_matches("/dev/mapper/");
+                    devices.values().find(|d| d.name == name).map(|d| d.device)
+                } else if token.split(':').count() == 2 {
+                    token.parse::<Device>().ok()
+                } else {
+                    None
+                };
+                if let Some(dev) = dev {
+                    if !deps.contains(&dev) {
+                        deps.push(dev);
+                    }
+                }
+            }
+        }
+        deps
+    }
+}
+
+impl Backend for SimBackend {
+    fn list_devices(&self) -> io::Result<Vec<(String, Device)>> {
+        Ok(self.devices.borrow().values().map(|d| (d.name.clone(), d.device)).collect())
+    }
+
+    fn device_create(&self, name: &str, uuid: Option<&str>, flags: DmFlags)
+                     -> io::Result<DeviceInfo> {
+        let uuid = uuid.unwrap_or("");
+        if name.is_empty() {
+            return Err(Error::new(InvalidInput, "device name is empty"));
+        }
+        if self.find(&DevId::Name(name)).is_ok() ||
+           (!uuid.is_empty() && self.find(&DevId::Uuid(uuid)).is_ok()) {
+            return Err(Error::from_raw_os_error(::libc::EBUSY));
+        }
+
+        let dev = {
+            let devices = self.devices.borrow();
+            let minor = try!((0..256u32)
+                .find(|m| {
+                    !devices.contains_key(&Device {
+                        major: SIM_MAJOR,
+                        minor: *m as u8,
+                    })
+                })
+                .ok_or_else(|| Error::from_raw_os_error(::libc::ENXIO)));
+            Device {
+                major: SIM_MAJOR,
+                minor: minor as u8,
+            }
+        };
+
+        self.devices.borrow_mut().insert(dev,
+                                         SimDevice {
+                                             name: name.to_owned(),
+                                             uuid: uuid.to_owned(),
+                                             device: dev,
+                                             read_only: flags.contains(DM_READONLY),
+                                             suspended: true,
+                                             event_nr: 0,
+                                             active: None,
+                                             inactive: None,
+                                         });
+        Ok(self.info(dev))
+    }
+
+    fn device_remove(&self, name: &DevId, _flags: DmFlags) -> io::Result<DeviceInfo> {
+        let dev = try!(self.find(name));
+        let info = self.info(dev);
+        if info.open_count() > 0 {
+            return Err(Error::from_raw_os_error(::libc::EBUSY));
+        }
+        self.devices.borrow_mut().remove(&dev);
+        Ok(info)
+    }
+
+    fn device_rename(&self, old_name: &str, new_name: &str, flags: DmFlags)
+                     -> io::Result<DeviceInfo> {
+        let (old, new) = if flags.contains(DM_UUID) {
+            (DevId::Uuid(old_name), DevId::Uuid(new_name))
+        } else {
+            (DevId::Name(old_name), DevId::Name(new_name))
+        };
+        if self.find(&new).is_ok() {
+            return Err(Error::from_raw_os_error(::libc::EBUSY));
+        }
+
+        let dev = try!(self.with_device(&old, |d| {
+            if flags.contains(DM_UUID) {
+                if !d.uuid.is_empty() {
+                    return Err(Error::new(InvalidInput, "device already has a UUID"));
+                }
+                d.uuid = new_name.to_owned();
+            } else {
+                d.name = new_name.to_owned();
+            }
+            Ok(d.device)
+        }));
+        Ok(self.info(dev))
+    }
+
+    fn device_suspend(&self, name: &DevId, flags: DmFlags) -> io::Result<DeviceInfo> {
+        let dev = try!(self.with_device(name, |d| {
+            if flags.contains(DM_SUSPEND) {
+                d.suspended = true;
+            } else {
+                if d.inactive.is_some() {
+                    d.active = d.inactive.take();
+                    d.event_nr += 1;
+                }
+                d.suspended = false;
+            }
+            Ok(d.device)
+        }));
+        Ok(self.info(dev))
+    }
+
+    fn device_status(&self, name: &DevId) -> io::Result<DeviceInfo> {
+        let dev = try!(self.find(name));
+        Ok(self.info(dev))
+    }
+
+    fn table_load(&self, name: &DevId, targets: &[TargetLine]) -> io::Result<DeviceInfo> {
+        try!(table::validate(targets));
+
+        let dev = try!(self.find(name));
+        let stored: Vec<TargetLine> = {
+            let devices = self.devices.borrow();
+            targets.iter()
+                .map(|t| {
+                    let params = t.3
+                        .split_whitespace()
+                        .map(|token| {
+                            let name = token.trim_start_matches("/dev/mapper/");
+                            match devices.values().find(|d| d.name == name) {
+                                Some(d) if token.starts_with("/dev/mapper/") => d.device.dstr(),
+                                _ => token.to_owned(),
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    (t.0, t.1, t.2.clone(), params)
+                })
+                .collect()
+        };
+
+        if self.deps_of(&self.devices.borrow(), &stored).contains(&dev) {
+            return Err(Error::new(InvalidInput, "table refers to its own device"));
+        }
+
+        try!(self.with_device(name, |d| {
+            d.inactive = Some(stored);
+            Ok(())
+        }));
+        Ok(self.info(dev))
+    }
+
+    fn table_clear(&self, name: &DevId) -> io::Result<DeviceInfo> {
+        let dev = try!(self.with_device(name, |d| {
+            d.inactive = None;
+            Ok(d.device)
+        }));
+        Ok(self.info(dev))
+    }
+
+    fn table_deps(&self, dev: Device, flags: DmFlags) -> io::Result<Vec<Device>> {
+        let devices = self.devices.borrow();
+        let d = try!(devices.get(&dev).ok_or_else(no_device));
+        let targets = if flags.contains(DM_QUERY_INACTIVE_TABLE) {
+            &d.inactive
+        } else {
+            &d.active
+        };
+        Ok(targets.as_ref().map_or_else(Vec::new, |t| self.deps_of(&devices, t)))
+    }
+
+    fn table_status(&self, name: &DevId, flags: DmFlags)
+                    -> io::Result<(DeviceInfo, Vec<TargetLine>)> {
+        let dev = try!(self.find(name));
+        let targets = {
+            let devices = self.devices.borrow();
+            let d = &devices[&dev];
+            let targets = if flags.contains(DM_QUERY_INACTIVE_TABLE) {
+                &d.inactive
+            } else {
+                &d.active
+            };
+            targets.clone().unwrap_or_default()
+        };
+
+        let targets = if flags.contains(DM_STATUS_TABLE) {
+            targets
+        } else {
+            targets.into_iter().map(|t| (t.0, t.1, t.2, String::new())).collect()
+        };
+
+        Ok((self.info(dev), targets))
+    }
+}
@@ -0,0 +1,206 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput, Other};
+use std::str::FromStr;
+
+use {DM, DevId, DmFlags};
+use types::Sectors;
+
+/// Whether a cache's metadata can be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMetadataMode {
+    /// Blocks can be promoted and demoted.
+    ReadWrite,
+    /// Metadata can no longer be changed.
+    ReadOnly,
+}
+
+/// Status of a working cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheWorkingStatus {
+    /// The metadata block size.
+    pub metadata_block_size: Sectors,
+    /// Metadata blocks in use.
+    pub used_metadata_blocks: u64,
+    /// Total metadata blocks.
+    pub total_metadata_blocks: u64,
+    /// The cache block size.
+    pub cache_block_size: Sectors,
+    /// Cache blocks holding data.
+    pub used_cache_blocks: u64,
+    /// Total cache blocks.
+    pub total_cache_blocks: u64,
+    /// Reads served from the cache.
+    pub read_hits: u64,
+    /// Reads sent to the origin.
+    pub read_misses: u64,
+    /// Writes to blocks in the cache.
+    pub write_hits: u64,
+    /// Writes to blocks not in the cache.
+    pub write_misses: u64,
+    /// Blocks moved out of the cache.
+    pub demotions: u64,
+    /// Blocks moved into the cache.
+    pub promotions: u64,
+    /// Cache blocks not yet written back to the origin.
+    pub dirty: u64,
+    /// The feature args, e.g. "writeback".
+    pub features: Vec<String>,
+    /// The core args, as (name, value).
+    pub core_args: Vec<(String, String)>,
+    /// The cache policy's name.
+    pub policy: String,
+    /// The policy's args.
+    pub policy_args: Vec<String>,
+    /// The metadata mode.
+    pub mode: CacheMetadataMode,
+    /// Whether the metadata has been flagged as needing a check.
+    pub needs_check: bool,
+}
+
+impl CacheWorkingStatus {
+    /// The fraction of reads served from the cache, from 0 to 1.
+    pub fn read_hit_ratio(&self) -> Option<f64> {
+        ratio(self.read_hits, self.read_hits + self.read_misses)
+    }
+
+    /// The fraction of writes to blocks in the cache, from 0 to 1.
+    pub fn write_hit_ratio(&self) -> Option<f64> {
+        ratio(self.write_hits, self.write_hits + self.write_misses)
+    }
+}
+
+fn ratio(part: u64, total: u64) -> Option<f64> {
+    if total == 0 {
+        None
+    } else {
+        Some(part as f64 / total as f64)
+    }
+}
+
+/// Status of a cache, as reported by its target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheStatus {
+    /// The cache is working.
+    Working(Box<CacheWorkingStatus>),
+    /// The cache has failed.
+    Fail,
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.parse::<u64>().map_err(|_| Error::new(InvalidData, format!("expected number, got \"{}\"", s)))
+}
+
+fn parse_fraction(s: &str) -> io::Result<(u64, u64)> {
+    let spl: Vec<_> = s.split('/').collect();
+    if spl.len() != 2 {
+        return Err(Error::new(InvalidData, format!("expected used/total, got \"{}\"", s)));
+    }
+    Ok((try!(parse_u64(spl[0])), try!(parse_u64(spl[1]))))
+}
+
+impl FromStr for CacheStatus {
+    type Err = Error;
+
+    /// Parse the params field of a cache target's status line.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use devicemapper::cache::CacheStatus;
+    ///
+    /// let status: CacheStatus = "8 72/4096 128 100/1000 30 10 5 5 0 100 2 1 writeback \
+    ///                            2 migration_threshold 2048 smq 0 rw -"
+    ///     .parse()
+    ///     .unwrap();
+    ///
+    /// match status {
+    ///     CacheStatus::Working(s) => assert_eq!(s.read_hit_ratio(), Some(0.75)),
+    ///     CacheStatus::Fail => panic!(),
+    /// }
+    /// ```
+    fn from_str(s: &str) -> io::Result<CacheStatus> {
+        let vals: Vec<_> = s.split_whitespace().collect();
+        let too_few = || Error::new(InvalidData, format!("too few fields in cache status \"{}\"", s));
+
+        match vals.first() {
+            Some(&"Fail") => return Ok(CacheStatus::Fail),
+            Some(&"Error") => return Err(Error::new(Other, "cache status unavailable")),
+            _ => {}
+        }
+
+        if vals.len() < 12 {
+            return Err(too_few());
+        }
+
+        let mut c = [0u64; 7];
+        for (i, v) in vals[4..11].iter().enumerate() {
+            c[i] = try!(parse_u64(v));
+        }
+
+        // Each of the features, core args and policy args is a count
+        // followed by that many words.
+        let mut pos = 11;
+        let words = |pos: &mut usize| -> io::Result<Vec<String>> {
+            let n = try!(vals.get(*pos).ok_or_else(&too_few).and_then(|n| parse_u64(n))) as usize;
+            let words = try!(vals.get(*pos + 1..*pos + 1 + n).ok_or_else(&too_few));
+            *pos += 1 + n;
+            Ok(words.iter().map(|w| (*w).to_owned()).collect())
+        };
+
+        let features = try!(words(&mut pos));
+        let core = try!(words(&mut pos));
+        let policy = try!(vals.get(pos).ok_or_else(&too_few)).to_string();
+        pos += 1;
+        let policy_args = try!(words(&mut pos));
+
+        let mode = match vals.get(pos) {
+            Some(&"rw") => CacheMetadataMode::ReadWrite,
+            Some(&"ro") => CacheMetadataMode::ReadOnly,
+            Some(x) => {
+                return Err(Error::new(InvalidData, format!("unknown cache metadata mode \"{}\"", x)))
+            }
+            None => return Err(too_few()),
+        };
+
+        let (used_meta, total_meta) = try!(parse_fraction(vals[1]));
+        let (used_cache, total_cache) = try!(parse_fraction(vals[3]));
+
+        Ok(CacheStatus::Working(Box::new(CacheWorkingStatus {
+            metadata_block_size: Sectors(try!(parse_u64(vals[0]))),
+            used_metadata_blocks: used_meta,
+            total_metadata_blocks: total_meta,
+            cache_block_size: Sectors(try!(parse_u64(vals[2]))),
+            used_cache_blocks: used_cache,
+            total_cache_blocks: total_cache,
+            read_hits: c[0],
+            read_misses: c[1],
+            write_hits: c[2],
+            write_misses: c[3],
+            demotions: c[4],
+            promotions: c[5],
+            dirty: c[6],
+            features: features,
+            core_args: core.chunks(2)
+                .map(|p| (p[0].clone(), p.get(1).cloned().unwrap_or_default()))
+                .collect(),
+            policy: policy,
+            policy_args: policy_args,
+            mode: mode,
+            needs_check: vals.get(pos + 1) == Some(&"needs_check"),
+        })))
+    }
+}
+
+/// Get the status of the cache device `cache`.
+pub fn cache_status(dm: &DM, cache: &DevId) -> io::Result<CacheStatus> {
+    let (_, status) = try!(dm.table_status(cache, DmFlags::empty()));
+    match status.first() {
+        Some((_, _, ttype, params)) if ttype == "cache" => params.parse(),
+        _ => Err(Error::new(InvalidInput, "device is not a cache")),
+    }
+}
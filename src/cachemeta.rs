@@ -0,0 +1,356 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Reading and checking dm-cache metadata.
+//
+// A cache's metadata device holds a superblock, an array mapping each
+// cache block to the origin block it holds, an array of
+// policy-specific hints, and a metadata space map. Format 1 keeps
+// dirty state in the mapping flags; format 2 keeps it in a separate
+// bitset.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+use std::path::Path;
+
+use pdata::{array_for_each, le32, le64, CheckReport, MetadataDev, MetadataError, SpaceMap,
+            SpaceMapKind, SpaceMapRoot, Walker, validate_block};
+use types::Sectors;
+use slice_to_null;
+
+const CACHE_SUPERBLOCK_MAGIC: u64 = 6142003;
+const CACHE_SUPERBLOCK_LOCATION: u64 = 0;
+const SUPERBLOCK_CSUM_XOR: u32 = 9031977;
+const CACHE_MIN_VERSION: u32 = 1;
+const CACHE_MAX_VERSION: u32 = 2;
+
+const CACHE_POLICY_NAME_SIZE: usize = 16;
+
+const MAPPING_SIZE: usize = 8;
+const M_VALID: u64 = 1;
+const M_DIRTY: u64 = 2;
+
+/// The contents of a dm-cache metadata superblock.
+#[derive(Debug, Clone)]
+pub struct CacheSuperblock {
+    /// Superblock flags. Bit 0 is "clean shutdown", bit 1 is "needs_check".
+    pub flags: u32,
+    /// Metadata format version, 1 or 2.
+    pub version: u32,
+    /// The name of the policy that wrote the hints.
+    pub policy_name: String,
+    /// The version of the policy that wrote the hints.
+    pub policy_version: (u32, u32, u32),
+    /// The size of each hint, in bytes.
+    pub policy_hint_size: u32,
+    /// Root of the metadata space map.
+    pub metadata_sm_root: SpaceMapRoot,
+    /// Root of the mapping array.
+    pub mapping_root: u64,
+    /// Root of the hint array.
+    pub hint_root: u64,
+    /// Root of the discard bitset.
+    pub discard_root: u64,
+    /// The size of a discard block.
+    pub discard_block_size: Sectors,
+    /// The number of discard blocks.
+    pub discard_nr_blocks: u64,
+    /// The size of a cache block.
+    pub data_block_size: Sectors,
+    /// The number of blocks on the cache device.
+    pub cache_blocks: u32,
+    /// Read hits recorded at the last clean shutdown.
+    pub read_hits: u32,
+    /// Read misses recorded at the last clean shutdown.
+    pub read_misses: u32,
+    /// Write hits recorded at the last clean shutdown.
+    pub write_hits: u32,
+    /// Write misses recorded at the last clean shutdown.
+    pub write_misses: u32,
+    /// Root of the dirty bitset. Only present in format 2.
+    pub dirty_root: Option<u64>,
+}
+
+impl CacheSuperblock {
+    /// Read the superblock.
+    pub fn read(dev: &MetadataDev) -> Result<CacheSuperblock, MetadataError> {
+        let b = CACHE_SUPERBLOCK_LOCATION;
+        let block = try!(dev.read_block(b)
+            .map_err(|e| MetadataError::BadSuperblock(format!("read failed: {}", e))));
+
+        try!(validate_block(&block, b, SUPERBLOCK_CSUM_XOR)
+            .map_err(MetadataError::BadSuperblock));
+
+        let magic = le64(&block, 32);
+        if magic != CACHE_SUPERBLOCK_MAGIC {
+            return Err(MetadataError::BadSuperblock(format!("bad magic {}", magic)));
+        }
+
+        let version = le32(&block, 40);
+        if !(CACHE_MIN_VERSION..=CACHE_MAX_VERSION).contains(&version) {
+            return Err(MetadataError::BadSuperblock(format!("unsupported version {}",
+                                                            version)));
+        }
+
+        let name_buf = &block[44..44 + CACHE_POLICY_NAME_SIZE];
+        let policy_name = String::from_utf8_lossy(slice_to_null(name_buf).unwrap_or(name_buf))
+            .into_owned();
+
+        Ok(CacheSuperblock {
+            flags: le32(&block, 4),
+            version: version,
+            policy_name: policy_name,
+            policy_version: (le32(&block, 272), le32(&block, 276), le32(&block, 280)),
+            policy_hint_size: le32(&block, 60),
+            metadata_sm_root: SpaceMapRoot::parse(&block[64..]),
+            mapping_root: le64(&block, 192),
+            hint_root: le64(&block, 200),
+            discard_root: le64(&block, 208),
+            discard_block_size: Sectors(le64(&block, 216)),
+            discard_nr_blocks: le64(&block, 224),
+            data_block_size: Sectors(le32(&block, 232) as u64),
+            cache_blocks: le32(&block, 240),
+            read_hits: le32(&block, 256),
+            read_misses: le32(&block, 260),
+            write_hits: le32(&block, 264),
+            write_misses: le32(&block, 268),
+            dirty_root: if version >= 2 {
+                Some(le64(&block, 284))
+            } else {
+                None
+            },
+        })
+    }
+
+    /// Whether the cache was shut down cleanly. Hints and dirty
+    /// state are only trustworthy after a clean shutdown.
+    pub fn clean_shutdown(&self) -> bool {
+        self.flags & 1 != 0
+    }
+
+    /// Whether the cache has flagged its metadata as needing a check.
+    pub fn needs_check(&self) -> bool {
+        self.flags & 2 != 0
+    }
+}
+
+/// A cache block holding a copy of an origin block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheMapping {
+    /// The block on the cache device.
+    pub cache_block: u64,
+    /// The block on the origin device it caches.
+    pub origin_block: u64,
+    /// Whether the cached copy has not yet been written back.
+    pub dirty: bool,
+    /// The policy's hint for this block, `policy_hint_size` bytes long.
+    pub hint: Vec<u8>,
+}
+
+/// The mappings held in a cache's metadata.
+#[derive(Debug, Clone)]
+pub struct CacheMetadata {
+    /// The superblock.
+    pub superblock: CacheSuperblock,
+    /// Every valid mapping, in cache block order.
+    pub mappings: Vec<CacheMapping>,
+}
+
+impl CacheMetadata {
+    /// The number of cache blocks that hold an origin block.
+    pub fn nr_mapped(&self) -> u64 {
+        self.mappings.len() as u64
+    }
+
+    /// The number of cache blocks that must be written back before
+    /// the cache can be removed.
+    pub fn nr_dirty(&self) -> u64 {
+        self.mappings.iter().filter(|m| m.dirty).count() as u64
+    }
+
+    /// Mappings whose cache block has not been written back.
+    pub fn dirty(&self) -> Vec<&CacheMapping> {
+        self.mappings.iter().filter(|m| m.dirty).collect()
+    }
+}
+
+// Mapping values pack the origin block above 16 bits of flags.
+fn unpack_mapping(buf: &[u8]) -> (u64, u64) {
+    let val = le64(buf, 0);
+    (val >> 16, val & 0xffff)
+}
+
+fn bit_set(words: &BTreeMap<u64, u64>, bit: u64) -> bool {
+    words.get(&(bit / 64)).is_some_and(|w| w & (1 << (bit % 64)) != 0)
+}
+
+/// Read the mappings from an inactive cache's metadata device or an
+/// image file.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use devicemapper::cachemeta;
+///
+/// let md = cachemeta::read(Path::new("/dev/vg/cache_cmeta")).unwrap();
+/// println!("{} of {} blocks dirty", md.nr_dirty(), md.nr_mapped());
+/// ```
+pub fn read(path: &Path) -> io::Result<CacheMetadata> {
+    let dev = try!(MetadataDev::open(path));
+    read_dev(&dev)
+}
+
+/// Read the mappings from an already opened metadata device. See
+/// `read()`.
+pub fn read_dev(dev: &MetadataDev) -> io::Result<CacheMetadata> {
+    let sb = try!(CacheSuperblock::read(dev).map_err(|e| Error::new(InvalidData, e.to_string())));
+
+    let mut dirty_words = BTreeMap::new();
+    if let Some(dirty_root) = sb.dirty_root {
+        try!(array_for_each(dev, dirty_root, 8, &mut |i, v| {
+            dirty_words.insert(i, le64(v, 0));
+            Ok(())
+        }));
+    }
+
+    let mut mappings = Vec::new();
+    try!(array_for_each(dev, sb.mapping_root, MAPPING_SIZE, &mut |cblock, v| {
+        let (oblock, flags) = unpack_mapping(v);
+        if flags & M_VALID != 0 {
+            let dirty = match sb.dirty_root {
+                Some(_) => bit_set(&dirty_words, cblock),
+                None => flags & M_DIRTY != 0,
+            };
+            mappings.push(CacheMapping {
+                cache_block: cblock,
+                origin_block: oblock,
+                dirty: dirty,
+                hint: Vec::new(),
+            });
+        }
+        Ok(())
+    }));
+
+    let hint_size = sb.policy_hint_size as usize;
+    if hint_size > 0 && !mappings.is_empty() {
+        let mut hints = BTreeMap::new();
+        try!(array_for_each(dev, sb.hint_root, hint_size, &mut |cblock, v| {
+            hints.insert(cblock, v.to_vec());
+            Ok(())
+        }));
+
+        for m in &mut mappings {
+            if let Some(hint) = hints.remove(&m.cache_block) {
+                m.hint = hint;
+            }
+        }
+    }
+
+    Ok(CacheMetadata {
+        superblock: sb,
+        mappings: mappings,
+    })
+}
+
+/// Check the consistency of dm-cache metadata in a device or image
+/// file.
+///
+/// Validates every btree node and array block of the mapping, hint
+/// and dirty structures, checks that array lengths match the number
+/// of cache blocks and that no origin block is cached twice, and
+/// compares the references found against the metadata space map.
+pub fn check(path: &Path) -> io::Result<CheckReport> {
+    let dev = try!(MetadataDev::open(path));
+    Ok(check_dev(&dev))
+}
+
+/// Check the consistency of dm-cache metadata on an already opened
+/// device. See `check()`.
+pub fn check_dev(dev: &MetadataDev) -> CheckReport {
+    let sb = match CacheSuperblock::read(dev) {
+        Ok(sb) => sb,
+        Err(e) => return CheckReport { errors: vec![e] },
+    };
+
+    let mut walker = Walker::new(dev, sb.metadata_sm_root.nr_blocks);
+    walker.inc(CACHE_SUPERBLOCK_LOCATION, "superblock");
+
+    // Superblocks of unused caches have no arrays yet.
+    if sb.cache_blocks > 0 {
+        let mut origins = BTreeMap::new();
+        let mut duplicates = Vec::new();
+
+        let nr_mappings = walker.walk_array(sb.mapping_root,
+                                            MAPPING_SIZE,
+                                            "mapping array",
+                                            &mut |cblock, v| {
+            let (oblock, flags) = unpack_mapping(v);
+            if flags & M_VALID != 0 {
+                if let Some(other) = origins.insert(oblock, cblock) {
+                    duplicates.push((oblock, other, cblock));
+                }
+            }
+        });
+
+        for (oblock, first, second) in duplicates {
+            walker.errors.push(MetadataError::BadBlock {
+                block: sb.mapping_root,
+                reason: format!("origin block {} is mapped by cache blocks {} and {}",
+                                oblock,
+                                first,
+                                second),
+            });
+        }
+
+        let cache_blocks = sb.cache_blocks as u64;
+        let mut lengths = vec![("mapping array", nr_mappings, cache_blocks)];
+
+        if sb.policy_hint_size > 0 {
+            let nr_hints = walker.walk_array(sb.hint_root,
+                                             sb.policy_hint_size as usize,
+                                             "hint array",
+                                             &mut |_, _| {});
+            lengths.push(("hint array", nr_hints, cache_blocks));
+        }
+
+        if let Some(dirty_root) = sb.dirty_root {
+            let nr_words = walker.walk_array(dirty_root, 8, "dirty bitset", &mut |_, _| {});
+            lengths.push(("dirty bitset", nr_words, cache_blocks.div_ceil(64)));
+        }
+
+        for (context, actual, expected) in lengths {
+            if actual != expected {
+                walker.errors.push(MetadataError::BadBlock {
+                    block: CACHE_SUPERBLOCK_LOCATION,
+                    reason: format!("{} has {} entries, expected {}", context, actual, expected),
+                });
+            }
+        }
+    }
+
+    if sb.discard_nr_blocks > 0 {
+        walker.walk_array(sb.discard_root, 8, "discard bitset", &mut |_, _| {});
+    }
+
+    let metadata_sm = SpaceMap::load(&mut walker, &sb.metadata_sm_root, SpaceMapKind::Metadata);
+
+    let mut errors = Vec::new();
+    metadata_sm.for_each_count(dev, &mut errors, |b, count| {
+        let expected = walker.ref_count(b);
+        if count != expected {
+            walker.errors.push(MetadataError::MetadataRefCount {
+                block: b,
+                expected: expected,
+                actual: count,
+            });
+        }
+    });
+
+    walker.errors.extend(errors);
+
+    CheckReport { errors: walker.errors }
+}
@@ -0,0 +1,326 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput, Other};
+use std::str::FromStr;
+
+use {DM, DevId, Device, DmFlags, TargetLine};
+use types::Sectors;
+
+/// The smallest region size dm-clone accepts: 4KiB.
+pub const MIN_REGION_SIZE: Sectors = Sectors(8);
+/// The largest region size dm-clone accepts: 1GiB.
+pub const MAX_REGION_SIZE: Sectors = Sectors(2097152);
+
+/// A dm-clone target, which presents `source` as if it had already
+/// been copied to `dest`, copying ("hydrating") regions on first
+/// access and in the background.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::clone::CloneTarget;
+/// use devicemapper::types::Sectors;
+///
+/// let meta = Device { major: 8, minor: 17 };
+/// let dest = Device { major: 8, minor: 18 };
+/// let source = Device { major: 8, minor: 32 };
+///
+/// let mut clone = CloneTarget::new(meta, dest, source, Sectors(8)).unwrap();
+/// clone.hydration(false).hydration_batch_size(16);
+///
+/// assert_eq!(clone.params(),
+///            "8:17 8:18 8:32 8 1 no_hydration 2 hydration_batch_size 16");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloneTarget {
+    metadata: Device,
+    dest: Device,
+    source: Device,
+    region_size: Sectors,
+    hydration: bool,
+    discard_passdown: bool,
+    hydration_threshold: Option<u64>,
+    hydration_batch_size: Option<u64>,
+}
+
+impl CloneTarget {
+    /// A clone of `source` onto `dest`, tracking hydrated regions of
+    /// `region_size` on `metadata`. The region size must be a power of
+    /// two between `MIN_REGION_SIZE` and `MAX_REGION_SIZE`.
+    pub fn new(metadata: Device,
+               dest: Device,
+               source: Device,
+               region_size: Sectors)
+               -> io::Result<CloneTarget> {
+        if !(*region_size).is_power_of_two() || region_size < MIN_REGION_SIZE ||
+           region_size > MAX_REGION_SIZE {
+            return Err(Error::new(InvalidInput,
+                                  format!("clone region size {} is not a power of two \
+                                           between {} and {}",
+                                          *region_size,
+                                          *MIN_REGION_SIZE,
+                                          *MAX_REGION_SIZE)));
+        }
+
+        Ok(CloneTarget {
+            metadata: metadata,
+            dest: dest,
+            source: source,
+            region_size: region_size,
+            hydration: true,
+            discard_passdown: true,
+            hydration_threshold: None,
+            hydration_batch_size: None,
+        })
+    }
+
+    /// Whether to start hydrating in the background as soon as the
+    /// target is loaded. On by default; `no_hydration` if off.
+    pub fn hydration(&mut self, enabled: bool) -> &mut CloneTarget {
+        self.hydration = enabled;
+        self
+    }
+
+    /// Whether discards are passed down to the destination. On by
+    /// default; `no_discard_passdown` if off.
+    pub fn discard_passdown(&mut self, enabled: bool) -> &mut CloneTarget {
+        self.discard_passdown = enabled;
+        self
+    }
+
+    /// The number of regions being hydrated at which background
+    /// hydration stops starting more.
+    pub fn hydration_threshold(&mut self, regions: u64) -> &mut CloneTarget {
+        self.hydration_threshold = Some(regions);
+        self
+    }
+
+    /// The number of adjacent regions background hydration copies at
+    /// once.
+    pub fn hydration_batch_size(&mut self, regions: u64) -> &mut CloneTarget {
+        self.hydration_batch_size = Some(regions);
+        self
+    }
+
+    /// The target's params, for `DM::table_load()`.
+    pub fn params(&self) -> String {
+        let mut params = format!("{} {} {} {}",
+                                 self.metadata.dstr(),
+                                 self.dest.dstr(),
+                                 self.source.dstr(),
+                                 *self.region_size);
+
+        let mut features = Vec::new();
+        if !self.hydration {
+            features.push("no_hydration");
+        }
+        if !self.discard_passdown {
+            features.push("no_discard_passdown");
+        }
+
+        let mut core = Vec::new();
+        if let Some(n) = self.hydration_threshold {
+            core.push(format!("hydration_threshold {}", n));
+        }
+        if let Some(n) = self.hydration_batch_size {
+            core.push(format!("hydration_batch_size {}", n));
+        }
+
+        // Core args can only be given after the feature args.
+        if !features.is_empty() || !core.is_empty() {
+            params.push_str(&format!(" {}", features.len()));
+            for f in features {
+                params.push_str(&format!(" {}", f));
+            }
+        }
+        if !core.is_empty() {
+            params.push_str(&format!(" {} {}", core.len() * 2, core.join(" ")));
+        }
+
+        params
+    }
+
+    /// A target line for a clone of `length` sectors starting at
+    /// `start`, which is usually the size of `source`.
+    pub fn target(&self, start: Sectors, length: Sectors) -> TargetLine {
+        (*start, *length, "clone".to_owned(), self.params())
+    }
+}
+
+/// Whether a clone's metadata can be changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloneMetadataMode {
+    /// Regions can be hydrated.
+    ReadWrite,
+    /// Metadata can no longer be changed; writes to unhydrated
+    /// regions fail.
+    ReadOnly,
+}
+
+/// Status of a working clone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloneWorkingStatus {
+    /// The metadata block size.
+    pub metadata_block_size: Sectors,
+    /// Metadata blocks in use.
+    pub used_metadata_blocks: u64,
+    /// Total metadata blocks.
+    pub total_metadata_blocks: u64,
+    /// The region size.
+    pub region_size: Sectors,
+    /// Regions copied to the destination.
+    pub hydrated_regions: u64,
+    /// Total regions.
+    pub total_regions: u64,
+    /// Regions being copied now.
+    pub hydrating_regions: u64,
+    /// Whether background hydration is enabled.
+    pub hydration: bool,
+    /// Whether discards are passed down to the destination.
+    pub discard_passdown: bool,
+    /// The hydration threshold, if reported.
+    pub hydration_threshold: Option<u64>,
+    /// The hydration batch size, if reported.
+    pub hydration_batch_size: Option<u64>,
+    /// The metadata mode.
+    pub mode: CloneMetadataMode,
+    /// Whether the metadata has been flagged as needing a check.
+    pub needs_check: bool,
+}
+
+impl CloneWorkingStatus {
+    /// True if every region has been copied, so the destination can
+    /// be used on its own.
+    pub fn is_hydrated(&self) -> bool {
+        self.hydrated_regions == self.total_regions
+    }
+}
+
+/// Status of a clone, as reported by its target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloneStatus {
+    /// The clone is working.
+    Working(Box<CloneWorkingStatus>),
+    /// The clone has failed; all I/O to it errors.
+    Fail,
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.parse::<u64>().map_err(|_| Error::new(InvalidData, format!("expected number, got \"{}\"", s)))
+}
+
+fn parse_fraction(s: &str) -> io::Result<(u64, u64)> {
+    let spl: Vec<_> = s.split('/').collect();
+    if spl.len() != 2 {
+        return Err(Error::new(InvalidData, format!("expected used/total, got \"{}\"", s)));
+    }
+    Ok((try!(parse_u64(spl[0])), try!(parse_u64(spl[1]))))
+}
+
+impl FromStr for CloneStatus {
+    type Err = Error;
+
+    /// Parse the params field of a clone target's status line.
+    fn from_str(s: &str) -> io::Result<CloneStatus> {
+        let vals: Vec<_> = s.split_whitespace().collect();
+        let too_few = || Error::new(InvalidData, format!("too few fields in clone status \"{}\"", s));
+
+        match vals.first() {
+            Some(&"Fail") => return Ok(CloneStatus::Fail),
+            Some(&"Error") => return Err(Error::new(Other, "clone status unavailable")),
+            _ => {}
+        }
+
+        if vals.len() < 6 {
+            return Err(too_few());
+        }
+
+        let (used_meta, total_meta) = try!(parse_fraction(vals[1]));
+        let (hydrated, total) = try!(parse_fraction(vals[3]));
+
+        let nr_features = try!(parse_u64(vals[5])) as usize;
+        let features = try!(vals.get(6..6 + nr_features).ok_or_else(&too_few));
+
+        let core_start = 6 + nr_features;
+        let nr_core = try!(vals.get(core_start).ok_or_else(&too_few).and_then(|n| parse_u64(n))) as
+                      usize;
+        let core = try!(vals.get(core_start + 1..core_start + 1 + nr_core).ok_or_else(&too_few));
+
+        let mut hydration_threshold = None;
+        let mut hydration_batch_size = None;
+        for pair in core.chunks(2) {
+            match (pair[0], pair.get(1)) {
+                ("hydration_threshold", Some(n)) => hydration_threshold = Some(try!(parse_u64(n))),
+                ("hydration_batch_size", Some(n)) => hydration_batch_size = Some(try!(parse_u64(n))),
+                _ => {}
+            }
+        }
+
+        let rest = &vals[core_start + 1 + nr_core..];
+        let mode = match rest.first() {
+            Some(&"rw") => CloneMetadataMode::ReadWrite,
+            Some(&"ro") => CloneMetadataMode::ReadOnly,
+            Some(x) => {
+                return Err(Error::new(InvalidData, format!("unknown clone metadata mode \"{}\"", x)))
+            }
+            None => return Err(too_few()),
+        };
+
+        Ok(CloneStatus::Working(Box::new(CloneWorkingStatus {
+            metadata_block_size: Sectors(try!(parse_u64(vals[0]))),
+            used_metadata_blocks: used_meta,
+            total_metadata_blocks: total_meta,
+            region_size: Sectors(try!(parse_u64(vals[2]))),
+            hydrated_regions: hydrated,
+            total_regions: total,
+            hydrating_regions: try!(parse_u64(vals[4])),
+            hydration: !features.contains(&"no_hydration"),
+            discard_passdown: !features.contains(&"no_discard_passdown"),
+            hydration_threshold: hydration_threshold,
+            hydration_batch_size: hydration_batch_size,
+            mode: mode,
+            needs_check: rest.get(1) == Some(&"needs_check"),
+        })))
+    }
+}
+
+/// Get the status of the clone device `clone`.
+pub fn clone_status(dm: &DM, clone: &DevId) -> io::Result<CloneStatus> {
+    let (_, status) = try!(dm.table_status(clone, DmFlags::empty()));
+    match status.first() {
+        Some((_, _, ttype, params)) if ttype == "clone" => params.parse(),
+        _ => Err(Error::new(InvalidInput, "device is not a clone")),
+    }
+}
+
+/// Start copying regions to the destination in the background.
+pub fn enable_hydration(dm: &DM, clone: &DevId) -> io::Result<()> {
+    try!(dm.target_msg(clone, 0, "enable_hydration"));
+    Ok(())
+}
+
+/// Stop copying regions in the background. Regions are still copied
+/// when written to.
+pub fn disable_hydration(dm: &DM, clone: &DevId) -> io::Result<()> {
+    try!(dm.target_msg(clone, 0, "disable_hydration"));
+    Ok(())
+}
+
+/// Set the number of regions being hydrated at which background
+/// hydration stops starting more.
+pub fn set_hydration_threshold(dm: &DM, clone: &DevId, regions: u64) -> io::Result<()> {
+    try!(dm.target_msg(clone, 0, &format!("hydration_threshold {}", regions)));
+    Ok(())
+}
+
+/// Set the number of adjacent regions background hydration copies at
+/// once.
+pub fn set_hydration_batch_size(dm: &DM, clone: &DevId, regions: u64) -> io::Result<()> {
+    try!(dm.target_msg(clone, 0, &format!("hydration_batch_size {}", regions)));
+    Ok(())
+}
@@ -0,0 +1,398 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, Other};
+
+use {DM, DevId, Device, DmFlags, DM_DEFERRED_REMOVE, DM_SUSPEND};
+use backend::Backend;
+
+/// A snapshot of which devices each DM device's table uses.
+///
+/// Nodes are the DM devices plus the non-DM (physical) devices their
+/// tables refer to. An edge goes from a device to each device its
+/// active table depends on, so a device's "holders" are the DM devices
+/// stacked on it.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::depgraph::DepGraph;
+///
+/// let sdb = Device { major: 8, minor: 16 };
+/// let pool = Device { major: 253, minor: 0 };
+/// let thin = Device { major: 253, minor: 1 };
+///
+/// let mut graph = DepGraph::new();
+/// graph.add_device("pool", pool, &[sdb]);
+/// graph.add_device("thin", thin, &[pool]);
+///
+/// assert_eq!(graph.topo_order().unwrap(), vec![pool, thin]);
+/// assert_eq!(graph.leaves(), vec![sdb]);
+/// assert_eq!(graph.tree(), "thin (253:1)\n └─pool (253:0)\n    └─ (8:16)\n");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DepGraph {
+    names: BTreeMap<Device, String>,
+    deps: BTreeMap<Device, Vec<Device>>,
+}
+
+impl DepGraph {
+    /// Create an empty graph.
+    pub fn new() -> DepGraph {
+        DepGraph {
+            names: BTreeMap::new(),
+            deps: BTreeMap::new(),
+        }
+    }
+
+    /// Snapshot every DM device and the dependencies of its active
+    /// table.
+    ///
+    /// A device removed between listing it and reading its table is
+    /// left out.
+    pub fn snapshot<B: Backend>(dm: &B) -> io::Result<DepGraph> {
+        let mut graph = DepGraph::new();
+
+        for (name, dev) in try!(dm.list_devices()) {
+            match dm.table_deps(dev, DmFlags::empty()) {
+                Ok(deps) => graph.add_device(&name, dev, &deps),
+                Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(graph)
+    }
+
+    /// Add a DM device and the devices its table depends on,
+    /// replacing any previous entry for it.
+    pub fn add_device(&mut self, name: &str, dev: Device, deps: &[Device]) {
+        self.names.insert(dev, name.to_owned());
+        self.deps.insert(dev, deps.to_vec());
+    }
+
+    /// The DM devices in the graph.
+    pub fn devices(&self) -> Vec<Device> {
+        self.names.keys().cloned().collect()
+    }
+
+    /// True if `dev` is a DM device in the graph.
+    pub fn is_dm(&self, dev: Device) -> bool {
+        self.names.contains_key(&dev)
+    }
+
+    /// The name of DM device `dev`.
+    pub fn name(&self, dev: Device) -> Option<&str> {
+        self.names.get(&dev).map(|n| &n[..])
+    }
+
+    /// The DM device named `name`.
+    pub fn device(&self, name: &str) -> Option<Device> {
+        self.names.iter().find(|&(_, n)| n == name).map(|(d, _)| *d)
+    }
+
+    /// The devices `dev`'s table directly depends on.
+    pub fn deps(&self, dev: Device) -> &[Device] {
+        self.deps.get(&dev).map_or(&[], |d| &d[..])
+    }
+
+    /// The DM devices whose tables directly depend on `dev`.
+    pub fn holders(&self, dev: Device) -> Vec<Device> {
+        self.deps
+            .iter()
+            .filter(|&(_, deps)| deps.contains(&dev))
+            .map(|(d, _)| *d)
+            .collect()
+    }
+
+    /// All DM devices stacked on `dev`, directly or indirectly.
+    pub fn ancestors(&self, dev: Device) -> BTreeSet<Device> {
+        self.reachable(dev, |d| self.holders(d))
+    }
+
+    /// All devices `dev` depends on, directly or indirectly.
+    pub fn descendants(&self, dev: Device) -> BTreeSet<Device> {
+        self.reachable(dev, |d| self.deps(d).to_vec())
+    }
+
+    fn reachable<F>(&self, dev: Device, next: F) -> BTreeSet<Device>
+        where F: Fn(Device) -> Vec<Device>
+    {
+        let mut found = BTreeSet::new();
+        let mut todo = next(dev);
+        while let Some(d) = todo.pop() {
+            if found.insert(d) {
+                todo.extend(next(d));
+            }
+        }
+        found
+    }
+
+    /// The non-DM devices that DM tables refer to.
+    pub fn leaves(&self) -> Vec<Device> {
+        self.deps
+            .values()
+            .flat_map(|deps| deps.iter())
+            .filter(|d| !self.is_dm(**d))
+            .cloned()
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    /// The non-DM devices `dev` is ultimately built on.
+    pub fn leaves_of(&self, dev: Device) -> Vec<Device> {
+        self.descendants(dev).into_iter().filter(|d| !self.is_dm(*d)).collect()
+    }
+
+    /// The DM devices that no other DM device depends on.
+    pub fn roots(&self) -> Vec<Device> {
+        let held: BTreeSet<_> = self.deps.values().flat_map(|deps| deps.iter()).collect();
+        self.names.keys().filter(|d| !held.contains(d)).cloned().collect()
+    }
+
+    /// A cycle of DM devices, each depending on the next and the last
+    /// on the first, if there is one.
+    pub fn find_cycle(&self) -> Option<Vec<Device>> {
+        // Depth-first search; a dependency on a device still on the
+        // path closes a cycle.
+        fn visit(graph: &DepGraph,
+                 dev: Device,
+                 path: &mut Vec<Device>,
+                 done: &mut BTreeSet<Device>)
+                 -> Option<Vec<Device>> {
+            if let Some(pos) = path.iter().position(|d| *d == dev) {
+                return Some(path[pos..].to_vec());
+            }
+            if !graph.is_dm(dev) || done.contains(&dev) {
+                return None;
+            }
+
+            path.push(dev);
+            for dep in graph.deps(dev) {
+                if let Some(cycle) = visit(graph, *dep, path, done) {
+                    return Some(cycle);
+                }
+            }
+            path.pop();
+            done.insert(dev);
+            None
+        }
+
+        let mut done = BTreeSet::new();
+        for dev in self.names.keys() {
+            if let Some(cycle) = visit(self, *dev, &mut Vec::new(), &mut done) {
+                return Some(cycle);
+            }
+        }
+        None
+    }
+
+    /// The DM devices ordered so that each comes after every device it
+    /// depends on. This is the order to create or resume a stack in;
+    /// reverse it to suspend or remove one.
+    ///
+    /// Returns an error if the devices' dependencies form a cycle.
+    pub fn topo_order(&self) -> io::Result<Vec<Device>> {
+        let mut order = Vec::new();
+        let mut placed = BTreeSet::new();
+
+        while order.len() < self.names.len() {
+            let ready: Vec<_> = self.names
+                .keys()
+                .filter(|d| !placed.contains(*d))
+                .filter(|d| self.deps(**d).iter().all(|dep| !self.is_dm(*dep) || placed.contains(dep)))
+                .cloned()
+                .collect();
+
+            if ready.is_empty() {
+                let cycle = self.find_cycle().unwrap_or_default();
+                return Err(Error::new(InvalidData,
+                                      format!("dependency cycle among devices: {}",
+                                              cycle.iter()
+                                                  .map(|d| self.label(*d))
+                                                  .collect::<Vec<_>>()
+                                                  .join(" -> "))));
+            }
+
+            placed.extend(ready.iter().cloned());
+            order.extend(ready);
+        }
+
+        Ok(order)
+    }
+
+    fn label(&self, dev: Device) -> String {
+        format!("{} ({})", self.name(dev).unwrap_or(""), dev.dstr())
+    }
+
+    /// Draw the stacks as `dmsetup ls --tree` does: one tree per root
+    /// device, showing what each device is built on.
+    pub fn tree(&self) -> String {
+        fn draw(graph: &DepGraph,
+                dev: Device,
+                prefix: &str,
+                path: &mut Vec<Device>,
+                out: &mut String) {
+            let deps = graph.deps(dev);
+            for (i, dep) in deps.iter().enumerate() {
+                let last = i + 1 == deps.len();
+                out.push_str(prefix);
+                out.push_str(if last { " └─" } else { " ├─" });
+                out.push_str(&graph.label(*dep));
+                out.push('\n');
+
+                // Don't follow a dependency cycle forever.
+                if !path.contains(dep) {
+                    path.push(*dep);
+                    let prefix = format!("{}{}", prefix, if last { "   " } else { " │ " });
+                    draw(graph, *dep, &prefix, path, out);
+                    path.pop();
+                }
+            }
+        }
+
+        let mut out = String::new();
+        let mut roots = self.roots();
+        if roots.is_empty() {
+            // Every device is in a cycle; show them all.
+            roots = self.devices();
+        }
+        for root in roots {
+            out.push_str(&self.label(root));
+            out.push('\n');
+            draw(self, root, "", &mut vec![root], &mut out);
+        }
+        out
+    }
+}
+
+/// One device to remove in a `remove_tree()` plan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoveStep {
+    /// The device's name.
+    pub name: String,
+    /// The device's number.
+    pub device: Device,
+    /// How many times the device is open, other than by devices being
+    /// removed before it.
+    pub open_count: i32,
+    /// True if the device is still in use, so is only marked for
+    /// removal with DM_DEFERRED_REMOVE. Otherwise it is suspended, its
+    /// inactive table cleared, and it is removed.
+    pub deferred: bool,
+}
+
+impl fmt::Display for RemoveStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if self.deferred {
+            write!(f,
+                   "deferred remove {} ({}), in use",
+                   self.name,
+                   self.device.dstr())
+        } else {
+            write!(f,
+                   "suspend, clear, and remove {} ({})",
+                   self.name,
+                   self.device.dstr())
+        }
+    }
+}
+
+/// Plan removing a device and every device stacked on it, without
+/// changing anything.
+///
+/// Returns the devices to remove, holders first, with the device
+/// itself last. Open counts are estimates: a device is taken to be
+/// opened once by each holder being removed ahead of it.
+///
+/// Fails if a device is in use by something other than a device being
+/// removed, unless DM_DEFERRED_REMOVE is given, in which case that
+/// device, and any device beneath it, is marked for deferred removal.
+///
+/// Valid flags: DM_DEFERRED_REMOVE
+pub fn plan_remove_tree(dm: &DM, id: &DevId, flags: DmFlags) -> io::Result<Vec<RemoveStep>> {
+    let deferred_ok = flags.contains(DM_DEFERRED_REMOVE);
+    let target = try!(dm.device_status(id)).device();
+    let graph = try!(DepGraph::snapshot(dm));
+
+    let mut doomed = graph.ancestors(target);
+    doomed.insert(target);
+
+    let order: Vec<_> = try!(graph.topo_order())
+        .into_iter()
+        .rev()
+        .filter(|d| doomed.contains(d))
+        .collect();
+
+    let mut plan: Vec<RemoveStep> = Vec::new();
+    for dev in order {
+        let name = graph.name(dev).unwrap_or("").to_owned();
+        let info = try!(dm.device_status(&DevId::Name(&name)));
+
+        let holders = graph.holders(dev);
+        let removed_holders = plan.iter().filter(|s| holders.contains(&s.device) && !s.deferred);
+        let open_count = info.open_count() - removed_holders.count() as i32;
+        let held = plan.iter().any(|s| holders.contains(&s.device) && s.deferred);
+
+        if (open_count > 0 || held) && !deferred_ok {
+            return Err(Error::new(Other,
+                                  format!("device {} ({}) is in use", name, dev.dstr())));
+        }
+
+        plan.push(RemoveStep {
+            name: name,
+            device: dev,
+            open_count: open_count,
+            deferred: open_count > 0 || held,
+        });
+    }
+
+    Ok(plan)
+}
+
+/// Remove a device and every device stacked on it, holders first.
+/// Returns the steps taken. See `plan_remove_tree()`.
+///
+/// The whole plan is made, and checked for busy devices, before
+/// anything is changed.
+///
+/// Valid flags: DM_DEFERRED_REMOVE
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId, DmFlags, DM_DEFERRED_REMOVE};
+/// use devicemapper::depgraph;
+///
+/// let dm = DM::new().unwrap();
+/// let id = DevId::Name("example-pool");
+///
+/// for step in depgraph::plan_remove_tree(&dm, &id, DmFlags::empty()).unwrap() {
+///     println!("{}", step);
+/// }
+///
+/// depgraph::remove_tree(&dm, &id, DM_DEFERRED_REMOVE).unwrap();
+/// ```
+pub fn remove_tree(dm: &DM, id: &DevId, flags: DmFlags) -> io::Result<Vec<RemoveStep>> {
+    let plan = try!(plan_remove_tree(dm, id, flags));
+
+    for step in &plan {
+        let id = DevId::Name(&step.name);
+        if step.deferred {
+            try!(dm.device_remove(&id, DM_DEFERRED_REMOVE));
+        } else {
+            try!(dm.device_suspend(&id, DM_SUSPEND));
+            try!(dm.table_clear(&id));
+            try!(dm.device_remove(&id, DmFlags::empty()));
+        }
+    }
+
+    Ok(plan)
+}
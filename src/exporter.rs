@@ -0,0 +1,514 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::Write;
+use std::time::Duration;
+
+use {DM, DevId, DeviceInfo, DmFlags, TargetLine, DM_READONLY, DM_SUSPEND};
+use cache::CacheStatus;
+use mirror::{LegHealth, MirrorStatus};
+use raid::RaidStatus;
+use stats::{self, AreaCounters, RegionInfo};
+use thinpool::{ThinPoolMode, ThinPoolStatus};
+use types::Sectors;
+
+// All the samples of one metric, with its help text and type.
+struct Family {
+    help: &'static str,
+    kind: &'static str,
+    samples: Vec<(String, f64)>,
+}
+
+/// Metrics to be rendered in the Prometheus text exposition format.
+/// Samples of the same metric are grouped together, under one HELP
+/// and TYPE line, whatever order they were added in.
+#[derive(Default)]
+pub struct Metrics {
+    families: BTreeMap<&'static str, Family>,
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_owned()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_owned()
+    } else {
+        value.to_string()
+    }
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+impl Metrics {
+    /// No metrics.
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Add a sample of metric `name` with `labels`. `kind` is the
+    /// metric type, e.g. "gauge" or "counter"; the help text and type
+    /// of the first sample of each metric are used.
+    pub fn add(&mut self,
+               name: &'static str,
+               kind: &'static str,
+               help: &'static str,
+               labels: &[(&str, &str)],
+               value: f64) {
+        let mut rendered = String::new();
+        if !labels.is_empty() {
+            let pairs: Vec<_> = labels.iter()
+                .map(|&(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            rendered = format!("{{{}}}", pairs.join(","));
+        }
+
+        self.families
+            .entry(name)
+            .or_insert_with(|| {
+                Family {
+                    help: help,
+                    kind: kind,
+                    samples: Vec::new(),
+                }
+            })
+            .samples
+            .push((rendered, value));
+    }
+
+    /// Render the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, family) in &self.families {
+            let _ = writeln!(out, "# HELP {} {}", name, family.help);
+            let _ = writeln!(out, "# TYPE {} {}", name, family.kind);
+            for &(ref labels, value) in &family.samples {
+                // Histogram samples carry their suffix in the labels
+                // string, e.g. "_bucket{le=\"0.001\"}".
+                let _ = writeln!(out, "{}{} {}", name, labels, format_value(value));
+            }
+        }
+        out
+    }
+
+    fn add_histogram(&mut self,
+                     name: &'static str,
+                     help: &'static str,
+                     labels: &[(&str, &str)],
+                     bounds: &[Duration],
+                     counts: &[u64],
+                     sum: f64) {
+        let base: Vec<_> = labels.iter().map(|&(k, v)| format!("{}=\"{}\"", k, escape(v))).collect();
+        let with = |extra: Option<String>| {
+            let mut l = base.clone();
+            l.extend(extra);
+            format!("{{{}}}", l.join(","))
+        };
+
+        let family = self.families.entry(name).or_insert_with(|| {
+            Family {
+                help: help,
+                kind: "histogram",
+                samples: Vec::new(),
+            }
+        });
+
+        let mut cumulative = 0;
+        for (i, count) in counts.iter().enumerate() {
+            cumulative += *count;
+            let le = match bounds.get(i) {
+                Some(b) => format_value(secs(*b)),
+                None => "+Inf".to_owned(),
+            };
+            family.samples.push((format!("_bucket{}", with(Some(format!("le=\"{}\"", le)))),
+                                 cumulative as f64));
+        }
+        family.samples.push((format!("_sum{}", with(None)), sum));
+        family.samples.push((format!("_count{}", with(None)), cumulative as f64));
+    }
+}
+
+/// Collects metrics about every DM device: its state, what its
+/// targets report about themselves, and optionally its dm-stats
+/// counters, and renders them for Prometheus, e.g. for the node
+/// exporter's textfile collector. Every metric is labelled with the
+/// device's name and UUID.
+///
+/// Status is understood for thin-pool, cache, raid and mirror
+/// targets. A target whose status cannot be parsed has only
+/// `dm_target_status_parse_error` set, rather than failing the whole
+/// collection.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// use devicemapper::DM;
+/// use devicemapper::exporter::Exporter;
+///
+/// let dm = DM::new().unwrap();
+/// let mut exporter = Exporter::new();
+/// exporter.stats(true);
+///
+/// let mut f = File::create("/var/lib/node_exporter/dm.prom.tmp").unwrap();
+/// exporter.write_to(&dm, &mut f).unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exporter {
+    stats: bool,
+    stats_program_id: Option<String>,
+}
+
+impl Exporter {
+    /// An exporter of device and target metrics, without dm-stats.
+    pub fn new() -> Exporter {
+        Exporter::default()
+    }
+
+    /// Whether to include dm-stats counters of existing regions,
+    /// summed over each region's areas.
+    pub fn stats(&mut self, enabled: bool) -> &mut Exporter {
+        self.stats = enabled;
+        self
+    }
+
+    /// Only include dm-stats regions of `program_id`.
+    pub fn stats_program_id(&mut self, program_id: &str) -> &mut Exporter {
+        self.stats_program_id = Some(program_id.to_owned());
+        self
+    }
+
+    /// Collect metrics for every device.
+    pub fn collect(&self, dm: &DM) -> io::Result<Metrics> {
+        let mut metrics = Metrics::new();
+
+        for (name, _) in try!(dm.list_devices()) {
+            let id = DevId::Name(&name);
+            let result = dm.device_status(&id).and_then(|info| {
+                let (_, status) = try!(dm.table_status(&id, DmFlags::empty()));
+                let regions = if self.stats {
+                    try!(self.regions(dm, &id))
+                } else {
+                    Vec::new()
+                };
+                Ok((info, status, regions))
+            });
+
+            let (info, status, regions) = match result {
+                Ok(r) => r,
+                // The device went away since it was listed.
+                Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => continue,
+                Err(err) => return Err(err),
+            };
+
+            let labels = [("name", info.name()), ("uuid", info.uuid())];
+            add_device(&mut metrics, &info, &labels);
+            for (i, target) in status.iter().enumerate() {
+                add_target(&mut metrics, &labels, i, target);
+            }
+            for (region, counters) in regions {
+                add_region(&mut metrics, &labels, &region, &counters);
+            }
+        }
+
+        Ok(metrics)
+    }
+
+    fn regions(&self, dm: &DM, id: &DevId) -> io::Result<Vec<(RegionInfo, AreaCounters)>> {
+        let program_id = self.stats_program_id.as_ref().map(|p| &p[..]);
+        let mut regions = Vec::new();
+        for region in try!(stats::list_regions(dm, id, program_id)) {
+            let sample = try!(stats::print_region(dm, id, &region));
+            regions.push((region, sample.total()));
+        }
+        Ok(regions)
+    }
+
+    /// Collect metrics for every device, in the Prometheus text
+    /// format.
+    pub fn render(&self, dm: &DM) -> io::Result<String> {
+        Ok(try!(self.collect(dm)).render())
+    }
+
+    /// Collect metrics for every device, and write them to `w` in the
+    /// Prometheus text format.
+    pub fn write_to<W: Write>(&self, dm: &DM, w: &mut W) -> io::Result<()> {
+        w.write_all(try!(self.render(dm)).as_bytes())
+    }
+}
+
+fn flag(set: bool) -> f64 {
+    if set { 1.0 } else { 0.0 }
+}
+
+fn add_device(m: &mut Metrics, info: &DeviceInfo, labels: &[(&str, &str)]) {
+    let dev = info.device().dstr();
+    let mut info_labels = labels.to_vec();
+    info_labels.push(("device", &dev));
+
+    m.add("dm_device_info",
+          "gauge",
+          "Device-mapper device, with its major:minor number.",
+          &info_labels,
+          1.0);
+    m.add("dm_device_open_count",
+          "gauge",
+          "Number of times the device is open.",
+          labels,
+          info.open_count() as f64);
+    m.add("dm_device_event_number",
+          "gauge",
+          "The device's event number.",
+          labels,
+          info.event_nr() as f64);
+    m.add("dm_device_suspended",
+          "gauge",
+          "Whether the device is suspended.",
+          labels,
+          flag(info.flags().contains(DM_SUSPEND)));
+    m.add("dm_device_read_only",
+          "gauge",
+          "Whether the device is read-only.",
+          labels,
+          flag(info.flags().contains(DM_READONLY)));
+}
+
+fn add_target(m: &mut Metrics, labels: &[(&str, &str)], index: usize, target: &TargetLine) {
+    let index = index.to_string();
+    let mut labels = labels.to_vec();
+    labels.push(("target", &index));
+    let labels = &labels[..];
+
+    let parsed = match &target.2[..] {
+        "thin-pool" => {
+            match target.3.parse::<ThinPoolStatus>() {
+                Ok(ThinPoolStatus::Working(s)) => {
+                    m.add("dm_thin_pool_failed",
+                          "gauge",
+                          "Whether the thin-pool has failed.",
+                          labels,
+                          0.0);
+                    m.add("dm_thin_pool_data_blocks_used",
+                          "gauge",
+                          "Thin-pool data blocks in use.",
+                          labels,
+                          s.used_data_blocks as f64);
+                    m.add("dm_thin_pool_data_blocks",
+                          "gauge",
+                          "Thin-pool data blocks.",
+                          labels,
+                          s.total_data_blocks as f64);
+                    m.add("dm_thin_pool_metadata_blocks_used",
+                          "gauge",
+                          "Thin-pool metadata blocks in use.",
+                          labels,
+                          s.used_metadata_blocks as f64);
+                    m.add("dm_thin_pool_metadata_blocks",
+                          "gauge",
+                          "Thin-pool metadata blocks.",
+                          labels,
+                          s.total_metadata_blocks as f64);
+                    m.add("dm_thin_pool_read_only",
+                          "gauge",
+                          "Whether the thin-pool's metadata is read-only.",
+                          labels,
+                          flag(s.mode == ThinPoolMode::ReadOnly));
+                    m.add("dm_thin_pool_out_of_data_space",
+                          "gauge",
+                          "Whether the thin-pool is out of data space.",
+                          labels,
+                          flag(s.mode == ThinPoolMode::OutOfDataSpace));
+                    m.add("dm_thin_pool_needs_check",
+                          "gauge",
+                          "Whether the thin-pool's metadata needs checking.",
+                          labels,
+                          flag(s.needs_check));
+                    true
+                }
+                Ok(ThinPoolStatus::Fail) => {
+                    m.add("dm_thin_pool_failed",
+                          "gauge",
+                          "Whether the thin-pool has failed.",
+                          labels,
+                          1.0);
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        "cache" => {
+            match target.3.parse::<CacheStatus>() {
+                Ok(CacheStatus::Working(s)) => {
+                    m.add("dm_cache_failed", "gauge", "Whether the cache has failed.", labels, 0.0);
+                    m.add("dm_cache_blocks_used",
+                          "gauge",
+                          "Cache blocks holding data.",
+                          labels,
+                          s.used_cache_blocks as f64);
+                    m.add("dm_cache_blocks", "gauge", "Cache blocks.", labels, s.total_cache_blocks as f64);
+                    m.add("dm_cache_dirty_blocks",
+                          "gauge",
+                          "Cache blocks not yet written back.",
+                          labels,
+                          s.dirty as f64);
+                    m.add("dm_cache_read_hits_total",
+                          "counter",
+                          "Reads served from the cache.",
+                          labels,
+                          s.read_hits as f64);
+                    m.add("dm_cache_read_misses_total",
+                          "counter",
+                          "Reads sent to the origin.",
+                          labels,
+                          s.read_misses as f64);
+                    m.add("dm_cache_write_hits_total",
+                          "counter",
+                          "Writes to blocks in the cache.",
+                          labels,
+                          s.write_hits as f64);
+                    m.add("dm_cache_write_misses_total",
+                          "counter",
+                          "Writes to blocks not in the cache.",
+                          labels,
+                          s.write_misses as f64);
+                    m.add("dm_cache_read_hit_ratio",
+                          "gauge",
+                          "Fraction of reads served from the cache.",
+                          labels,
+                          s.read_hit_ratio().unwrap_or(0.0));
+                    m.add("dm_cache_write_hit_ratio",
+                          "gauge",
+                          "Fraction of writes to blocks in the cache.",
+                          labels,
+                          s.write_hit_ratio().unwrap_or(0.0));
+                    m.add("dm_cache_promotions_total",
+                          "counter",
+                          "Blocks moved into the cache.",
+                          labels,
+                          s.promotions as f64);
+                    m.add("dm_cache_demotions_total",
+                          "counter",
+                          "Blocks moved out of the cache.",
+                          labels,
+                          s.demotions as f64);
+                    true
+                }
+                Ok(CacheStatus::Fail) => {
+                    m.add("dm_cache_failed", "gauge", "Whether the cache has failed.", labels, 1.0);
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+        "raid" => {
+            let parsed = target.3.parse::<RaidStatus>();
+            if let Ok(ref s) = parsed {
+                m.add("dm_raid_sync_ratio",
+                      "gauge",
+                      "Fraction of the raid set that is in sync.",
+                      labels,
+                      s.sync_ratio());
+                m.add("dm_raid_devices",
+                      "gauge",
+                      "Devices in the raid set.",
+                      labels,
+                      s.health.len() as f64);
+                m.add("dm_raid_devices_failed",
+                      "gauge",
+                      "Failed or missing devices in the raid set.",
+                      labels,
+                      s.dead_devices() as f64);
+                m.add("dm_raid_mismatches",
+                      "gauge",
+                      "Mismatches found by the last check or repair.",
+                      labels,
+                      s.mismatch_count as f64);
+            }
+            parsed.is_ok()
+        }
+        "mirror" => {
+            let parsed = target.3.parse::<MirrorStatus>();
+            if let Ok(ref s) = parsed {
+                let failed = s.health.iter().filter(|h| **h != LegHealth::Alive).count();
+                let ratio = if s.total_regions == 0 {
+                    1.0
+                } else {
+                    s.in_sync_regions as f64 / s.total_regions as f64
+                };
+                m.add("dm_mirror_sync_ratio",
+                      "gauge",
+                      "Fraction of the mirror's regions in sync.",
+                      labels,
+                      ratio);
+                m.add("dm_mirror_legs", "gauge", "Legs of the mirror.", labels, s.devices.len() as f64);
+                m.add("dm_mirror_legs_failed",
+                      "gauge",
+                      "Legs of the mirror that are not alive.",
+                      labels,
+                      failed as f64);
+            }
+            parsed.is_ok()
+        }
+        _ => return,
+    };
+
+    // A status that cannot be parsed exports none of the target's
+    // metrics, so make that visible.
+    m.add("dm_target_status_parse_error",
+          "gauge",
+          "Whether the target's status could not be parsed.",
+          labels,
+          flag(!parsed));
+}
+
+fn add_region(m: &mut Metrics, labels: &[(&str, &str)], region: &RegionInfo, c: &AreaCounters) {
+    let id = region.id.to_string();
+    let mut labels = labels.to_vec();
+    labels.push(("region", &id));
+    labels.push(("program_id", &region.program_id));
+    let labels = &labels[..];
+
+    let counters = [("dm_stats_reads_total", "Reads completed.", c.reads as f64),
+                    ("dm_stats_reads_merged_total", "Reads merged.", c.reads_merged as f64),
+                    ("dm_stats_read_bytes_total",
+                     "Bytes read.",
+                     *Sectors(c.read_sectors).bytes() as f64),
+                    ("dm_stats_read_seconds_total", "Time spent by all reads.", secs(c.read_time)),
+                    ("dm_stats_writes_total", "Writes completed.", c.writes as f64),
+                    ("dm_stats_writes_merged_total", "Writes merged.", c.writes_merged as f64),
+                    ("dm_stats_write_bytes_total",
+                     "Bytes written.",
+                     *Sectors(c.write_sectors).bytes() as f64),
+                    ("dm_stats_write_seconds_total", "Time spent by all writes.", secs(c.write_time)),
+                    ("dm_stats_io_seconds_total", "Time with I/O in progress.", secs(c.io_time)),
+                    ("dm_stats_weighted_io_seconds_total",
+                     "Time spent by all I/Os, including queueing.",
+                     secs(c.weighted_io_time))];
+    for &(name, help, value) in &counters {
+        m.add(name, "counter", help, labels, value);
+    }
+    m.add("dm_stats_in_flight",
+          "gauge",
+          "I/Os in progress.",
+          labels,
+          c.in_flight as f64);
+
+    if !region.histogram.is_empty() && c.histogram.len() == region.histogram.len() + 1 {
+        m.add_histogram("dm_stats_latency_seconds",
+                        "Latency of completed I/Os.",
+                        labels,
+                        &region.histogram,
+                        &c.histogram,
+                        secs(c.read_time) + secs(c.write_time));
+    }
+}
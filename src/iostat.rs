@@ -0,0 +1,301 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cmp::Ordering;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use {DM, DevId};
+use stats::{self, AreaCounters, IoRates, RegionInfo, StatsSample};
+use types::Sectors;
+
+/// The I/O of one area, or a whole region, over an interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AreaReport {
+    /// The area's first sector.
+    pub start: Sectors,
+    /// The area's length.
+    pub length: Sectors,
+    /// Rates over the interval.
+    pub rates: IoRates,
+    /// I/Os in progress at the end of the interval.
+    pub in_flight: u64,
+    /// The latency histogram's bucket bounds, empty if the region has
+    /// no histogram.
+    pub bounds: Vec<Duration>,
+    /// I/Os completed in each latency bucket over the interval.
+    pub histogram: Vec<u64>,
+}
+
+impl AreaReport {
+    fn new(earlier: &AreaCounters,
+           later: &AreaCounters,
+           rates: IoRates,
+           bounds: &[Duration])
+           -> AreaReport {
+        let histogram = if later.histogram.len() == earlier.histogram.len() {
+            later.histogram
+                .iter()
+                .zip(earlier.histogram.iter())
+                .map(|(l, e)| l.saturating_sub(*e))
+                .collect()
+        } else {
+            later.histogram.clone()
+        };
+
+        AreaReport {
+            start: later.start,
+            length: later.length,
+            rates: rates,
+            in_flight: later.in_flight,
+            bounds: bounds.to_vec(),
+            histogram: histogram,
+        }
+    }
+
+    /// Reads and writes completed per second.
+    pub fn iops(&self) -> f64 {
+        self.rates.reads_per_sec + self.rates.writes_per_sec
+    }
+
+    /// Bytes read and written per second.
+    pub fn throughput(&self) -> f64 {
+        (self.rates.read_sectors_per_sec + self.rates.write_sectors_per_sec) *
+        *Sectors(1).bytes() as f64
+    }
+
+    /// The latency below which `percent` of the I/Os in the interval
+    /// completed, estimated from the histogram by assuming latencies
+    /// are spread evenly within each bucket. I/Os above the last bound
+    /// count as taking exactly that long. None without a histogram, or
+    /// if no I/O completed.
+    pub fn percentile(&self, percent: f64) -> Option<Duration> {
+        percentile(&self.bounds, &self.histogram, percent)
+    }
+}
+
+/// The latency below which `percent` of I/Os completed, given
+/// histogram `bounds` and the number of I/Os in each bucket: below the
+/// first bound, between each pair, and above the last.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use devicemapper::iostat::percentile;
+///
+/// let bounds = [Duration::from_millis(1), Duration::from_millis(3)];
+///
+/// assert_eq!(percentile(&bounds, &[50, 50, 0], 75.0), Some(Duration::from_millis(2)));
+/// ```
+pub fn percentile(bounds: &[Duration], counts: &[u64], percent: f64) -> Option<Duration> {
+    if bounds.is_empty() || counts.len() != bounds.len() + 1 {
+        return None;
+    }
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+
+    let rank = total as f64 * percent.clamp(0.0, 100.0) / 100.0;
+    let mut below = 0.0;
+    for (i, count) in counts.iter().enumerate() {
+        let count = *count as f64;
+        if below + count >= rank && count > 0.0 {
+            let lower = if i == 0 { Duration::from_secs(0) } else { bounds[i - 1] };
+            let upper = match bounds.get(i) {
+                Some(upper) => *upper,
+                None => return Some(lower),
+            };
+            let fraction = (rank - below) / count;
+            let nanos = |d: Duration| d.as_secs() as f64 * 1e9 + d.subsec_nanos() as f64;
+            let at = nanos(lower) + (nanos(upper) - nanos(lower)) * fraction;
+            return Some(Duration::new((at / 1e9) as u64, (at % 1e9) as u32));
+        }
+        below += count;
+    }
+    bounds.last().cloned()
+}
+
+/// The I/O of one stats region over an interval.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionReport {
+    /// The name of the device the region is on.
+    pub device: String,
+    /// The region's id.
+    pub region_id: u64,
+    /// The length of the interval.
+    pub interval: Duration,
+    /// The whole region's I/O.
+    pub total: AreaReport,
+    /// Each area's I/O.
+    pub areas: Vec<AreaReport>,
+}
+
+impl RegionReport {
+    /// The `n` areas with the most I/O per second, busiest first.
+    pub fn hottest(&self, n: usize) -> Vec<&AreaReport> {
+        let mut areas: Vec<_> = self.areas.iter().collect();
+        areas.sort_by(|a, b| b.iops().partial_cmp(&a.iops()).unwrap_or(Ordering::Equal));
+        areas.truncate(n);
+        areas
+    }
+}
+
+struct Watch {
+    device: String,
+    region: RegionInfo,
+    last: Option<StatsSample>,
+}
+
+/// Samples the counters of dm-stats regions on any number of devices
+/// every interval, and reports the I/O in between, much like
+/// `iostat`. Counters are read without clearing them, so other users
+/// of the same regions are not disturbed.
+///
+/// A monitor is also an endless iterator, each item being the
+/// reports for the next interval.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use devicemapper::{DM, DevId};
+/// use devicemapper::iostat::Monitor;
+/// use devicemapper::stats::{self, RegionSpec, StatsStep};
+///
+/// let dm = DM::new().unwrap();
+/// let mut spec = RegionSpec::whole();
+/// spec.step(StatsStep::Areas(64)).program_id("hotspots");
+/// stats::create_region(&dm, &DevId::Name("thin-vol"), &spec).unwrap();
+///
+/// let mut monitor = Monitor::new(&dm, Duration::from_secs(5));
+/// monitor.watch_regions("thin-vol", Some("hotspots")).unwrap();
+///
+/// for reports in monitor.take(12) {
+///     for r in reports.unwrap() {
+///         for area in r.hottest(3) {
+///             println!("{} {}+{}: {:.0} IOPS",
+///                      r.device,
+///                      *area.start,
+///                      *area.length,
+///                      area.iops());
+///         }
+///     }
+/// }
+/// ```
+pub struct Monitor<'a> {
+    dm: &'a DM,
+    interval: Duration,
+    watches: Vec<Watch>,
+    next_tick: Option<Instant>,
+}
+
+impl<'a> Monitor<'a> {
+    /// A monitor sampling every `interval`.
+    pub fn new(dm: &'a DM, interval: Duration) -> Monitor<'a> {
+        Monitor {
+            dm: dm,
+            interval: interval,
+            watches: Vec::new(),
+            next_tick: None,
+        }
+    }
+
+    /// Watch `region` on `device`.
+    pub fn watch(&mut self, device: &str, region: RegionInfo) -> &mut Monitor<'a> {
+        self.watches.push(Watch {
+            device: device.to_owned(),
+            region: region,
+            last: None,
+        });
+        self
+    }
+
+    /// Watch every stats region on `device`, or only those of
+    /// `program_id`. Returns how many regions were added.
+    pub fn watch_regions(&mut self, device: &str, program_id: Option<&str>) -> io::Result<usize> {
+        let regions = try!(stats::list_regions(self.dm, &DevId::Name(device), program_id));
+        let count = regions.len();
+        for r in regions {
+            self.watch(device, r);
+        }
+        Ok(count)
+    }
+
+    /// Read every watched region's counters now, and report the I/O
+    /// since the last sample of each. Regions sampled for the first
+    /// time are not reported.
+    pub fn sample(&mut self) -> io::Result<Vec<RegionReport>> {
+        let mut reports = Vec::new();
+
+        for w in &mut self.watches {
+            let sample = try!(stats::print_region(self.dm, &DevId::Name(&w.device), &w.region));
+
+            if let Some(ref last) = w.last {
+                let rates = try!(sample.rates(last));
+                let total_rates = try!(sample.total_rates(last));
+                let bounds = &w.region.histogram;
+
+                reports.push(RegionReport {
+                    device: w.device.clone(),
+                    region_id: w.region.id,
+                    interval: sample.taken.duration_since(last.taken),
+                    total: AreaReport::new(&last.total(), &sample.total(), total_rates, bounds),
+                    areas: last.areas
+                        .iter()
+                        .zip(sample.areas.iter())
+                        .zip(rates)
+                        .map(|((e, l), r)| AreaReport::new(e, l, r, bounds))
+                        .collect(),
+                });
+            }
+            w.last = Some(sample);
+        }
+
+        Ok(reports)
+    }
+
+    // Wait for the next tick, keeping to the interval however long
+    // sampling takes, then sample.
+    fn tick(&mut self) -> io::Result<Vec<RegionReport>> {
+        let now = Instant::now();
+        let tick = match self.next_tick {
+            Some(tick) => tick,
+            None => {
+                try!(self.sample());
+                now + self.interval
+            }
+        };
+
+        if tick > now {
+            thread::sleep(tick - now);
+        }
+        self.next_tick = Some(tick + self.interval);
+        self.sample()
+    }
+
+    /// Report every interval to `handler`, until it returns false.
+    pub fn run<F>(&mut self, mut handler: F) -> io::Result<()>
+        where F: FnMut(&[RegionReport]) -> io::Result<bool>
+    {
+        loop {
+            let reports = try!(self.tick());
+            if !try!(handler(&reports)) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Monitor<'a> {
+    type Item = io::Result<Vec<RegionReport>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<RegionReport>>> {
+        Some(self.tick())
+    }
+}
@@ -54,6 +54,7 @@ extern crate libc;
 #[macro_use]
 extern crate nix;
 extern crate serde;
+extern crate serde_json;
 #[macro_use]
 extern crate bitflags;
 
@@ -64,6 +65,58 @@ mod util;
 pub mod types;
 /// Module for shared constants
 pub mod consts;
+/// Module for reading on-disk persistent-data structures
+pub mod pdata;
+/// Module for reading and checking thin-pool metadata
+pub mod thinmeta;
+/// Module for reading and checking dm-cache metadata
+pub mod cachemeta;
+/// Module for thin-pool status and live metadata access
+pub mod thinpool;
+/// Module for building and validating mapping tables
+pub mod table;
+/// Module for comparing tables and planning reloads
+pub mod tablediff;
+/// Module for applying changes to several devices with rollback
+pub mod transaction;
+/// Module for the dependency graph of DM devices
+pub mod depgraph;
+/// Module for exporting the DM topology as DOT or JSON
+pub mod topology;
+/// Module for running against the kernel or a simulated device-mapper
+pub mod backend;
+/// Module for saving and restoring the state of all devices
+pub mod state;
+/// Module for reconciling devices with a desired state
+pub mod reconcile;
+/// Module for growing and shrinking linear and striped devices
+pub mod resize;
+/// Module for allocating volumes from backing devices
+pub mod allocator;
+/// Module for dm-mirror tables and status
+pub mod mirror;
+/// Module for moving volumes between backing devices
+pub mod migrate;
+/// Module for dm-clone targets
+pub mod clone;
+/// Module for dm-log-writes targets and replaying their logs
+pub mod logwrites;
+/// Module for dm-switch targets and their region tables
+pub mod switch;
+/// Module for dm-vdo targets
+pub mod vdo;
+/// Module for dm-stats regions and counters
+pub mod stats;
+/// Module for monitoring I/O on DM devices using dm-stats
+pub mod iostat;
+/// Module for dm-cache target status
+pub mod cache;
+/// Module for dm-raid target status
+pub mod raid;
+/// Module for exporting DM metrics in the Prometheus text format
+pub mod exporter;
+/// Module for listening for uevents about DM devices
+pub mod uevent;
 
 use std::fs::File;
 use std::io;
@@ -182,6 +235,17 @@ impl Device {
 impl FromStr for Device {
     type Err = Error;
     fn from_str(s: &str) -> io::Result<Device> {
+        // "<major>:<minor>", as produced by dstr() and DM table output
+        let spl: Vec<_> = s.split(':').collect();
+        if spl.len() == 2 {
+            if let (Ok(major), Ok(minor)) = (spl[0].parse::<u32>(), spl[1].parse::<u8>()) {
+                return Ok(Device {
+                    major: major,
+                    minor: minor,
+                });
+            }
+        }
+
         match s.parse::<i64>() {
             Ok(x) => Ok(Device::from(x as u64)),
             Err(_) => {
@@ -626,6 +690,20 @@ impl DM {
                        name: &DevId,
                        flags: DmFlags)
                        -> io::Result<(DeviceInfo, Vec<TargetLine>)> {
+        self.device_wait_event(name, 0, flags)
+    }
+
+    /// Wait for a device's event number to differ from `event_nr`.
+    ///
+    /// Pass the `event_nr()` of a `DeviceInfo` obtained before
+    /// checking the device's state, so that events occurring in
+    /// between are not missed. Otherwise this behaves just like
+    /// `device_wait`.
+    pub fn device_wait_event(&self,
+                             name: &DevId,
+                             event_nr: u32,
+                             flags: DmFlags)
+                             -> io::Result<(DeviceInfo, Vec<TargetLine>)> {
         let mut hdr: dmi::Struct_dm_ioctl = Default::default();
 
         let clean_flags = DM_QUERY_INACTIVE_TABLE & flags;
@@ -636,6 +714,8 @@ impl DM {
             DevId::Uuid(uuid) => Self::hdr_set_uuid(&mut hdr, uuid),
         };
 
+        hdr.event_nr = event_nr;
+
         let data_out = try!(self.do_ioctl(dmi::DM_DEV_WAIT_CMD as u8, &mut hdr, None));
 
         let status = try!(Self::parse_table_status(hdr.target_count, &data_out));
@@ -650,6 +730,9 @@ impl DM {
     ///
     /// `params` are target-specific, please see [Linux kernel documentation](https://git.kernel.org/cgit/linux/kernel/git/torvalds/linux.git/tree/Documentation/device-mapper) for more.
     ///
+    /// The kernel rejects invalid layouts with EINVAL; `table::Table`
+    /// can be used to build a table that is checked beforehand.
+    ///
     /// # Example
     ///
     /// ```no_run
@@ -785,14 +868,21 @@ impl DM {
 
     // Both table_status and dev_wait return table status, so
     // unify table status parsing.
+    //
+    // Each target spec is followed by its params. Unlike table_load's
+    // input, the kernel sets each spec's "next" to the offset of the
+    // following spec from the start of the output buffer.
     fn parse_table_status(count: u32, buf: &[u8]) -> io::Result<Vec<(u64, u64, String, String)>> {
         let mut targets = Vec::new();
-        if buf.len() > 0 {
+        if !buf.is_empty() {
             let mut next_off = 0;
-            let mut result = &buf[..];
+            let spec_size = size_of::<dmi::Struct_dm_target_spec>();
 
             for _ in 0..count {
-                result = &result[next_off..];
+                if next_off + spec_size > buf.len() {
+                    return Err(Error::new(Other, "bad data from ioctl"));
+                }
+                let result = &buf[next_off..];
                 let targ = unsafe {
                     (result.as_ptr() as *const dmi::Struct_dm_target_spec).as_ref().unwrap()
                 };
@@ -804,8 +894,8 @@ impl DM {
                 };
 
                 let params = {
-                    let slc = slice_to_null(&buf[size_of::<dmi::Struct_dm_target_spec>()..])
-                        .expect("bad data from ioctl");
+                    let slc = try!(slice_to_null(&result[spec_size..])
+                        .ok_or_else(|| Error::new(Other, "bad data from ioctl")));
                     String::from_utf8_lossy(slc).into_owned()
                 };
 
@@ -972,3 +1062,64 @@ fn slice_to_null(slc: &[u8]) -> Option<&[u8]> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{size_of, transmute};
+    use std::slice;
+
+    use super::*;
+
+    #[test]
+    fn parse_device() {
+        let dev = Device {
+            major: 253,
+            minor: 3,
+        };
+        assert_eq!(dev.dstr().parse::<Device>().unwrap(), dev);
+        assert_eq!("64771".parse::<Device>().unwrap(), dev);
+    }
+
+    // Lay out targets the way the kernel's retrieve_status() does:
+    // each spec followed by its params, 8-byte aligned, with "next"
+    // counted from the start of the buffer.
+    fn status_buf(targets: &[(u64, u64, &str, &str)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for t in targets {
+            let mut targ = dmi::Struct_dm_target_spec {
+                sector_start: t.0,
+                length: t.1,
+                ..Default::default()
+            };
+            let dst: &mut [u8; 16] = unsafe { transmute(&mut targ.target_type) };
+            dst[..t.2.len()].clone_from_slice(t.2.as_bytes());
+
+            let start = buf.len();
+            let end = start + size_of::<dmi::Struct_dm_target_spec>() + t.3.len() + 1;
+            targ.next = ((end + 7) & !7) as u32;
+
+            let spec = unsafe {
+                slice::from_raw_parts(&targ as *const dmi::Struct_dm_target_spec as *const u8,
+                                      size_of::<dmi::Struct_dm_target_spec>())
+            };
+            buf.extend_from_slice(spec);
+            buf.extend_from_slice(t.3.as_bytes());
+            buf.resize(targ.next as usize, 0);
+        }
+        buf
+    }
+
+    #[test]
+    fn parse_multi_target_table_status() {
+        let targets = [(0, 2048, "linear", "8:16 2048"),
+                       (2048, 4096, "striped", "2 128 8:32 0 8:48 0"),
+                       (6144, 1024, "linear", "8:64 384")];
+        let buf = status_buf(&targets);
+
+        let parsed = DM::parse_table_status(targets.len() as u32, &buf).unwrap();
+        let expected: Vec<_> = targets.iter()
+            .map(|t| (t.0, t.1, t.2.to_owned(), t.3.to_owned()))
+            .collect();
+        assert_eq!(parsed, expected);
+    }
+}
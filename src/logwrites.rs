@@ -0,0 +1,359 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// The dm-log-writes target passes writes through to a device and
+// records each one, in order, on a log device. The log starts with a
+// superblock in its first block; every entry then takes one block
+// for its header, which a mark's name follows, and is followed by the
+// written data, except for discards.
+
+use std::fs::File;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput, NotFound};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use {DM, DevId, Device, TargetLine};
+use pdata::{le32, le64};
+use types::{Bytes, Sectors};
+
+/// The magic number in a log's superblock.
+pub const LOG_WRITES_MAGIC: u64 = 0x6a736677736872;
+/// The log format version this module reads.
+pub const LOG_WRITES_VERSION: u64 = 1;
+
+const SUPER_SIZE: usize = 28;
+const ENTRY_SIZE: usize = 32;
+
+bitflags!(
+    /// Flags of a logged write.
+    flags LogEntryFlags: u64 {
+        /// The write was, or carried, a flush.
+        const LOG_FLUSH    = (1 << 0),
+        /// The write was FUA.
+        const LOG_FUA      = (1 << 1),
+        /// The entry is a discard, and has no data.
+        const LOG_DISCARD  = (1 << 2),
+        /// The entry is a mark, sent as a message.
+        const LOG_MARK     = (1 << 3),
+        /// The write was tagged as filesystem metadata.
+        const LOG_METADATA = (1 << 4),
+    }
+);
+
+/// A dm-log-writes target, passing writes through to `dev` and
+/// logging them on `log_dev`.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::logwrites::LogWritesTarget;
+/// use devicemapper::types::Sectors;
+///
+/// let target = LogWritesTarget::new(Device { major: 7, minor: 0 },
+///                                   Device { major: 7, minor: 1 });
+///
+/// assert_eq!(target.target(Sectors(0), Sectors(2048)),
+///            (0, 2048, "log-writes".to_owned(), "7:0 7:1".to_owned()));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogWritesTarget {
+    dev: Device,
+    log_dev: Device,
+}
+
+impl LogWritesTarget {
+    /// A target logging writes to `dev` on `log_dev`.
+    pub fn new(dev: Device, log_dev: Device) -> LogWritesTarget {
+        LogWritesTarget {
+            dev: dev,
+            log_dev: log_dev,
+        }
+    }
+
+    /// The target's params, for `DM::table_load()`.
+    pub fn params(&self) -> String {
+        format!("{} {}", self.dev.dstr(), self.log_dev.dstr())
+    }
+
+    /// A target line of `length` sectors starting at `start`, which is
+    /// usually the size of `dev`.
+    pub fn target(&self, start: Sectors, length: Sectors) -> TargetLine {
+        (*start, *length, "log-writes".to_owned(), self.params())
+    }
+}
+
+/// Add a mark named `name` to the log of the log-writes device `dev`,
+/// so a replay can stop there. The name cannot contain whitespace.
+pub fn mark(dm: &DM, dev: &DevId, name: &str) -> io::Result<()> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(Error::new(InvalidInput,
+                              format!("mark name \"{}\" is empty or contains whitespace", name)));
+    }
+    try!(dm.target_msg(dev, 0, &format!("mark {}", name)));
+    Ok(())
+}
+
+/// One entry in a write log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    /// The entry's position in the log, from 0.
+    pub index: u64,
+    /// Where on the device the write went.
+    pub offset: Bytes,
+    /// How much was written or discarded.
+    pub length: Bytes,
+    /// The write's flags.
+    pub flags: LogEntryFlags,
+    /// The mark's name, if the entry is a mark.
+    pub mark: Option<String>,
+    // Where the data starts in the log.
+    data_pos: u64,
+}
+
+impl LogEntry {
+    /// True if the entry is a mark.
+    pub fn is_mark(&self) -> bool {
+        self.flags.contains(LOG_MARK)
+    }
+
+    /// True if the entry is a discard.
+    pub fn is_discard(&self) -> bool {
+        self.flags.contains(LOG_DISCARD)
+    }
+}
+
+/// A reader for the log device of a log-writes target. The log
+/// should not be written to while it is read.
+#[derive(Debug)]
+pub struct LogReader {
+    file: File,
+    sector_size: u64,
+    nr_entries: u64,
+}
+
+impl LogReader {
+    /// Open the log at `path` and read its superblock.
+    pub fn open(path: &Path) -> io::Result<LogReader> {
+        let file = try!(File::open(path));
+
+        let mut buf = [0u8; SUPER_SIZE];
+        try!(file.read_exact_at(&mut buf, 0));
+
+        if le64(&buf, 0) != LOG_WRITES_MAGIC {
+            return Err(Error::new(InvalidData, "bad magic in write log superblock"));
+        }
+        let version = le64(&buf, 8);
+        if version != LOG_WRITES_VERSION {
+            return Err(Error::new(InvalidData,
+                                  format!("unsupported write log version {}", version)));
+        }
+        let sector_size = le32(&buf, 24) as u64;
+        if sector_size < ENTRY_SIZE as u64 || !sector_size.is_power_of_two() {
+            return Err(Error::new(InvalidData,
+                                  format!("bad write log sector size {}", sector_size)));
+        }
+
+        Ok(LogReader {
+            file: file,
+            sector_size: sector_size,
+            nr_entries: le64(&buf, 16),
+        })
+    }
+
+    /// The log's sector size, which is the logical block size of the
+    /// logged device.
+    pub fn sector_size(&self) -> Bytes {
+        Bytes(self.sector_size)
+    }
+
+    /// The number of entries in the log, as of the last flush.
+    pub fn nr_entries(&self) -> u64 {
+        self.nr_entries
+    }
+
+    /// The log's entries, in order.
+    pub fn entries(&self) -> LogEntries<'_> {
+        LogEntries {
+            log: self,
+            next: 0,
+            pos: self.sector_size,
+        }
+    }
+
+    /// The data written by `entry`, which must be one of this log's.
+    pub fn read_data(&self, entry: &LogEntry) -> io::Result<Vec<u8>> {
+        if entry.is_discard() {
+            return Ok(Vec::new());
+        }
+        let mut buf = vec![0u8; *entry.length as usize];
+        try!(self.file.read_exact_at(&mut buf, entry.data_pos));
+        Ok(buf)
+    }
+
+    /// The index of the first mark named `name`, if there is one.
+    pub fn find_mark(&self, name: &str) -> io::Result<Option<u64>> {
+        for entry in self.entries() {
+            let entry = try!(entry);
+            if entry.mark.as_ref().is_some_and(|m| m == name) {
+                return Ok(Some(entry.index));
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_entry(&self, index: u64, pos: u64) -> io::Result<LogEntry> {
+        let mut buf = vec![0u8; self.sector_size as usize];
+        try!(self.file.read_exact_at(&mut buf, pos));
+
+        let flags = LogEntryFlags::from_bits_truncate(le64(&buf, 16));
+        let data_len = le64(&buf, 24);
+
+        let mark = if flags.contains(LOG_MARK) {
+            let end = ENTRY_SIZE as u64 + data_len;
+            if end > self.sector_size {
+                return Err(Error::new(InvalidData,
+                                      format!("mark in write log entry {} is too long", index)));
+            }
+            let name = &buf[ENTRY_SIZE..end as usize];
+            let name = name.split(|b| *b == 0).next().unwrap_or(name);
+            Some(String::from_utf8_lossy(name).into_owned())
+        } else {
+            None
+        };
+
+        Ok(LogEntry {
+            index: index,
+            offset: Bytes(le64(&buf, 0) * self.sector_size),
+            length: Bytes(le64(&buf, 8) * self.sector_size),
+            flags: flags,
+            mark: mark,
+            data_pos: pos + self.sector_size,
+        })
+    }
+}
+
+/// An iterator over the entries of a write log.
+#[derive(Debug)]
+pub struct LogEntries<'a> {
+    log: &'a LogReader,
+    next: u64,
+    pos: u64,
+}
+
+impl<'a> Iterator for LogEntries<'a> {
+    type Item = io::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<io::Result<LogEntry>> {
+        if self.next >= self.log.nr_entries {
+            return None;
+        }
+
+        let entry = match self.log.read_entry(self.next, self.pos) {
+            Ok(entry) => entry,
+            Err(err) => {
+                // Stop after an error; the position of the next entry
+                // is unknown.
+                self.next = self.log.nr_entries;
+                return Some(Err(err));
+            }
+        };
+
+        self.next += 1;
+        self.pos = entry.data_pos;
+        if !entry.is_discard() {
+            self.pos += *entry.length;
+        }
+        Some(Ok(entry))
+    }
+}
+
+/// How far to replay a write log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayUntil<'a> {
+    /// Replay every entry.
+    End,
+    /// Replay entries up to and including the one with this index.
+    Entry(u64),
+    /// Replay entries up to and including the first mark with this
+    /// name.
+    Mark(&'a str),
+}
+
+/// Apply the entries of `log` to `dev`, in order, up to `until`.
+/// `dev` should hold what the logged device held when logging
+/// started, usually zeroes. Discarded ranges are zeroed, and flush or
+/// FUA entries sync `dev`.
+///
+/// Returns the number of entries applied.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::fs::OpenOptions;
+/// use std::path::Path;
+///
+/// use devicemapper::logwrites::{LogReader, ReplayUntil, replay};
+///
+/// let log = LogReader::open(Path::new("/dev/loop1")).unwrap();
+/// let dev = OpenOptions::new().write(true).open("/dev/loop2").unwrap();
+///
+/// replay(&log, &dev, ReplayUntil::Mark("after-fsync")).unwrap();
+/// // Check that the filesystem on /dev/loop2 is consistent...
+/// ```
+pub fn replay(log: &LogReader, dev: &File, until: ReplayUntil) -> io::Result<u64> {
+    let last = match until {
+        ReplayUntil::End => None,
+        ReplayUntil::Entry(index) => {
+            if index >= log.nr_entries() {
+                return Err(Error::new(InvalidInput,
+                                      format!("write log has only {} entries", log.nr_entries())));
+            }
+            Some(index)
+        }
+        ReplayUntil::Mark(name) => {
+            match try!(log.find_mark(name)) {
+                Some(index) => Some(index),
+                None => return Err(Error::new(NotFound, format!("no mark \"{}\" in write log", name))),
+            }
+        }
+    };
+
+    let mut applied = 0;
+    for entry in log.entries() {
+        let entry = try!(entry);
+
+        if entry.is_discard() {
+            try!(zero(dev, entry.offset, entry.length));
+        } else if *entry.length > 0 {
+            let data = try!(log.read_data(&entry));
+            try!(dev.write_all_at(&data, *entry.offset));
+        }
+        if entry.flags.intersects(LOG_FLUSH | LOG_FUA) {
+            try!(dev.sync_data());
+        }
+
+        applied += 1;
+        if last == Some(entry.index) {
+            break;
+        }
+    }
+
+    Ok(applied)
+}
+
+fn zero(dev: &File, offset: Bytes, length: Bytes) -> io::Result<()> {
+    const CHUNK: u64 = 1 << 20;
+    let zeroes = vec![0u8; CHUNK as usize];
+
+    let mut done = 0;
+    while done < *length {
+        let len = (*length - done).min(CHUNK);
+        try!(dev.write_all_at(&zeroes[..len as usize], *offset + done));
+        done += len;
+    }
+    Ok(())
+}
@@ -0,0 +1,506 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Error, Read, Write};
+use std::io::ErrorKind::{InvalidData, InvalidInput, NotFound, Other};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use serde;
+use serde::de::Error as DeError;
+use serde::ser::SerializeStruct;
+use serde_json;
+use serde_json::Value;
+
+use {DevId, Device, DmFlags, TargetLine, DM_STATUS_TABLE, DM_SUSPEND};
+use allocator::{Allocation, Allocator, Policy, Segment};
+use backend::Backend;
+use mirror::{self, MirrorStatus};
+use tablediff;
+use types::Sectors;
+
+/// Where one target of a volume is in its move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MoveState {
+    /// Still mapped onto the source.
+    Pending,
+    /// Mirrored onto the source and the destination while the
+    /// destination is synced. Reads come from the source.
+    Mirroring,
+    /// Mapped onto the destination. The source is no longer used.
+    Done,
+}
+
+impl MoveState {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            MoveState::Pending => "pending",
+            MoveState::Mirroring => "mirroring",
+            MoveState::Done => "done",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<MoveState> {
+        match s {
+            "pending" => Some(MoveState::Pending),
+            "mirroring" => Some(MoveState::Mirroring),
+            "done" => Some(MoveState::Done),
+            _ => None,
+        }
+    }
+}
+
+/// The move of one linear target from the source device to its
+/// destination.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetMove {
+    /// The target's index in the volume's table.
+    pub target: usize,
+    /// The source device and the offset the target maps onto.
+    pub source: (Device, Sectors),
+    /// The destination device and offset.
+    pub dest: (Device, Sectors),
+    /// How far the move has got.
+    pub state: MoveState,
+}
+
+/// A pvmove-style move of a volume's data off a backing device while
+/// the volume stays in use.
+///
+/// Each linear target on the source device is moved in turn: it is
+/// replaced by a dm-mirror target with the source and the destination
+/// as legs, and once the mirror is in sync, by a linear target on the
+/// destination. The volume's table at any point follows from the
+/// original table and the moves' states, which are saved to a file
+/// before each change, so that an interrupted migration can be picked
+/// up again with `Migration::load()` and `run()`.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use std::time::Duration;
+///
+/// use devicemapper::{DM, Device};
+/// use devicemapper::allocator::Allocator;
+/// use devicemapper::migrate::Migration;
+/// use devicemapper::types::Sectors;
+///
+/// let dm = DM::new().unwrap();
+/// let sdb = Device { major: 8, minor: 16 };
+/// let sdc = Device { major: 8, minor: 32 };
+///
+/// let mut allocator = Allocator::new(Sectors(2048));
+/// allocator.add_device(sdc, Sectors(1 << 24)).unwrap();
+///
+/// let state = Path::new("/var/lib/example/pvmove.json");
+/// let migration = Migration::plan(&dm, "example-vol", sdb, &mut allocator, Sectors(1024))
+///     .unwrap();
+/// migration.run(&dm, state, Duration::from_secs(1), |i, status| {
+///     println!("target {}: {}/{} regions", i, status.in_sync_regions, status.total_regions);
+///     Ok(true)
+/// }).unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    /// The name of the volume being moved.
+    pub volume: String,
+    /// The mirror region size.
+    pub region_size: Sectors,
+    /// The volume's table before the migration started.
+    pub table: Vec<TargetLine>,
+    /// The targets to move, in the order they are moved.
+    pub moves: Vec<TargetMove>,
+}
+
+impl Migration {
+    /// Plan moving every linear target of `volume` that maps onto
+    /// `from` to space allocated from `allocator`. `from` should not
+    /// be one of the allocator's devices, or must have no free space
+    /// in it.
+    ///
+    /// Only linear targets can be moved, so this fails if any other
+    /// target, e.g. a striped one, uses `from`.
+    pub fn plan<B: Backend>(dm: &B,
+                            volume: &str,
+                            from: Device,
+                            allocator: &mut Allocator,
+                            region_size: Sectors)
+                            -> io::Result<Migration> {
+        if *region_size == 0 || !(*region_size).is_power_of_two() {
+            return Err(Error::new(InvalidInput, "mirror region size must be a power of two"));
+        }
+
+        let (_, table) = try!(dm.table_status(&DevId::Name(volume), DM_STATUS_TABLE));
+        if table.is_empty() {
+            return Err(Error::new(InvalidInput, "device has no active table"));
+        }
+
+        let mut migration = Migration {
+            volume: volume.to_owned(),
+            region_size: region_size,
+            table: table,
+            moves: Vec::new(),
+        };
+
+        let mut allocations = Vec::new();
+        let result = migration.plan_moves(from, allocator, &mut allocations);
+        if result.is_err() {
+            for a in &allocations {
+                let _ = allocator.release(a);
+            }
+        }
+        result.map(|_| migration)
+    }
+
+    fn plan_moves(&mut self,
+                  from: Device,
+                  allocator: &mut Allocator,
+                  allocations: &mut Vec<Allocation>)
+                  -> io::Result<()> {
+        for (i, t) in self.table.iter().enumerate() {
+            let source = match try!(source_of(t)) {
+                Some((dev, off)) if dev == from => (dev, off),
+                Some(_) => continue,
+                None if uses_device(t, from) => {
+                    return Err(Error::new(InvalidInput,
+                                          format!("{} target {} uses {}, but only linear \
+                                                   targets can be moved",
+                                                  t.2,
+                                                  i,
+                                                  from.dstr())));
+                }
+                None => continue,
+            };
+
+            let allocation = try!(allocator.allocate(Sectors(t.1), Policy::Contiguous));
+            let dest = match allocation.segments[..] {
+                [Segment::Linear { device, start, .. }] => (device, start),
+                _ => unreachable!("a contiguous allocation has one linear segment"),
+            };
+            allocations.push(allocation);
+            if dest.0 == from {
+                return Err(Error::new(InvalidInput,
+                                      format!("space for target {} was allocated on the \
+                                               source device {}",
+                                              i,
+                                              from.dstr())));
+            }
+
+            self.moves.push(TargetMove {
+                target: i,
+                source: source,
+                dest: dest,
+                state: MoveState::Pending,
+            });
+        }
+
+        if self.moves.is_empty() {
+            return Err(Error::new(InvalidInput,
+                                  format!("{} does not use {}", self.volume, from.dstr())));
+        }
+        Ok(())
+    }
+
+    /// True if every target has been moved.
+    pub fn is_done(&self) -> bool {
+        self.moves.iter().all(|m| m.state == MoveState::Done)
+    }
+
+    /// The extents on the source device the volume used, as
+    /// (device, start, length). These are free once the migration is
+    /// done.
+    pub fn source_extents(&self) -> Vec<(Device, Sectors, Sectors)> {
+        self.moves
+            .iter()
+            .map(|m| (m.source.0, m.source.1, Sectors(self.table[m.target].1)))
+            .collect()
+    }
+
+    /// The extents the volume is moved to, as (device, start, length).
+    pub fn dest_extents(&self) -> Vec<(Device, Sectors, Sectors)> {
+        self.moves
+            .iter()
+            .map(|m| (m.dest.0, m.dest.1, Sectors(self.table[m.target].1)))
+            .collect()
+    }
+
+    /// The volume's table for the moves' current states.
+    pub fn current_table(&self) -> Vec<TargetLine> {
+        let mut table = self.table.clone();
+        for m in &self.moves {
+            let t = &mut table[m.target];
+            match m.state {
+                MoveState::Pending => {}
+                MoveState::Mirroring => {
+                    t.2 = "mirror".to_owned();
+                    t.3 = mirror::core_params(self.region_size, &[m.source, m.dest]);
+                }
+                MoveState::Done => {
+                    t.2 = "linear".to_owned();
+                    t.3 = format!("{} {}", m.dest.0.dstr(), *m.dest.1);
+                }
+            }
+        }
+        table
+    }
+
+    /// Read a migration saved by `save()`.
+    pub fn load(path: &Path) -> io::Result<Migration> {
+        let mut json = String::new();
+        try!(try!(File::open(path)).read_to_string(&mut json));
+        serde_json::from_str(&json).map_err(|e| Error::new(InvalidData, e))
+    }
+
+    /// Save the migration to `path`. The file is replaced atomically,
+    /// so it holds either the old or the new state after a crash.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = try!(serde_json::to_string_pretty(self).map_err(|e| Error::new(InvalidData, e)));
+
+        let mut tmp_name = path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp = Path::new(&tmp_name);
+        {
+            let mut f = try!(File::create(tmp));
+            try!(f.write_all(json.as_bytes()));
+            try!(f.sync_all());
+        }
+        fs::rename(tmp, path)
+    }
+
+    /// Move the volume's data, saving progress to `state_path`. If
+    /// the migration was interrupted, the volume is first reloaded
+    /// with the table for the saved state, which restarts the sync of
+    /// a target that was being mirrored.
+    ///
+    /// While a target is mirrored, its status is polled every
+    /// `interval` and passed to `progress` with the move's index.
+    /// `progress` returns false to stop; the migration can be resumed
+    /// later from the saved state. A mirror leg failing stops the
+    /// migration with an error, leaving reads on the source.
+    ///
+    /// Returns true once every target is moved, and removes the state
+    /// file.
+    pub fn run<B, F>(mut self,
+                     dm: &B,
+                     state_path: &Path,
+                     interval: Duration,
+                     mut progress: F)
+                     -> io::Result<bool>
+        where B: Backend,
+              F: FnMut(usize, &MirrorStatus) -> io::Result<bool>
+    {
+        let volume = self.volume.clone();
+        let id = DevId::Name(&volume);
+        try!(self.save(state_path));
+
+        let (_, live) = try!(dm.table_status(&id, DM_STATUS_TABLE));
+        let table = self.current_table();
+        if !tablediff::diff(&live, &table).is_noop() {
+            try!(reload(dm, &id, &table));
+        }
+
+        for i in 0..self.moves.len() {
+            if self.moves[i].state == MoveState::Done {
+                continue;
+            }
+
+            if self.moves[i].state == MoveState::Pending {
+                self.moves[i].state = MoveState::Mirroring;
+                try!(self.save(state_path));
+                try!(reload(dm, &id, &self.current_table()));
+            }
+
+            let target = self.moves[i].target;
+            loop {
+                let status = try!(mirror_status(dm, &id, target));
+                if !status.healthy() {
+                    return Err(Error::new(Other,
+                                          format!("mirror of target {} of {} failed: {:?}",
+                                                  target,
+                                                  self.volume,
+                                                  status.health)));
+                }
+                if !try!(progress(i, &status)) {
+                    return Ok(false);
+                }
+                if status.in_sync() {
+                    break;
+                }
+                thread::sleep(interval);
+            }
+
+            // Record the move as done before switching to the
+            // destination: once writes go only there, resyncing from
+            // the source would lose them.
+            self.moves[i].state = MoveState::Done;
+            try!(self.save(state_path));
+            try!(reload(dm, &id, &self.current_table()));
+        }
+
+        if let Err(err) = fs::remove_file(state_path) {
+            if err.kind() != NotFound {
+                return Err(err);
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The device and offset a linear target maps onto.
+fn source_of(target: &TargetLine) -> io::Result<Option<(Device, Sectors)>> {
+    if target.2 != "linear" {
+        return Ok(None);
+    }
+    let fields: Vec<_> = target.3.split_whitespace().collect();
+    // As reported by the kernel, so the device is "<major>:<minor>".
+    if fields.len() != 2 || !fields[0].contains(':') {
+        return Err(Error::new(InvalidData, format!("bad linear params \"{}\"", target.3)));
+    }
+    let dev = try!(fields[0].parse::<Device>());
+    let off = try!(fields[1]
+        .parse()
+        .map_err(|_| Error::new(InvalidData, format!("bad linear params \"{}\"", target.3))));
+    Ok(Some((dev, Sectors(off))))
+}
+
+// Whether any of a target's params names `dev`. The kernel reports
+// devices as "<major>:<minor>", and other params never look like that.
+fn uses_device(target: &TargetLine, dev: Device) -> bool {
+    let dstr = dev.dstr();
+    target.3.split_whitespace().any(|f| f == dstr)
+}
+
+fn reload<B: Backend>(dm: &B, id: &DevId, table: &[TargetLine]) -> io::Result<()> {
+    try!(dm.table_load(id, table));
+    try!(dm.device_suspend(id, DM_SUSPEND));
+    try!(dm.device_suspend(id, DmFlags::empty()));
+    Ok(())
+}
+
+fn mirror_status<B: Backend>(dm: &B, id: &DevId, target: usize) -> io::Result<MirrorStatus> {
+    let (_, lines) = try!(dm.table_status(id, DmFlags::empty()));
+    match lines.get(target) {
+        Some((_, _, target_type, params)) if target_type == "mirror" => params.parse(),
+        _ => Err(Error::new(InvalidData, format!("target {} is not a mirror", target))),
+    }
+}
+
+impl serde::Serialize for TargetMove {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("TargetMove", 6));
+        try!(state.serialize_field("target", &self.target));
+        try!(state.serialize_field("source", &self.source.0.dstr()));
+        try!(state.serialize_field("source_offset", &self.source.1));
+        try!(state.serialize_field("dest", &self.dest.0.dstr()));
+        try!(state.serialize_field("dest_offset", &self.dest.1));
+        try!(state.serialize_field("state", self.state.as_str()));
+        state.end()
+    }
+}
+
+impl serde::Serialize for Migration {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let table: Vec<_> = self.table
+            .iter()
+            .map(|t| {
+                let mut map = BTreeMap::new();
+                map.insert("start", Value::from(t.0));
+                map.insert("length", Value::from(t.1));
+                map.insert("type", Value::from(&t.2[..]));
+                map.insert("params", Value::from(&t.3[..]));
+                map
+            })
+            .collect();
+
+        let mut state = try!(serializer.serialize_struct("Migration", 4));
+        try!(state.serialize_field("volume", &self.volume));
+        try!(state.serialize_field("region_size", &self.region_size));
+        try!(state.serialize_field("table", &table));
+        try!(state.serialize_field("moves", &self.moves));
+        state.end()
+    }
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+    value.get(name).ok_or_else(|| format!("missing field \"{}\"", name))
+}
+
+fn str_field(value: &Value, name: &str) -> Result<String, String> {
+    try!(field(value, name))
+        .as_str()
+        .map(|s| s.to_owned())
+        .ok_or_else(|| format!("field \"{}\" is not a string", name))
+}
+
+fn u64_field(value: &Value, name: &str) -> Result<u64, String> {
+    try!(field(value, name))
+        .as_u64()
+        .ok_or_else(|| format!("field \"{}\" is not an unsigned integer", name))
+}
+
+fn array_field<'a>(value: &'a Value, name: &str) -> Result<&'a Vec<Value>, String> {
+    try!(field(value, name))
+        .as_array()
+        .ok_or_else(|| format!("field \"{}\" is not an array", name))
+}
+
+fn device_field(value: &Value, name: &str) -> Result<Device, String> {
+    let dev = try!(str_field(value, name));
+    dev.parse().map_err(|_| format!("field \"{}\" is not a device", name))
+}
+
+fn move_from_value(value: &Value) -> Result<TargetMove, String> {
+    let state = try!(str_field(value, "state"));
+    Ok(TargetMove {
+        target: try!(u64_field(value, "target")) as usize,
+        source: (try!(device_field(value, "source")), Sectors(try!(u64_field(value, "source_offset")))),
+        dest: (try!(device_field(value, "dest")), Sectors(try!(u64_field(value, "dest_offset")))),
+        state: try!(MoveState::from_str(&state).ok_or_else(|| format!("unknown state \"{}\"", state))),
+    })
+}
+
+fn migration_from_value(value: &Value) -> Result<Migration, String> {
+    let mut table = Vec::new();
+    for t in try!(array_field(value, "table")) {
+        table.push((try!(u64_field(t, "start")),
+                    try!(u64_field(t, "length")),
+                    try!(str_field(t, "type")),
+                    try!(str_field(t, "params"))));
+    }
+
+    let mut moves = Vec::new();
+    for m in try!(array_field(value, "moves")) {
+        let m = try!(move_from_value(m));
+        if m.target >= table.len() {
+            return Err(format!("move of target {} is past the end of the table", m.target));
+        }
+        moves.push(m);
+    }
+
+    Ok(Migration {
+        volume: try!(str_field(value, "volume")),
+        region_size: Sectors(try!(u64_field(value, "region_size"))),
+        table: table,
+        moves: moves,
+    })
+}
+
+impl serde::Deserialize for Migration {
+    fn deserialize<D>(deserializer: D) -> Result<Migration, D::Error>
+        where D: serde::de::Deserializer
+    {
+        let value: Value = try!(serde::Deserialize::deserialize(deserializer));
+        migration_from_value(&value).map_err(D::Error::custom)
+    }
+}
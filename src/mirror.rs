@@ -0,0 +1,137 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+use std::str::FromStr;
+
+use Device;
+use types::Sectors;
+
+/// The state of one mirror leg, as reported in dm-mirror status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegHealth {
+    /// The leg is working ('A').
+    Alive,
+    /// A write to the leg failed ('D').
+    WriteFailure,
+    /// Syncing the leg failed ('S').
+    SyncFailure,
+    /// A read from the leg failed ('R').
+    ReadFailure,
+    /// A flush of the leg failed ('F').
+    FlushFailure,
+    /// Any other state ('U').
+    Unknown,
+}
+
+impl LegHealth {
+    fn from_char(c: char) -> LegHealth {
+        match c {
+            'A' => LegHealth::Alive,
+            'D' => LegHealth::WriteFailure,
+            'S' => LegHealth::SyncFailure,
+            'R' => LegHealth::ReadFailure,
+            'F' => LegHealth::FlushFailure,
+            _ => LegHealth::Unknown,
+        }
+    }
+}
+
+/// The status of a dm-mirror target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorStatus {
+    /// The mirror's legs.
+    pub devices: Vec<Device>,
+    /// How many regions are in sync on every leg.
+    pub in_sync_regions: u64,
+    /// How many regions the mirror has.
+    pub total_regions: u64,
+    /// The health of each leg, in the order of `devices`.
+    pub health: Vec<LegHealth>,
+    /// The dirty log's status fields, e.g. ["core"].
+    pub log: Vec<String>,
+}
+
+impl MirrorStatus {
+    /// True if every region is in sync.
+    pub fn in_sync(&self) -> bool {
+        self.in_sync_regions == self.total_regions
+    }
+
+    /// True if every leg is alive.
+    pub fn healthy(&self) -> bool {
+        self.health.iter().all(|h| *h == LegHealth::Alive)
+    }
+}
+
+impl FromStr for MirrorStatus {
+    type Err = Error;
+
+    /// Parse dm-mirror status: "<#legs> <leg>... <in sync>/<regions>
+    /// 1 <health chars> <#log fields> <log field>...".
+    fn from_str(s: &str) -> io::Result<MirrorStatus> {
+        let bad = || Error::new(InvalidData, format!("bad mirror status \"{}\"", s));
+        let fields: Vec<_> = s.split_whitespace().collect();
+
+        let nr_legs = try!(fields.first().and_then(|f| f.parse::<usize>().ok()).ok_or_else(&bad));
+        if fields.len() < nr_legs + 4 {
+            return Err(bad());
+        }
+
+        let mut devices = Vec::new();
+        for f in &fields[1..nr_legs + 1] {
+            devices.push(try!(f.parse::<Device>().map_err(|_| bad())));
+        }
+
+        let mut sync = fields[nr_legs + 1].split('/');
+        let (in_sync, total) = match (sync.next().map(|n| n.parse::<u64>()),
+                                      sync.next().map(|n| n.parse::<u64>())) {
+            (Some(Ok(in_sync)), Some(Ok(total))) => (in_sync, total),
+            _ => return Err(bad()),
+        };
+
+        let health: Vec<_> = fields[nr_legs + 3].chars().map(LegHealth::from_char).collect();
+        if health.len() != nr_legs {
+            return Err(bad());
+        }
+
+        let log = fields.get(nr_legs + 5..)
+            .map_or_else(Vec::new, |f| f.iter().map(|s| (*s).to_owned()).collect());
+
+        Ok(MirrorStatus {
+            devices: devices,
+            in_sync_regions: in_sync,
+            total_regions: total,
+            health: health,
+            log: log,
+        })
+    }
+}
+
+/// Params for a dm-mirror target with an in-memory ("core") log,
+/// copying `region_size` regions between `legs`, each a device and
+/// offset. The first leg is the one read from until the others are in
+/// sync.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::mirror::core_params;
+/// use devicemapper::types::Sectors;
+///
+/// let legs = [(Device { major: 8, minor: 16 }, Sectors(0)),
+///             (Device { major: 8, minor: 32 }, Sectors(2048))];
+///
+/// assert_eq!(core_params(Sectors(1024), &legs), "core 1 1024 2 8:16 0 8:32 2048");
+/// ```
+pub fn core_params(region_size: Sectors, legs: &[(Device, Sectors)]) -> String {
+    let mut params = format!("core 1 {} {}", *region_size, legs.len());
+    for &(dev, off) in legs {
+        params.push_str(&format!(" {} {}", dev.dstr(), *off));
+    }
+    params
+}
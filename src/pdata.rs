@@ -0,0 +1,805 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Reading the kernel's "persistent-data" on-disk structures.
+//
+// dm-thin and dm-cache both store their metadata using the same
+// building blocks: 4KiB checksummed blocks, btrees, and reference
+// counting space maps. This module reads and validates those
+// structures so that they can be interpreted by the target-specific
+// metadata modules.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Error, Seek, SeekFrom};
+use std::io::ErrorKind::{InvalidData, InvalidInput};
+use std::os::unix::fs::{FileExt, FileTypeExt, OpenOptionsExt};
+use std::path::Path;
+
+use libc;
+
+use util::align_to;
+
+/// Size of a metadata block, in bytes.
+pub const BLOCK_SIZE: usize = 4096;
+
+const BTREE_CSUM_XOR: u32 = 121107;
+const BITMAP_CSUM_XOR: u32 = 240779;
+const INDEX_CSUM_XOR: u32 = 160478;
+const ARRAY_CSUM_XOR: u32 = 595846735;
+
+const INTERNAL_NODE: u32 = 1;
+const LEAF_NODE: u32 = 2;
+const NODE_HEADER_SIZE: usize = 32;
+
+const BITMAP_HEADER_SIZE: usize = 16;
+const ENTRIES_PER_BITMAP: u64 = ((BLOCK_SIZE - BITMAP_HEADER_SIZE) * 4) as u64;
+const INDEX_ENTRY_SIZE: usize = 16;
+const ARRAY_HEADER_SIZE: usize = 24;
+const MAX_METADATA_BITMAPS: usize = 255;
+
+/// Size of a space map root as stored in a superblock, in bytes.
+pub const SPACE_MAP_ROOT_SIZE: usize = 128;
+
+/// Read a little-endian u32 at `off`.
+pub fn le32(buf: &[u8], off: usize) -> u32 {
+    (0..4).fold(0, |acc, i| acc | (buf[off + i] as u32) << (8 * i))
+}
+
+/// Read a little-endian u64 at `off`.
+pub fn le64(buf: &[u8], off: usize) -> u64 {
+    (0..8).fold(0, |acc, i| acc | (buf[off + i] as u64) << (8 * i))
+}
+
+// The kernel's crc32c() does not invert the result, and
+// dm_bm_checksum() starts from ~0 and xors in a per-structure value.
+fn crc32c(seed: u32, data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+
+    data.iter()
+        .fold(seed,
+              |crc, b| table[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Check that a block's leading checksum field matches its contents,
+/// and that the block number it records is where it was read from.
+pub fn validate_block(block: &[u8], blocknr: u64, csum_xor: u32) -> Result<(), String> {
+    validate(block, blocknr, 8, csum_xor)
+}
+
+// Most structures record their block number just after the checksum
+// and flags, array blocks record it after their header counts.
+fn validate(block: &[u8], blocknr: u64, blocknr_off: usize, csum_xor: u32) -> Result<(), String> {
+    let csum = crc32c(!0, &block[4..]) ^ csum_xor;
+    if le32(block, 0) != csum {
+        return Err(format!("checksum mismatch (on-disk {:#x}, computed {:#x})",
+                           le32(block, 0),
+                           csum));
+    }
+
+    if le64(block, blocknr_off) != blocknr {
+        return Err(format!("block claims to be block {}", le64(block, blocknr_off)));
+    }
+
+    Ok(())
+}
+
+/// A device or image file containing persistent-data metadata.
+pub struct MetadataDev {
+    file: File,
+    nr_blocks: u64,
+}
+
+impl MetadataDev {
+    /// Open a metadata device or image file for reading.
+    ///
+    /// Block devices are opened with O_DIRECT so that blocks written
+    /// by the kernel are not hidden by stale cached pages.
+    pub fn open(path: &Path) -> io::Result<MetadataDev> {
+        let is_blk = try!(path.metadata()).file_type().is_block_device();
+
+        let mut opts = OpenOptions::new();
+        opts.read(true);
+        if is_blk {
+            opts.custom_flags(libc::O_DIRECT);
+        }
+
+        let mut file = try!(opts.open(path));
+        let len = try!(file.seek(SeekFrom::End(0)));
+
+        Ok(MetadataDev {
+            file: file,
+            nr_blocks: len / BLOCK_SIZE as u64,
+        })
+    }
+
+    /// The number of whole metadata blocks on the device.
+    pub fn nr_blocks(&self) -> u64 {
+        self.nr_blocks
+    }
+
+    /// Read metadata block `b`.
+    pub fn read_block(&self, b: u64) -> io::Result<Vec<u8>> {
+        if b >= self.nr_blocks {
+            return Err(Error::new(InvalidInput,
+                                  format!("block {} beyond end of metadata device", b)));
+        }
+
+        // O_DIRECT requires an aligned buffer
+        let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+        let addr = buf.as_ptr() as usize;
+        let off = align_to(addr, BLOCK_SIZE) - addr;
+
+        try!(self.file.read_exact_at(&mut buf[off..off + BLOCK_SIZE], b * BLOCK_SIZE as u64));
+
+        Ok(buf[off..off + BLOCK_SIZE].to_vec())
+    }
+}
+
+/// A problem found while checking metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataError {
+    /// The superblock is missing, corrupt, or describes an unsupported format.
+    BadSuperblock(String),
+    /// A metadata block could not be read or failed validation.
+    BadBlock {
+        /// The metadata block number.
+        block: u64,
+        /// What was wrong with it.
+        reason: String,
+    },
+    /// A block number refers beyond the end of the device it addresses.
+    OutOfBounds {
+        /// Which structure contained the reference.
+        context: String,
+        /// The block number referred to.
+        block: u64,
+        /// The number of blocks actually available.
+        nr_blocks: u64,
+    },
+    /// The metadata space map's reference count for a block does not
+    /// match the number of references found.
+    MetadataRefCount {
+        /// The metadata block number.
+        block: u64,
+        /// The number of references found.
+        expected: u32,
+        /// The count recorded in the space map.
+        actual: u32,
+    },
+    /// The data space map's reference count for a block does not
+    /// match the number of references found.
+    DataRefCount {
+        /// The data block number.
+        block: u64,
+        /// The number of references found.
+        expected: u32,
+        /// The count recorded in the space map.
+        actual: u32,
+    },
+    /// A summary counter stored in the metadata disagrees with the
+    /// structures it summarizes.
+    Counter {
+        /// What is being counted.
+        context: String,
+        /// The value computed from the metadata.
+        expected: u64,
+        /// The value recorded in the metadata.
+        actual: u64,
+    },
+}
+
+impl MetadataError {
+    /// Whether the error can be fixed by rebuilding derived
+    /// structures (space maps and counters) without losing mappings.
+    pub fn repairable(&self) -> bool {
+        match *self {
+            MetadataError::BadSuperblock(_) |
+            MetadataError::BadBlock { .. } |
+            MetadataError::OutOfBounds { .. } => false,
+            MetadataError::MetadataRefCount { .. } |
+            MetadataError::DataRefCount { .. } |
+            MetadataError::Counter { .. } => true,
+        }
+    }
+}
+
+impl fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            MetadataError::BadSuperblock(ref reason) => write!(f, "bad superblock: {}", reason),
+            MetadataError::BadBlock { block, ref reason } => {
+                write!(f, "metadata block {}: {}", block, reason)
+            }
+            MetadataError::OutOfBounds { ref context, block, nr_blocks } => {
+                write!(f,
+                       "{} refers to block {}, but only {} blocks exist",
+                       context,
+                       block,
+                       nr_blocks)
+            }
+            MetadataError::MetadataRefCount { block, expected, actual } => {
+                write!(f,
+                       "metadata block {}: ref count is {}, expected {}",
+                       block,
+                       actual,
+                       expected)
+            }
+            MetadataError::DataRefCount { block, expected, actual } => {
+                write!(f,
+                       "data block {}: ref count is {}, expected {}",
+                       block,
+                       actual,
+                       expected)
+            }
+            MetadataError::Counter { ref context, expected, actual } => {
+                write!(f, "{} is {}, expected {}", context, actual, expected)
+            }
+        }
+    }
+}
+
+/// The result of checking a metadata device.
+#[derive(Debug, Clone, Default)]
+pub struct CheckReport {
+    /// Every problem found, in the order found.
+    pub errors: Vec<MetadataError>,
+}
+
+impl CheckReport {
+    /// True if no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// True if all problems found can be repaired without data loss.
+    pub fn is_repairable(&self) -> bool {
+        self.errors.iter().all(|e| e.repairable())
+    }
+
+    /// Problems that can be repaired by rebuilding derived structures.
+    pub fn repairable(&self) -> Vec<&MetadataError> {
+        self.errors.iter().filter(|e| e.repairable()).collect()
+    }
+
+    /// Problems that cannot be repaired without losing metadata.
+    pub fn unrepairable(&self) -> Vec<&MetadataError> {
+        self.errors.iter().filter(|e| !e.repairable()).collect()
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        if self.errors.is_empty() {
+            return write!(f, "no errors found");
+        }
+        for err in &self.errors {
+            try!(writeln!(f, "{}", err));
+        }
+        Ok(())
+    }
+}
+
+/// A validated btree node.
+pub struct Node {
+    /// The block the node was read from.
+    pub blocknr: u64,
+    /// Whether this is a leaf node. Internal node values are child
+    /// block numbers.
+    pub leaf: bool,
+    /// The node's keys, in ascending order.
+    pub keys: Vec<u64>,
+    value_size: usize,
+    values: Vec<u8>,
+}
+
+impl Node {
+    /// The raw value for entry `i`.
+    pub fn value(&self, i: usize) -> &[u8] {
+        &self.values[i * self.value_size..(i + 1) * self.value_size]
+    }
+
+    /// Child block numbers of an internal node.
+    pub fn children(&self) -> Vec<u64> {
+        (0..self.keys.len()).map(|i| le64(self.value(i), 0)).collect()
+    }
+}
+
+/// Read and validate the btree node at block `b`. Leaves must hold
+/// values of `value_size` bytes.
+pub fn read_node(dev: &MetadataDev, b: u64, value_size: usize) -> Result<Node, MetadataError> {
+    let bad = |reason: String| {
+        MetadataError::BadBlock {
+            block: b,
+            reason: reason,
+        }
+    };
+
+    let block = try!(dev.read_block(b).map_err(|e| bad(format!("read failed: {}", e))));
+    try!(validate_block(&block, b, BTREE_CSUM_XOR).map_err(&bad));
+
+    let flags = le32(&block, 4);
+    let nr_entries = le32(&block, 16) as usize;
+    let max_entries = le32(&block, 20) as usize;
+    let on_disk_value_size = le32(&block, 24) as usize;
+
+    let leaf = match flags {
+        INTERNAL_NODE => false,
+        LEAF_NODE => true,
+        _ => return Err(bad(format!("invalid node flags {:#x}", flags))),
+    };
+
+    let expected_size = if leaf { value_size } else { 8 };
+    if on_disk_value_size != expected_size {
+        return Err(bad(format!("value size is {}, expected {}", on_disk_value_size, expected_size)));
+    }
+
+    if nr_entries > max_entries ||
+       NODE_HEADER_SIZE + max_entries * (8 + on_disk_value_size) > BLOCK_SIZE {
+        return Err(bad(format!("bad entry counts ({} of {})", nr_entries, max_entries)));
+    }
+
+    if !leaf && nr_entries == 0 {
+        return Err(bad("empty internal node".to_owned()));
+    }
+
+    let keys: Vec<_> = (0..nr_entries).map(|i| le64(&block, NODE_HEADER_SIZE + i * 8)).collect();
+    if keys.windows(2).any(|w| w[0] >= w[1]) {
+        return Err(bad("keys out of order".to_owned()));
+    }
+
+    let values_start = NODE_HEADER_SIZE + max_entries * 8;
+
+    Ok(Node {
+        blocknr: b,
+        leaf: leaf,
+        keys: keys,
+        value_size: on_disk_value_size,
+        values: block[values_start..values_start + nr_entries * on_disk_value_size].to_vec(),
+    })
+}
+
+/// Call `f` with each key and value in a btree, in key order.
+///
+/// Nodes are not checked for sharing, so a corrupt tree containing a
+/// cycle will not terminate; use `Walker` on untrusted metadata.
+pub fn btree_for_each<F>(dev: &MetadataDev, root: u64, value_size: usize, f: &mut F) -> io::Result<()>
+    where F: FnMut(u64, &[u8]) -> io::Result<()>
+{
+    let node = try!(read_node(dev, root, value_size)
+        .map_err(|e| Error::new(InvalidData, e.to_string())));
+
+    if node.leaf {
+        for (i, key) in node.keys.iter().enumerate() {
+            try!(f(*key, node.value(i)));
+        }
+    } else {
+        for child in node.children() {
+            try!(btree_for_each(dev, child, value_size, f));
+        }
+    }
+
+    Ok(())
+}
+
+/// Look up a single key in a btree.
+pub fn btree_lookup(dev: &MetadataDev,
+                    root: u64,
+                    value_size: usize,
+                    key: u64)
+                    -> io::Result<Option<Vec<u8>>> {
+    let mut b = root;
+    loop {
+        let node = try!(read_node(dev, b, value_size)
+            .map_err(|e| Error::new(InvalidData, e.to_string())));
+
+        let idx = match node.keys.binary_search(&key) {
+            Ok(i) => i,
+            Err(0) => return Ok(None),
+            Err(i) => i - 1,
+        };
+
+        if node.leaf {
+            return Ok(if node.keys[idx] == key {
+                Some(node.value(idx).to_vec())
+            } else {
+                None
+            });
+        }
+
+        b = le64(node.value(idx), 0);
+    }
+}
+
+// Read and validate an array block, returning its populated values.
+fn read_array_block(dev: &MetadataDev, b: u64, value_size: usize) -> Result<Vec<u8>, MetadataError> {
+    let bad = |reason: String| {
+        MetadataError::BadBlock {
+            block: b,
+            reason: format!("array block: {}", reason),
+        }
+    };
+
+    let block = try!(dev.read_block(b).map_err(|e| bad(format!("read failed: {}", e))));
+    try!(validate(&block, b, 16, ARRAY_CSUM_XOR).map_err(&bad));
+
+    let max_entries = le32(&block, 4) as usize;
+    let nr_entries = le32(&block, 8) as usize;
+    let on_disk_value_size = le32(&block, 12) as usize;
+
+    if on_disk_value_size != value_size {
+        return Err(bad(format!("value size is {}, expected {}", on_disk_value_size, value_size)));
+    }
+
+    if max_entries != (BLOCK_SIZE - ARRAY_HEADER_SIZE) / value_size || nr_entries > max_entries {
+        return Err(bad(format!("bad entry counts ({} of {})", nr_entries, max_entries)));
+    }
+
+    Ok(block[ARRAY_HEADER_SIZE..ARRAY_HEADER_SIZE + nr_entries * value_size].to_vec())
+}
+
+/// Call `f` with each index and value in an array. Arrays are btrees
+/// whose values are blocks, each holding a run of array entries.
+pub fn array_for_each<F>(dev: &MetadataDev, root: u64, value_size: usize, f: &mut F) -> io::Result<()>
+    where F: FnMut(u64, &[u8]) -> io::Result<()>
+{
+    let per_block = ((BLOCK_SIZE - ARRAY_HEADER_SIZE) / value_size) as u64;
+
+    btree_for_each(dev, root, 8, &mut |key, val| {
+        let values = try!(read_array_block(dev, le64(val, 0), value_size)
+            .map_err(|e| Error::new(InvalidData, e.to_string())));
+
+        for (i, v) in values.chunks(value_size).enumerate() {
+            try!(f(key * per_block + i as u64, v));
+        }
+        Ok(())
+    })
+}
+
+/// Counts references to metadata blocks while walking structures,
+/// and collects any errors found.
+///
+/// Each block is only descended into the first time it is
+/// referenced, so shared subtrees are visited once.
+pub struct Walker<'a> {
+    dev: &'a MetadataDev,
+    nr_blocks: u64,
+    refs: BTreeMap<u64, u32>,
+    /// Errors found so far.
+    pub errors: Vec<MetadataError>,
+}
+
+impl<'a> Walker<'a> {
+    /// Create a walker for a device whose metadata space map covers
+    /// `nr_blocks` blocks.
+    pub fn new(dev: &'a MetadataDev, nr_blocks: u64) -> Walker<'a> {
+        Walker {
+            dev: dev,
+            nr_blocks: nr_blocks,
+            refs: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// The device being walked.
+    pub fn dev(&self) -> &'a MetadataDev {
+        self.dev
+    }
+
+    /// Record a reference to block `b`. Returns true if this is the
+    /// first reference, and the block is within bounds.
+    pub fn inc(&mut self, b: u64, context: &str) -> bool {
+        if b >= self.nr_blocks || b >= self.dev.nr_blocks() {
+            self.errors.push(MetadataError::OutOfBounds {
+                context: context.to_owned(),
+                block: b,
+                nr_blocks: ::std::cmp::min(self.nr_blocks, self.dev.nr_blocks()),
+            });
+            return false;
+        }
+
+        let count = self.refs.entry(b).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    /// The number of references recorded for block `b`.
+    pub fn ref_count(&self, b: u64) -> u32 {
+        self.refs.get(&b).cloned().unwrap_or(0)
+    }
+
+    /// Walk the btree at `root`, calling `visit` for each leaf the
+    /// first time it is reached.
+    pub fn walk<F>(&mut self, root: u64, value_size: usize, context: &str, visit: &mut F)
+        where F: FnMut(&mut Walker<'a>, &Node)
+    {
+        if !self.inc(root, context) {
+            return;
+        }
+
+        let node = match read_node(self.dev, root, value_size) {
+            Ok(node) => node,
+            Err(e) => {
+                self.errors.push(e);
+                return;
+            }
+        };
+
+        if node.leaf {
+            visit(self, &node);
+        } else {
+            for child in node.children() {
+                self.walk(child, value_size, context, visit);
+            }
+        }
+    }
+
+    /// Walk the array at `root`, calling `visit` with the index and
+    /// value of every entry. Returns the number of entries found.
+    pub fn walk_array<F>(&mut self, root: u64, value_size: usize, context: &str, visit: &mut F) -> u64
+        where F: FnMut(u64, &[u8])
+    {
+        let per_block = ((BLOCK_SIZE - ARRAY_HEADER_SIZE) / value_size) as u64;
+
+        let mut blocks = Vec::new();
+        self.walk(root, 8, context, &mut |_, node| {
+            for (i, key) in node.keys.iter().enumerate() {
+                blocks.push((*key, le64(node.value(i), 0)));
+            }
+        });
+
+        let mut nr_entries = 0;
+        for (key, b) in blocks {
+            if !self.inc(b, context) {
+                continue;
+            }
+
+            match read_array_block(self.dev, b, value_size) {
+                Ok(values) => {
+                    for (i, v) in values.chunks(value_size).enumerate() {
+                        visit(key * per_block + i as u64, v);
+                        nr_entries += 1;
+                    }
+                }
+                Err(e) => self.errors.push(e),
+            }
+        }
+
+        nr_entries
+    }
+}
+
+/// The root of a space map, as stored in a superblock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SpaceMapRoot {
+    /// Number of blocks tracked.
+    pub nr_blocks: u64,
+    /// Number of blocks with a non-zero reference count.
+    pub nr_allocated: u64,
+    /// Root of the bitmap index.
+    pub bitmap_root: u64,
+    /// Root of the btree holding counts too large for the bitmaps.
+    pub ref_count_root: u64,
+}
+
+impl SpaceMapRoot {
+    /// Parse a space map root from the start of `buf`.
+    pub fn parse(buf: &[u8]) -> SpaceMapRoot {
+        SpaceMapRoot {
+            nr_blocks: le64(buf, 0),
+            nr_allocated: le64(buf, 8),
+            bitmap_root: le64(buf, 16),
+            ref_count_root: le64(buf, 24),
+        }
+    }
+}
+
+/// Which kind of space map is being read. The metadata space map
+/// keeps its bitmap index in a single block; the data space map
+/// keeps it in a btree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpaceMapKind {
+    /// The space map for metadata blocks.
+    Metadata,
+    /// The space map for data blocks.
+    Data,
+}
+
+/// A space map whose structure has been validated.
+pub struct SpaceMap {
+    root: SpaceMapRoot,
+    bitmaps: Vec<(u64, u32)>,
+    overflow: BTreeMap<u64, u32>,
+}
+
+impl SpaceMap {
+    /// Read the space map at `root`, recording references to the
+    /// blocks it occupies in `walker`.
+    pub fn load(walker: &mut Walker, root: &SpaceMapRoot, kind: SpaceMapKind) -> SpaceMap {
+        let context = match kind {
+            SpaceMapKind::Metadata => "metadata space map",
+            SpaceMapKind::Data => "data space map",
+        };
+
+        let nr_bitmaps = root.nr_blocks.div_ceil(ENTRIES_PER_BITMAP) as usize;
+        let mut entries = BTreeMap::new();
+
+        match kind {
+            SpaceMapKind::Metadata => {
+                if nr_bitmaps > MAX_METADATA_BITMAPS {
+                    walker.errors.push(MetadataError::BadSuperblock(format!(
+                        "{} needs {} bitmaps, at most {} supported",
+                        context, nr_bitmaps, MAX_METADATA_BITMAPS)));
+                } else if walker.inc(root.bitmap_root, context) {
+                    match Self::read_index_block(walker.dev(), root.bitmap_root) {
+                        Ok(block) => {
+                            for i in 0..nr_bitmaps {
+                                let off = 16 + i * INDEX_ENTRY_SIZE;
+                                entries.insert(i as u64, (le64(&block, off), le32(&block, off + 8)));
+                            }
+                        }
+                        Err(e) => walker.errors.push(e),
+                    }
+                }
+            }
+            SpaceMapKind::Data => {
+                walker.walk(root.bitmap_root,
+                            INDEX_ENTRY_SIZE,
+                            context,
+                            &mut |_, node| {
+                    for (i, key) in node.keys.iter().enumerate() {
+                        let val = node.value(i);
+                        entries.insert(*key, (le64(val, 0), le32(val, 8)));
+                    }
+                });
+            }
+        }
+
+        let mut bitmaps = Vec::with_capacity(nr_bitmaps);
+        for i in 0..nr_bitmaps as u64 {
+            match entries.get(&i) {
+                Some(&(blocknr, nr_free)) => {
+                    if walker.inc(blocknr, context) {
+                        let res = walker.dev()
+                            .read_block(blocknr)
+                            .map_err(|e| format!("read failed: {}", e))
+                            .and_then(|b| validate_block(&b, blocknr, BITMAP_CSUM_XOR));
+                        if let Err(reason) = res {
+                            walker.errors.push(MetadataError::BadBlock {
+                                block: blocknr,
+                                reason: format!("{} bitmap: {}", context, reason),
+                            });
+                        }
+                    }
+                    bitmaps.push((blocknr, nr_free));
+                }
+                None => {
+                    walker.errors.push(MetadataError::BadBlock {
+                        block: root.bitmap_root,
+                        reason: format!("{} index has no entry for bitmap {}", context, i),
+                    });
+                    bitmaps.push((0, 0));
+                }
+            }
+        }
+
+        let mut overflow = BTreeMap::new();
+        walker.walk(root.ref_count_root,
+                    4,
+                    context,
+                    &mut |_, node| {
+            for (i, key) in node.keys.iter().enumerate() {
+                overflow.insert(*key, le32(node.value(i), 0));
+            }
+        });
+
+        SpaceMap {
+            root: *root,
+            bitmaps: bitmaps,
+            overflow: overflow,
+        }
+    }
+
+    fn read_index_block(dev: &MetadataDev, b: u64) -> Result<Vec<u8>, MetadataError> {
+        let block = try!(dev.read_block(b).map_err(|e| {
+            MetadataError::BadBlock {
+                block: b,
+                reason: format!("read failed: {}", e),
+            }
+        }));
+        try!(validate_block(&block, b, INDEX_CSUM_XOR).map_err(|reason| {
+            MetadataError::BadBlock {
+                block: b,
+                reason: format!("space map index: {}", reason),
+            }
+        }));
+        Ok(block)
+    }
+
+    /// The root this space map was loaded from.
+    pub fn root(&self) -> &SpaceMapRoot {
+        &self.root
+    }
+
+    /// Call `f` with the reference count of every block, in order.
+    /// Bitmaps that cannot be read are skipped; they were reported
+    /// when the space map was loaded.
+    ///
+    /// Also checks the free counts kept in the bitmap index and the
+    /// root's allocated count, adding any mismatches to `errors`.
+    pub fn for_each_count<F>(&self, dev: &MetadataDev, errors: &mut Vec<MetadataError>, mut f: F)
+        where F: FnMut(u64, u32)
+    {
+        let mut nr_allocated = 0;
+        let mut complete = true;
+
+        for (i, &(blocknr, nr_free)) in self.bitmaps.iter().enumerate() {
+            let block = match dev.read_block(blocknr) {
+                Ok(ref b) if validate_block(b, blocknr, BITMAP_CSUM_XOR).is_ok() => b.clone(),
+                _ => {
+                    complete = false;
+                    continue;
+                }
+            };
+
+            // The kernel counts the entries past the end of the last
+            // bitmap as free, so check them all but only report blocks
+            // that exist.
+            let first = i as u64 * ENTRIES_PER_BITMAP;
+            let mut free = 0;
+
+            for b in first..first + ENTRIES_PER_BITMAP {
+                let entry = (b - first) as usize;
+                let word = le64(&block, BITMAP_HEADER_SIZE + (entry / 32) * 8);
+                let bit = (entry % 32) * 2;
+                let hi = (word >> bit) & 1;
+                let lo = (word >> (bit + 1)) & 1;
+
+                let count = match (hi << 1) | lo {
+                    3 => self.overflow.get(&b).cloned().unwrap_or(0),
+                    x => x as u32,
+                };
+
+                if count == 0 {
+                    free += 1;
+                }
+                if b < self.root.nr_blocks {
+                    if count != 0 {
+                        nr_allocated += 1;
+                    }
+                    f(b, count);
+                }
+            }
+
+            if free != nr_free as u64 {
+                errors.push(MetadataError::Counter {
+                    context: format!("free count of space map bitmap {}", blocknr),
+                    expected: free,
+                    actual: nr_free as u64,
+                });
+            }
+        }
+
+        if complete && nr_allocated != self.root.nr_allocated {
+            errors.push(MetadataError::Counter {
+                context: "space map allocated count".to_owned(),
+                expected: nr_allocated,
+                actual: self.root.nr_allocated,
+            });
+        }
+    }
+}
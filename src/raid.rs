@@ -0,0 +1,171 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput};
+use std::str::FromStr;
+
+use {DM, DevId, DmFlags};
+use types::Sectors;
+
+/// The state of one device in a raid set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaidDevHealth {
+    /// Alive and in sync ('A').
+    InSync,
+    /// Alive but not yet in sync ('a').
+    OutOfSync,
+    /// Failed ('D').
+    Dead,
+    /// No device in this slot ('-'), as in a degraded set.
+    Missing,
+}
+
+/// What a raid set is doing to its devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncAction {
+    /// Nothing.
+    Idle,
+    /// Stopped from doing anything.
+    Frozen,
+    /// Syncing the devices, e.g. after being created.
+    Resync,
+    /// Rebuilding a replaced device.
+    Recover,
+    /// Checking, and counting mismatches.
+    Check,
+    /// Checking and fixing mismatches.
+    Repair,
+    /// Changing layout.
+    Reshape,
+}
+
+/// Status of a raid target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaidStatus {
+    /// The raid type, e.g. "raid1" or "raid5_ls".
+    pub raid_type: String,
+    /// The health of each device.
+    pub health: Vec<RaidDevHealth>,
+    /// Sectors synced so far.
+    pub synced: Sectors,
+    /// Sectors to sync.
+    pub total: Sectors,
+    /// The current sync action.
+    pub sync_action: SyncAction,
+    /// Mismatches found by the last check or repair.
+    pub mismatch_count: u64,
+    /// Where data starts on each device, if reported.
+    pub data_offset: Option<Sectors>,
+}
+
+impl RaidStatus {
+    /// The fraction of the set that is in sync, from 0 to 1.
+    pub fn sync_ratio(&self) -> f64 {
+        if *self.total == 0 {
+            1.0
+        } else {
+            *self.synced as f64 / *self.total as f64
+        }
+    }
+
+    /// The number of failed or missing devices.
+    pub fn dead_devices(&self) -> usize {
+        self.health
+            .iter()
+            .filter(|h| **h == RaidDevHealth::Dead || **h == RaidDevHealth::Missing)
+            .count()
+    }
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.parse::<u64>().map_err(|_| Error::new(InvalidData, format!("expected number, got \"{}\"", s)))
+}
+
+impl FromStr for RaidStatus {
+    type Err = Error;
+
+    /// Parse the params field of a raid target's status line:
+    /// "<type> <#devices> <health chars> <synced>/<total>
+    /// <sync action> <mismatches> [<data offset> [<journal>]]".
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use devicemapper::raid::RaidStatus;
+    ///
+    /// let status: RaidStatus = "raid1 2 AD 1024/2048 idle 0 0 -".parse().unwrap();
+    /// assert_eq!(status.sync_ratio(), 0.5);
+    /// assert_eq!(status.dead_devices(), 1);
+    ///
+    /// let degraded: RaidStatus = "raid5_ls 3 AA- 4096/4096 idle 0 0 -".parse().unwrap();
+    /// assert_eq!(degraded.dead_devices(), 1);
+    /// ```
+    fn from_str(s: &str) -> io::Result<RaidStatus> {
+        let bad = |what: &str| Error::new(InvalidData, format!("bad {} in raid status \"{}\"", what, s));
+        let vals: Vec<_> = s.split_whitespace().collect();
+        if vals.len() < 4 {
+            return Err(Error::new(InvalidData, format!("too few fields in raid status \"{}\"", s)));
+        }
+
+        let nr_devs = try!(parse_u64(vals[1])) as usize;
+        let mut health = Vec::new();
+        for c in vals[2].chars() {
+            health.push(match c {
+                'A' => RaidDevHealth::InSync,
+                'a' => RaidDevHealth::OutOfSync,
+                'D' => RaidDevHealth::Dead,
+                '-' => RaidDevHealth::Missing,
+                _ => return Err(bad("health")),
+            });
+        }
+        if health.len() != nr_devs {
+            return Err(bad("health"));
+        }
+
+        let mut sync = vals[3].split('/');
+        let (synced, total) = match (sync.next().map(parse_u64), sync.next().map(parse_u64)) {
+            (Some(Ok(synced)), Some(Ok(total))) => (synced, total),
+            _ => return Err(bad("sync ratio")),
+        };
+
+        // Older kernels stop after the sync ratio.
+        let sync_action = match vals.get(4) {
+            None | Some(&"idle") => SyncAction::Idle,
+            Some(&"frozen") => SyncAction::Frozen,
+            Some(&"resync") => SyncAction::Resync,
+            Some(&"recover") => SyncAction::Recover,
+            Some(&"check") => SyncAction::Check,
+            Some(&"repair") => SyncAction::Repair,
+            Some(&"reshape") => SyncAction::Reshape,
+            Some(_) => return Err(bad("sync action")),
+        };
+
+        Ok(RaidStatus {
+            raid_type: vals[0].to_owned(),
+            health: health,
+            synced: Sectors(synced),
+            total: Sectors(total),
+            sync_action: sync_action,
+            mismatch_count: match vals.get(5) {
+                Some(n) => try!(parse_u64(n)),
+                None => 0,
+            },
+            data_offset: match vals.get(6) {
+                Some(n) => Some(Sectors(try!(parse_u64(n)))),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Get the status of the raid device `raid`.
+pub fn raid_status(dm: &DM, raid: &DevId) -> io::Result<RaidStatus> {
+    let (_, status) = try!(dm.table_status(raid, DmFlags::empty()));
+    match status.first() {
+        Some((_, _, ttype, params)) if ttype == "raid" => params.parse(),
+        _ => Err(Error::new(InvalidInput, "device is not a raid")),
+    }
+}
@@ -0,0 +1,490 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{AlreadyExists, InvalidInput};
+
+use {DevId, Device, DmFlags, TargetLine, DM_ACTIVE_PRESENT, DM_STATUS_TABLE};
+use backend::Backend;
+use depgraph::DepGraph;
+use table;
+use tablediff::{self, ReloadPlan};
+use transaction::Transaction;
+
+/// A device that should exist, identified by its UUID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesiredDevice {
+    /// The device's UUID, which identifies it across renames.
+    pub uuid: String,
+    /// The name the device should have.
+    pub name: String,
+    /// The table the device should have active. Other desired devices
+    /// may be referred to as "/dev/mapper/<name>", which is replaced
+    /// by the device's number when the table is loaded.
+    pub table: Vec<TargetLine>,
+}
+
+impl DesiredDevice {
+    /// Describe a device that should exist.
+    pub fn new<T1, T2>(uuid: &str, name: &str, targets: &[(u64, u64, T1, T2)]) -> DesiredDevice
+        where T1: Borrow<str>,
+              T2: Borrow<str>
+    {
+        DesiredDevice {
+            uuid: uuid.to_owned(),
+            name: name.to_owned(),
+            table: targets.iter()
+                .map(|t| (t.0, t.1, t.2.borrow().to_owned(), t.3.borrow().to_owned()))
+                .collect(),
+        }
+    }
+}
+
+/// A change to bring a device to its desired state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Create a device, load its table, and activate it.
+    Create {
+        /// The device's UUID.
+        uuid: String,
+        /// The device's name.
+        name: String,
+        /// The device's table.
+        table: Vec<TargetLine>,
+    },
+    /// Load a new table into an existing device and activate it.
+    Reload {
+        /// The device's UUID.
+        uuid: String,
+        /// The device's name, after any renames.
+        name: String,
+        /// The new table.
+        table: Vec<TargetLine>,
+        /// Whether the new table only resizes the device.
+        plan: ReloadPlan,
+    },
+    /// Rename an existing device.
+    Rename {
+        /// The device's UUID.
+        uuid: String,
+        /// The device's current name.
+        old_name: String,
+        /// The device's new name.
+        new_name: String,
+    },
+    /// Remove a device that is no longer wanted.
+    Remove {
+        /// The device's UUID.
+        uuid: String,
+        /// The device's name.
+        name: String,
+    },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Action::Create { ref uuid, ref name, .. } => write!(f, "create {} ({})", name, uuid),
+            Action::Reload { ref uuid, ref name, plan, .. } => {
+                match plan {
+                    ReloadPlan::Resize { old_size, new_size } => {
+                        write!(f,
+                               "resize {} ({}) from {} to {} sectors",
+                               name,
+                               uuid,
+                               *old_size,
+                               *new_size)
+                    }
+                    _ => write!(f, "reload {} ({})", name, uuid),
+                }
+            }
+            Action::Rename { ref uuid, ref old_name, ref new_name } => {
+                write!(f, "rename {} ({}) to {}", old_name, uuid, new_name)
+            }
+            Action::Remove { ref uuid, ref name } => write!(f, "remove {} ({})", name, uuid),
+        }
+    }
+}
+
+// A live device the reconciler manages.
+struct LiveDevice {
+    name: String,
+    device: Device,
+    active: Vec<TargetLine>,
+}
+
+/// Makes the devices whose UUIDs start with a prefix match a desired
+/// set: devices are created, renamed, and reloaded as needed, and
+/// devices with the prefix that are not wanted are removed. Devices
+/// without the prefix are never changed.
+///
+/// Devices are matched by UUID, so a device whose name changed is
+/// renamed, not recreated. If a change fails, the others are undone.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::backend::SimBackend;
+/// use devicemapper::reconcile::{DesiredDevice, Reconciler};
+///
+/// let sim = SimBackend::new();
+/// let reconciler = Reconciler::new("example-");
+///
+/// let desired = vec![
+///     DesiredDevice::new("example-1", "data", &[(0, 2048, "linear", "8:16 0")]),
+///     DesiredDevice::new("example-2", "top", &[(0, 2048, "linear", "/dev/mapper/data 0")]),
+/// ];
+/// assert_eq!(reconciler.apply(&sim, &desired).unwrap().len(), 2);
+/// assert!(reconciler.plan(&sim, &desired).unwrap().is_empty());
+///
+/// let renamed = vec![
+///     DesiredDevice::new("example-1", "data2", &[(0, 2048, "linear", "8:16 0")]),
+/// ];
+/// // Rename example-1, remove example-2.
+/// assert_eq!(reconciler.apply(&sim, &renamed).unwrap().len(), 2);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reconciler {
+    uuid_prefix: String,
+}
+
+impl Reconciler {
+    /// Create a reconciler that manages devices whose UUIDs start with
+    /// `uuid_prefix`.
+    pub fn new(uuid_prefix: &str) -> Reconciler {
+        Reconciler { uuid_prefix: uuid_prefix.to_owned() }
+    }
+
+    fn check_desired(&self, desired: &[DesiredDevice]) -> io::Result<()> {
+        let mut uuids = BTreeSet::new();
+        let mut names = BTreeSet::new();
+        for d in desired {
+            if !d.uuid.starts_with(&self.uuid_prefix) {
+                return Err(Error::new(InvalidInput,
+                                      format!("UUID {} does not start with {}",
+                                              d.uuid,
+                                              self.uuid_prefix)));
+            }
+            if !uuids.insert(&d.uuid) {
+                return Err(Error::new(InvalidInput, format!("UUID {} repeated", d.uuid)));
+            }
+            if !names.insert(&d.name) {
+                return Err(Error::new(InvalidInput, format!("name {} repeated", d.name)));
+            }
+            try!(table::validate(&d.table));
+        }
+        Ok(())
+    }
+
+    // The live devices with our prefix, by UUID, and the names of all
+    // live devices, with their UUIDs.
+    fn live<B: Backend>(&self,
+                        dm: &B)
+                        -> io::Result<(BTreeMap<String, LiveDevice>, BTreeMap<String, String>)> {
+        let mut managed = BTreeMap::new();
+        let mut names = BTreeMap::new();
+
+        for (name, dev) in try!(dm.list_devices()) {
+            let id = DevId::Name(&name);
+            let info = match dm.device_status(&id) {
+                Ok(info) => info,
+                Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => continue,
+                Err(err) => return Err(err),
+            };
+            let uuid = info.uuid().to_owned();
+            names.insert(name.clone(), uuid.clone());
+
+            if !uuid.is_empty() && uuid.starts_with(&self.uuid_prefix) {
+                let active = if info.flags().contains(DM_ACTIVE_PRESENT) {
+                    try!(dm.table_status(&id, DM_STATUS_TABLE)).1
+                } else {
+                    Vec::new()
+                };
+                managed.insert(uuid,
+                               LiveDevice {
+                                   name: name,
+                                   device: dev,
+                                   active: active,
+                               });
+            }
+        }
+
+        Ok((managed, names))
+    }
+
+    /// Work out the actions that would bring the devices to the
+    /// desired state, without changing anything. Actions are listed in
+    /// the order `apply()` takes them.
+    pub fn plan<B: Backend>(&self, dm: &B, desired: &[DesiredDevice]) -> io::Result<Vec<Action>> {
+        try!(self.check_desired(desired));
+        let (live, live_names) = try!(self.live(dm));
+        let mut actions = Vec::new();
+
+        // Renames. A wanted name may belong to a managed device that is
+        // itself being renamed or removed; move such devices out of the
+        // way first.
+        let wanted: BTreeMap<_, _> = desired.iter().map(|d| (&d.name[..], &d.uuid[..])).collect();
+        let mut current: BTreeMap<String, String> =
+            live.iter().map(|(uuid, l)| (uuid.clone(), l.name.clone())).collect();
+        let mut taken: BTreeSet<String> = live_names.keys().cloned().collect();
+
+        for d in desired {
+            match live_names.get(&d.name) {
+                Some(owner) if *owner != d.uuid && !live.contains_key(owner) => {
+                    return Err(Error::new(AlreadyExists,
+                                          format!("name {} is used by a device not managed \
+                                                   by this reconciler",
+                                                  d.name)));
+                }
+                _ => {}
+            }
+        }
+
+        for (uuid, l) in &live {
+            if wanted.get(&l.name[..]).is_some_and(|owner| *owner != uuid) {
+                let tmp = (0..)
+                    .map(|i| format!("reconcile-tmp-{}", i))
+                    .find(|n| !taken.contains(n) && !wanted.contains_key(&n[..]))
+                    .expect("unbounded range");
+                taken.insert(tmp.clone());
+                actions.push(Action::Rename {
+                    uuid: uuid.clone(),
+                    old_name: l.name.clone(),
+                    new_name: tmp.clone(),
+                });
+                current.insert(uuid.clone(), tmp);
+            }
+        }
+
+        for d in desired {
+            if let Some(name) = current.get_mut(&d.uuid) {
+                if *name != d.name {
+                    actions.push(Action::Rename {
+                        uuid: d.uuid.clone(),
+                        old_name: name.clone(),
+                        new_name: d.name.clone(),
+                    });
+                    *name = d.name.clone();
+                }
+            }
+        }
+
+        // Creates and reloads, each after the devices it is built on.
+        let live_numbers: BTreeMap<_, _> = desired.iter()
+            .filter_map(|d| live.get(&d.uuid).map(|l| (d.name.clone(), l.device)))
+            .collect();
+        for d in try!(dependency_order(desired, &live)) {
+            match live.get(&d.uuid) {
+                None => {
+                    actions.push(Action::Create {
+                        uuid: d.uuid.clone(),
+                        name: d.name.clone(),
+                        table: d.table.clone(),
+                    })
+                }
+                Some(l) => {
+                    let resolved = resolve_names(&d.table, &live_numbers);
+                    let diff = tablediff::diff(&l.active, &resolved);
+                    if !diff.is_noop() {
+                        actions.push(Action::Reload {
+                            uuid: d.uuid.clone(),
+                            name: d.name.clone(),
+                            table: d.table.clone(),
+                            plan: diff.plan,
+                        });
+                    }
+                }
+            }
+        }
+
+        // Removes, holders first.
+        let unwanted: BTreeMap<_, _> = live.iter()
+            .filter(|&(uuid, _)| !desired.iter().any(|d| d.uuid == *uuid))
+            .map(|(uuid, l)| (l.device, uuid))
+            .collect();
+        if !unwanted.is_empty() {
+            let graph = try!(DepGraph::snapshot(dm));
+            for dev in try!(graph.topo_order()).into_iter().rev() {
+                if let Some(uuid) = unwanted.get(&dev) {
+                    actions.push(Action::Remove {
+                        uuid: (*uuid).clone(),
+                        name: current[*uuid].clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(actions)
+    }
+
+    /// Bring the devices to the desired state, and return the actions
+    /// taken. See `plan()`.
+    ///
+    /// Renames and creates are made first, as one transaction. A new
+    /// device's `/dev/mapper` node is made by udev some time after the
+    /// device is created, so tables are then loaded with any
+    /// "/dev/mapper/<name>" of a desired device replaced by its number.
+    /// Reloaded devices are suspended holders first and resumed in
+    /// the opposite order. If any step fails, the completed steps are
+    /// undone and the error is returned.
+    pub fn apply<B: Backend>(&self, dm: &B, desired: &[DesiredDevice]) -> io::Result<Vec<Action>> {
+        let actions = try!(self.plan(dm, desired));
+
+        let mut setup = Transaction::new();
+        for action in &actions {
+            match *action {
+                Action::Rename { ref old_name, ref new_name, .. } => {
+                    setup.rename(old_name, new_name);
+                }
+                Action::Create { ref uuid, ref name, .. } => {
+                    setup.create(name, Some(uuid), DmFlags::empty());
+                }
+                _ => {}
+            }
+        }
+        try!(setup.commit(dm));
+
+        if let Err(err) = device_numbers(dm, desired).and_then(|numbers| load(dm, &actions, &numbers)) {
+            let mut undo = Transaction::new();
+            for action in actions.iter().rev() {
+                match *action {
+                    Action::Rename { ref old_name, ref new_name, .. } => {
+                        undo.rename(new_name, old_name);
+                    }
+                    Action::Create { ref name, .. } => {
+                        undo.remove(name);
+                    }
+                    _ => {}
+                }
+            }
+            return match undo.commit(dm) {
+                Ok(()) => Err(err),
+                Err(undo_err) => {
+                    Err(Error::new(err.kind(),
+                                   format!("{}; undoing renames and creates also failed: {}",
+                                           err,
+                                           undo_err)))
+                }
+            };
+        }
+
+        Ok(actions)
+    }
+}
+
+// The numbers of the desired devices, which all exist, by name.
+fn device_numbers<B: Backend>(dm: &B, desired: &[DesiredDevice]) -> io::Result<BTreeMap<String, Device>> {
+    let mut numbers = BTreeMap::new();
+    for d in desired {
+        let info = try!(dm.device_status(&DevId::Uuid(&d.uuid)));
+        numbers.insert(d.name.clone(), info.device());
+    }
+    Ok(numbers)
+}
+
+// Load and activate the tables of created and reloaded devices, and
+// remove unwanted ones, once renames and creates are done.
+fn load<B: Backend>(dm: &B, actions: &[Action], numbers: &BTreeMap<String, Device>) -> io::Result<()> {
+    let mut trans = Transaction::new();
+    let mut reloaded = Vec::new();
+    for action in actions {
+        match *action {
+            Action::Create { ref name, ref table, .. } => {
+                trans.load(name, &resolve_names(table, numbers))
+                    .resume(name);
+            }
+            Action::Reload { ref name, ref table, .. } => {
+                trans.load(name, &resolve_names(table, numbers));
+                reloaded.push(name);
+            }
+            Action::Rename { .. } |
+            Action::Remove { .. } => {}
+        }
+    }
+    for name in &reloaded {
+        trans.suspend(name, DmFlags::empty());
+    }
+    for name in &reloaded {
+        trans.resume(name);
+    }
+    for action in actions {
+        if let Action::Remove { ref name, .. } = *action {
+            trans.remove(name);
+        }
+    }
+
+    try!(trans.commit(dm));
+    Ok(())
+}
+
+// The desired devices another desired device's table refers to, by
+// "/dev/mapper/<name>" or by the number of the live device.
+fn desired_deps(d: &DesiredDevice,
+                desired: &[DesiredDevice],
+                live: &BTreeMap<String, LiveDevice>)
+                -> Vec<usize> {
+    let mut deps = Vec::new();
+    for t in &d.table {
+        for token in t.3.split_whitespace() {
+            let found = desired.iter().position(|other| {
+                token.trim_start_matches("/dev/mapper/") == other.name &&
+                token.starts_with("/dev/mapper/") ||
+                live.get(&other.uuid).is_some_and(|l| l.device.dstr() == token)
+            });
+            if let Some(i) = found {
+                deps.push(i);
+            }
+        }
+    }
+    deps
+}
+
+fn dependency_order<'a>(desired: &'a [DesiredDevice],
+                        live: &BTreeMap<String, LiveDevice>)
+                        -> io::Result<Vec<&'a DesiredDevice>> {
+    let deps: Vec<_> = desired.iter().map(|d| desired_deps(d, desired, live)).collect();
+    let mut placed = vec![false; desired.len()];
+    let mut order = Vec::new();
+
+    while order.len() < desired.len() {
+        let ready: Vec<_> = (0..desired.len())
+            .filter(|&i| !placed[i] && deps[i].iter().all(|&dep| placed[dep] && dep != i))
+            .collect();
+        if ready.is_empty() {
+            return Err(Error::new(InvalidInput, "desired devices depend on each other in a cycle"));
+        }
+        for i in ready {
+            placed[i] = true;
+            order.push(&desired[i]);
+        }
+    }
+
+    Ok(order)
+}
+
+// Replace "/dev/mapper/<name>" references to the devices in
+// `numbers` with their device numbers, as the kernel reports them.
+fn resolve_names(targets: &[TargetLine], numbers: &BTreeMap<String, Device>) -> Vec<TargetLine> {
+    targets.iter()
+        .map(|t| {
+            let params = t.3
+                .split_whitespace()
+                .map(|token| {
+                    let name = if token.starts_with("/dev/mapper/") {
+                        numbers.get(token.trim_start_matches("/dev/mapper/"))
+                    } else {
+                        None
+                    };
+                    name.map_or_else(|| token.to_owned(), |dev| dev.dstr())
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            (t.0, t.1, t.2.clone(), params)
+        })
+        .collect()
+}
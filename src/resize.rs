@@ -0,0 +1,272 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidInput, Other};
+
+use {DevId, Device, DmFlags, TargetLine, DM_STATUS_TABLE, DM_SUSPEND};
+use backend::Backend;
+use types::Sectors;
+
+// A linear or striped target's params, parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Layout {
+    Linear(String, u64),
+    Striped(u64, Vec<(String, u64)>),
+}
+
+impl Layout {
+    fn parse(target_type: &str, params: &str) -> Option<Layout> {
+        let fields: Vec<_> = params.split_whitespace().collect();
+        match target_type {
+            "linear" if fields.len() == 2 => {
+                fields[1].parse().ok().map(|off| Layout::Linear(fields[0].to_owned(), off))
+            }
+            "striped" if fields.len() >= 2 => {
+                let stripes = match fields[0].parse::<usize>() {
+                    Ok(stripes) if stripes > 0 && fields.len() == 2 + 2 * stripes => stripes,
+                    _ => return None,
+                };
+                let chunk = match fields[1].parse::<u64>() {
+                    Ok(chunk) if chunk > 0 => chunk,
+                    _ => return None,
+                };
+                let mut devs = Vec::new();
+                for i in 0..stripes {
+                    let off = match fields[3 + 2 * i].parse() {
+                        Ok(off) => off,
+                        Err(_) => return None,
+                    };
+                    devs.push((fields[2 + 2 * i].to_owned(), off));
+                }
+                Some(Layout::Striped(chunk, devs))
+            }
+            _ => None,
+        }
+    }
+
+    fn params(&self) -> String {
+        match *self {
+            Layout::Linear(ref dev, off) => format!("{} {}", dev, off),
+            Layout::Striped(chunk, ref devs) => {
+                let mut params = format!("{} {}", devs.len(), chunk);
+                for &(ref dev, off) in devs {
+                    params.push_str(&format!(" {} {}", dev, off));
+                }
+                params
+            }
+        }
+    }
+
+    // Whether a target with this layout and `length` sectors can be
+    // followed by `next` as one target.
+    fn continues_into(&self, length: u64, next: &Layout) -> bool {
+        match (self, next) {
+            (&Layout::Linear(ref dev, off), &Layout::Linear(ref next_dev, next_off)) => {
+                dev == next_dev && off + length == next_off
+            }
+            (&Layout::Striped(chunk, ref devs), &Layout::Striped(next_chunk, ref next_devs)) => {
+                let per_stripe = length / devs.len() as u64;
+                chunk == next_chunk && devs.len() == next_devs.len() &&
+                devs.iter()
+                    .zip(next_devs.iter())
+                    .all(|(a, b)| a.0 == b.0 && a.1 + per_stripe == b.1)
+            }
+            _ => false,
+        }
+    }
+}
+
+// The kernel requires a striped target's length to divide evenly
+// into stripes, and each stripe into chunks.
+fn check_striped_length(stripes: u64, chunk: u64, length: u64) -> io::Result<()> {
+    if !length.is_multiple_of(stripes) || !(length / stripes).is_multiple_of(chunk) {
+        return Err(Error::new(InvalidInput,
+                              format!("striped target length {} is not a multiple of {} \
+                                       stripes of {} sector chunks",
+                                      length,
+                                      stripes,
+                                      chunk)));
+    }
+    Ok(())
+}
+
+/// Merge each linear or striped target into the one before it when it
+/// continues the same mapping, i.e. it maps onto the same devices,
+/// starting where the previous target's mapping ends.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::resize::merge_adjacent;
+///
+/// let table = vec![(0, 2048, "linear".to_owned(), "8:16 0".to_owned()),
+///                  (2048, 1024, "linear".to_owned(), "8:16 2048".to_owned()),
+///                  (3072, 1024, "linear".to_owned(), "8:32 0".to_owned())];
+///
+/// let merged = merge_adjacent(&table);
+/// assert_eq!(merged[0], (0, 3072, "linear".to_owned(), "8:16 0".to_owned()));
+/// assert_eq!(merged.len(), 2);
+/// ```
+pub fn merge_adjacent(targets: &[TargetLine]) -> Vec<TargetLine> {
+    let mut merged: Vec<TargetLine> = Vec::new();
+
+    for t in targets {
+        let extends_last = merged.last().is_some_and(|last| {
+            last.2 == t.2 && last.0 + last.1 == t.0 &&
+            match (Layout::parse(&last.2, &last.3), Layout::parse(&t.2, &t.3)) {
+                (Some(a), Some(b)) => a.continues_into(last.1, &b),
+                _ => false,
+            }
+        });
+
+        if extends_last {
+            merged.last_mut().expect("checked above").1 += t.1;
+        } else {
+            merged.push(t.clone());
+        }
+    }
+
+    merged
+}
+
+fn active_table<B: Backend>(dm: &B, id: &DevId) -> io::Result<Vec<TargetLine>> {
+    let (_, table) = try!(dm.table_status(id, DM_STATUS_TABLE));
+    if table.is_empty() {
+        return Err(Error::new(InvalidInput, "device has no active table"));
+    }
+    Ok(table)
+}
+
+// Load `table` and swap it in.
+fn reload<B: Backend>(dm: &B, id: &DevId, table: &[TargetLine]) -> io::Result<Sectors> {
+    try!(dm.table_load(id, table));
+    try!(dm.device_suspend(id, DM_SUSPEND));
+    try!(dm.device_suspend(id, DmFlags::empty()));
+    Ok(Sectors(table.last().map_or(0, |t| t.0 + t.1)))
+}
+
+fn extend<B: Backend>(dm: &B, id: &DevId, length: Sectors, layout: Layout) -> io::Result<Sectors> {
+    if *length == 0 {
+        return Err(Error::new(InvalidInput, "cannot extend by zero sectors"));
+    }
+
+    let target_type = match layout {
+        Layout::Linear(..) => "linear",
+        Layout::Striped(chunk, ref devs) => {
+            if devs.is_empty() || chunk == 0 {
+                return Err(Error::new(InvalidInput, "striped target needs devices and a chunk size"));
+            }
+            try!(check_striped_length(devs.len() as u64, chunk, *length));
+            "striped"
+        }
+    };
+
+    let mut table = try!(active_table(dm, id));
+    let start = table.last().map_or(0, |t| t.0 + t.1);
+    table.push((start, *length, target_type.to_owned(), layout.params()));
+
+    reload(dm, id, &merge_adjacent(&table))
+}
+
+/// Grow a device by `length` sectors, mapped onto `dev` starting at
+/// `offset`. If this continues the device's last target, that target
+/// is lengthened instead of a new one being added.
+///
+/// Returns the device's new size.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId, Device};
+/// use devicemapper::resize;
+/// use devicemapper::types::Sectors;
+///
+/// let dm = DM::new().unwrap();
+/// let sdb = Device { major: 8, minor: 16 };
+///
+/// resize::extend_linear(&dm, &DevId::Name("example-dev"), sdb, Sectors(32768), Sectors(8192))
+///     .unwrap();
+/// ```
+pub fn extend_linear<B: Backend>(dm: &B,
+                                 id: &DevId,
+                                 dev: Device,
+                                 offset: Sectors,
+                                 length: Sectors)
+                                 -> io::Result<Sectors> {
+    extend(dm, id, length, Layout::Linear(dev.dstr(), *offset))
+}
+
+/// Grow a device by `length` sectors, striped across `stripes`, each
+/// a device and the offset to start at, in chunks of `chunk_size`.
+/// `length` must be a whole number of chunks on every stripe. If this
+/// continues the device's last target, that target is lengthened
+/// instead.
+///
+/// Returns the device's new size.
+pub fn extend_striped<B: Backend>(dm: &B,
+                                  id: &DevId,
+                                  chunk_size: Sectors,
+                                  stripes: &[(Device, Sectors)],
+                                  length: Sectors)
+                                  -> io::Result<Sectors> {
+    let devs = stripes.iter().map(|&(dev, off)| (dev.dstr(), *off)).collect();
+    extend(dm, id, length, Layout::Striped(*chunk_size, devs))
+}
+
+/// Shrink a device to `new_size` sectors by dropping targets past the
+/// new end and shortening the last target kept, which must be linear
+/// or striped.
+///
+/// Data past the new end is no longer reachable through the device,
+/// so the shrink is refused if the device is open, unless `force` is
+/// set. Callers must shrink anything using the device, such as a
+/// filesystem, first.
+///
+/// Returns the device's new size.
+pub fn shrink<B: Backend>(dm: &B, id: &DevId, new_size: Sectors, force: bool) -> io::Result<Sectors> {
+    if *new_size == 0 {
+        return Err(Error::new(InvalidInput, "cannot shrink a device to zero sectors"));
+    }
+
+    let info = try!(dm.device_status(id));
+    if info.open_count() > 0 && !force {
+        return Err(Error::new(Other,
+                              format!("device {} is open {} times; not shrinking it",
+                                      info.name(),
+                                      info.open_count())));
+    }
+
+    let mut table = try!(active_table(dm, id));
+    let old_size = table.last().map_or(0, |t| t.0 + t.1);
+    if *new_size > old_size {
+        return Err(Error::new(InvalidInput,
+                              format!("new size {} is larger than current size {}",
+                                      *new_size,
+                                      old_size)));
+    }
+    if *new_size == old_size {
+        return Ok(new_size);
+    }
+
+    table.retain(|t| t.0 < *new_size);
+    let last = table.last_mut().expect("new_size > 0, so a target starts before it");
+    let length = *new_size - last.0;
+    if length != last.1 {
+        match Layout::parse(&last.2, &last.3) {
+            Some(Layout::Linear(..)) => {}
+            Some(Layout::Striped(chunk, ref devs)) => {
+                try!(check_striped_length(devs.len() as u64, chunk, length))
+            }
+            None => {
+                return Err(Error::new(InvalidInput,
+                                      format!("cannot shorten {} target", last.2)))
+            }
+        }
+        last.1 = length;
+    }
+
+    reload(dm, id, &table)
+}
@@ -0,0 +1,375 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{AlreadyExists, InvalidData};
+
+use serde;
+use serde::de::Error as DeError;
+use serde::ser::SerializeStruct;
+use serde_json;
+use serde_json::Value;
+
+use {DevId, Device, DmFlags, TargetLine};
+use {DM_ACTIVE_PRESENT, DM_INACTIVE_PRESENT, DM_QUERY_INACTIVE_TABLE, DM_READONLY,
+     DM_STATUS_TABLE};
+use backend::Backend;
+use depgraph::DepGraph;
+use tablediff;
+
+/// The saved state of one device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceState {
+    /// The device's name.
+    pub name: String,
+    /// The device's UUID, empty if it has none.
+    pub uuid: String,
+    /// The device's number when the state was captured. Tables refer
+    /// to other DM devices by number, so this is used to follow them
+    /// to wherever those devices are recreated.
+    pub device: Device,
+    /// True if the device is read-only.
+    pub read_only: bool,
+    /// The active table, if any.
+    pub active: Option<Vec<TargetLine>>,
+    /// The inactive table, if any.
+    pub inactive: Option<Vec<TargetLine>>,
+}
+
+impl DeviceState {
+    fn tables(&self) -> Vec<&Vec<TargetLine>> {
+        self.active.iter().chain(self.inactive.iter()).collect()
+    }
+}
+
+/// What `DmState::restore()` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RestoreReport {
+    /// The devices created, in the order they were created.
+    pub created: Vec<String>,
+    /// The devices that already existed with the same UUID and tables.
+    pub skipped: Vec<String>,
+}
+
+/// The state of every DM device: names, UUIDs, read-only flags, and
+/// active and inactive tables.
+///
+/// Tables are saved as the kernel reports them, so a dm-crypt table
+/// includes its key, unless the key is in the kernel keyring.
+///
+/// Non-DM devices are saved by number, and must have the same number
+/// when the state is restored.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::{DevId, DmFlags};
+/// use devicemapper::backend::{Backend, SimBackend};
+/// use devicemapper::state::DmState;
+///
+/// let sim = SimBackend::new();
+/// let id = DevId::Name("example-dev");
+/// sim.device_create("example-dev", Some("example-uuid"), DmFlags::empty()).unwrap();
+/// sim.table_load(&id, &[(0, 2048, "linear".to_owned(), "8:16 0".to_owned())]).unwrap();
+/// sim.device_suspend(&id, DmFlags::empty()).unwrap();
+///
+/// let json = DmState::capture(&sim).unwrap().to_json().unwrap();
+///
+/// let other = SimBackend::new();
+/// let report = DmState::from_json(&json).unwrap().restore(&other, false).unwrap();
+/// assert_eq!(report.created, vec!["example-dev".to_owned()]);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DmState {
+    /// The devices, in the order they were listed.
+    pub devices: Vec<DeviceState>,
+}
+
+impl DmState {
+    /// Capture the state of every device.
+    ///
+    /// A device removed while the state is captured is left out.
+    pub fn capture<B: Backend>(dm: &B) -> io::Result<DmState> {
+        let mut devices = Vec::new();
+
+        for (name, dev) in try!(dm.list_devices()) {
+            match capture_device(dm, &name, dev) {
+                Ok(state) => devices.push(state),
+                Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(DmState { devices: devices })
+    }
+
+    /// The saved state as JSON.
+    pub fn to_json(&self) -> io::Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::new(InvalidData, e))
+    }
+
+    /// Read a state saved by `to_json()`.
+    pub fn from_json(json: &str) -> io::Result<DmState> {
+        serde_json::from_str(json).map_err(|e| Error::new(InvalidData, e))
+    }
+
+    // The saved devices, ordered so each comes after the DM devices
+    // its tables use.
+    fn restore_order(&self) -> io::Result<Vec<&DeviceState>> {
+        let by_dev: BTreeMap<_, _> = self.devices.iter().map(|d| (d.device, d)).collect();
+
+        let mut graph = DepGraph::new();
+        for d in &self.devices {
+            let deps: Vec<_> = d.tables()
+                .iter()
+                .flat_map(|t| t.iter())
+                .flat_map(|t| t.3.split_whitespace())
+                .filter_map(|token| {
+                    by_dev.keys().find(|dev| dev.dstr() == token).cloned()
+                })
+                .collect();
+            graph.add_device(&d.name, d.device, &deps);
+        }
+
+        Ok(try!(graph.topo_order()).into_iter().map(|dev| by_dev[&dev]).collect())
+    }
+
+    /// Recreate the saved devices: create each device, after the
+    /// devices it is stacked on, load and activate its active table,
+    /// then load its inactive table. References to saved DM devices in
+    /// tables are updated to the devices' new numbers.
+    ///
+    /// A device that already exists is an error, unless
+    /// `skip_identical` is set and it has the saved UUID and equivalent
+    /// tables, in which case it is left alone. If restoring fails, the
+    /// devices created so far are removed.
+    pub fn restore<B: Backend>(&self, dm: &B, skip_identical: bool) -> io::Result<RestoreReport> {
+        let mut report = RestoreReport::default();
+        let mut renumbered = BTreeMap::new();
+
+        let result = self.restore_order().and_then(|order| {
+            for saved in order {
+                let rewrite = |targets: &Vec<TargetLine>| -> Vec<TargetLine> {
+                    targets.iter()
+                        .map(|t| {
+                            let params = t.3
+                                .split_whitespace()
+                                .map(|token| {
+                                    renumbered.iter()
+                                        .find(|&(old, _): &(&Device, &Device)| old.dstr() == token)
+                                        .map_or_else(|| token.to_owned(), |(_, new)| new.dstr())
+                                })
+                                .collect::<Vec<_>>()
+                                .join(" ");
+                            (t.0, t.1, t.2.clone(), params)
+                        })
+                        .collect()
+                };
+                let active = saved.active.as_ref().map(&rewrite);
+                let inactive = saved.inactive.as_ref().map(&rewrite);
+
+                let id = DevId::Name(&saved.name);
+                match dm.device_status(&id) {
+                    Ok(info) => {
+                        let existing = try!(capture_device(dm, &saved.name, info.device()));
+                        if !skip_identical || existing.uuid != saved.uuid ||
+                           !same_table(&existing.active, &active) ||
+                           !same_table(&existing.inactive, &inactive) {
+                            return Err(Error::new(AlreadyExists,
+                                                  format!("device {} already exists", saved.name)));
+                        }
+                        renumbered.insert(saved.device, info.device());
+                        report.skipped.push(saved.name.clone());
+                        continue;
+                    }
+                    Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => {}
+                    Err(err) => return Err(err),
+                }
+
+                let uuid = if saved.uuid.is_empty() {
+                    None
+                } else {
+                    Some(&saved.uuid[..])
+                };
+                let flags = if saved.read_only {
+                    DM_READONLY
+                } else {
+                    DmFlags::empty()
+                };
+                let info = try!(dm.device_create(&saved.name, uuid, flags));
+                report.created.push(saved.name.clone());
+                renumbered.insert(saved.device, info.device());
+
+                if let Some(active) = active {
+                    try!(dm.table_load(&id, &active));
+                    try!(dm.device_suspend(&id, DmFlags::empty()));
+                }
+                if let Some(inactive) = inactive {
+                    try!(dm.table_load(&id, &inactive));
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(err) = result {
+            for name in report.created.iter().rev() {
+                let _ = dm.device_remove(&DevId::Name(name), DmFlags::empty());
+            }
+            return Err(err);
+        }
+
+        Ok(report)
+    }
+}
+
+fn capture_device<B: Backend>(dm: &B, name: &str, dev: Device) -> io::Result<DeviceState> {
+    let id = DevId::Name(name);
+    let info = try!(dm.device_status(&id));
+    let flags = info.flags();
+
+    let active = if flags.contains(DM_ACTIVE_PRESENT) {
+        Some(try!(dm.table_status(&id, DM_STATUS_TABLE)).1)
+    } else {
+        None
+    };
+    let inactive = if flags.contains(DM_INACTIVE_PRESENT) {
+        Some(try!(dm.table_status(&id, DM_STATUS_TABLE | DM_QUERY_INACTIVE_TABLE)).1)
+    } else {
+        None
+    };
+
+    Ok(DeviceState {
+        name: name.to_owned(),
+        uuid: info.uuid().to_owned(),
+        device: dev,
+        read_only: flags.contains(DM_READONLY),
+        active: active,
+        inactive: inactive,
+    })
+}
+
+fn same_table(a: &Option<Vec<TargetLine>>, b: &Option<Vec<TargetLine>>) -> bool {
+    match (a.as_ref(), b.as_ref()) {
+        (None, None) => true,
+        (Some(a), Some(b)) => tablediff::diff(a, b).is_noop(),
+        _ => false,
+    }
+}
+
+struct Targets<'a>(&'a [TargetLine]);
+
+impl<'a> serde::Serialize for Targets<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let targets: Vec<_> = self.0
+            .iter()
+            .map(|t| {
+                let mut map = BTreeMap::new();
+                map.insert("start", Value::from(t.0));
+                map.insert("length", Value::from(t.1));
+                map.insert("type", Value::from(&t.2[..]));
+                map.insert("params", Value::from(&t.3[..]));
+                map
+            })
+            .collect();
+        serde::Serialize::serialize(&targets, serializer)
+    }
+}
+
+impl serde::Serialize for DeviceState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("DeviceState", 6));
+        try!(state.serialize_field("name", &self.name));
+        try!(state.serialize_field("uuid", &self.uuid));
+        try!(state.serialize_field("device", &self.device.dstr()));
+        try!(state.serialize_field("read_only", &self.read_only));
+        try!(state.serialize_field("active", &self.active.as_ref().map(|t| Targets(t))));
+        try!(state.serialize_field("inactive", &self.inactive.as_ref().map(|t| Targets(t))));
+        state.end()
+    }
+}
+
+impl serde::Serialize for DmState {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("DmState", 1));
+        try!(state.serialize_field("devices", &self.devices));
+        state.end()
+    }
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value, String> {
+    value.get(name).ok_or_else(|| format!("missing field \"{}\"", name))
+}
+
+fn str_field(value: &Value, name: &str) -> Result<String, String> {
+    try!(field(value, name))
+        .as_str()
+        .map(|s| s.to_owned())
+        .ok_or_else(|| format!("field \"{}\" is not a string", name))
+}
+
+fn u64_field(value: &Value, name: &str) -> Result<u64, String> {
+    try!(field(value, name))
+        .as_u64()
+        .ok_or_else(|| format!("field \"{}\" is not an unsigned integer", name))
+}
+
+fn targets_from_value(value: &Value, name: &str) -> Result<Option<Vec<TargetLine>>, String> {
+    let value = try!(field(value, name));
+    if value.is_null() {
+        return Ok(None);
+    }
+
+    let targets = try!(value.as_array()
+        .ok_or_else(|| format!("field \"{}\" is not an array", name)));
+    let mut table = Vec::new();
+    for t in targets {
+        table.push((try!(u64_field(t, "start")),
+                    try!(u64_field(t, "length")),
+                    try!(str_field(t, "type")),
+                    try!(str_field(t, "params"))));
+    }
+    Ok(Some(table))
+}
+
+fn device_from_value(value: &Value) -> Result<DeviceState, String> {
+    let device = try!(str_field(value, "device"));
+    Ok(DeviceState {
+        name: try!(str_field(value, "name")),
+        uuid: try!(str_field(value, "uuid")),
+        device: try!(device.parse::<Device>()
+            .ok()
+            .filter(|_| device.contains(':'))
+            .ok_or_else(|| format!("invalid device \"{}\"", device))),
+        read_only: try!(try!(field(value, "read_only"))
+            .as_bool()
+            .ok_or_else(|| "field \"read_only\" is not a boolean".to_owned())),
+        active: try!(targets_from_value(value, "active")),
+        inactive: try!(targets_from_value(value, "inactive")),
+    })
+}
+
+impl serde::Deserialize for DmState {
+    fn deserialize<D>(deserializer: D) -> Result<DmState, D::Error>
+        where D: serde::de::Deserializer
+    {
+        let value: Value = try!(serde::Deserialize::deserialize(deserializer));
+        let devices = try!(field(&value, "devices")
+            .and_then(|d| d.as_array().ok_or_else(|| "field \"devices\" is not an array".to_owned()))
+            .map_err(D::Error::custom));
+
+        let mut states = Vec::new();
+        for d in devices {
+            states.push(try!(device_from_value(d).map_err(D::Error::custom)));
+        }
+        Ok(DmState { devices: states })
+    }
+}
@@ -0,0 +1,639 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// dm-stats keeps I/O counters for regions of a DM device, each split
+// into areas of equal size. Everything is done with "@stats_*"
+// messages to the device, which return their results as text.
+
+use std::cmp;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput, NotFound};
+use std::time::{Duration, Instant};
+
+use {DM, DevId};
+use types::Sectors;
+
+/// Which part of a device a stats region covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsRange {
+    /// The whole device.
+    Whole,
+    /// `length` sectors from `start`.
+    Range {
+        /// The first sector.
+        start: Sectors,
+        /// The number of sectors.
+        length: Sectors,
+    },
+}
+
+/// How a stats region is split into areas, each with its own
+/// counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsStep {
+    /// Into this many areas of equal size.
+    Areas(u64),
+    /// Into areas of this size. The last may be smaller.
+    AreaSize(Sectors),
+}
+
+/// A stats region to create.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use devicemapper::stats::{RegionSpec, StatsStep};
+/// use devicemapper::types::Sectors;
+///
+/// let mut spec = RegionSpec::range(Sectors(0), Sectors(2048));
+/// spec.step(StatsStep::Areas(4))
+///     .histogram(&[Duration::from_millis(1), Duration::from_millis(10)])
+///     .program_id("iomon");
+///
+/// assert_eq!(spec.message().unwrap(),
+///            "@stats_create 0+2048 /4 1 histogram:1,10 iomon");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionSpec {
+    range: StatsRange,
+    step: StatsStep,
+    precise_timestamps: bool,
+    histogram: Vec<Duration>,
+    program_id: Option<String>,
+    aux_data: Option<String>,
+}
+
+impl RegionSpec {
+    fn new(range: StatsRange) -> RegionSpec {
+        RegionSpec {
+            range: range,
+            step: StatsStep::Areas(1),
+            precise_timestamps: false,
+            histogram: Vec::new(),
+            program_id: None,
+            aux_data: None,
+        }
+    }
+
+    /// A region covering the whole device, as one area.
+    pub fn whole() -> RegionSpec {
+        RegionSpec::new(StatsRange::Whole)
+    }
+
+    /// A region covering `length` sectors from `start`, as one area.
+    pub fn range(start: Sectors, length: Sectors) -> RegionSpec {
+        RegionSpec::new(StatsRange::Range {
+            start: start,
+            length: length,
+        })
+    }
+
+    /// Split the region into areas.
+    pub fn step(&mut self, step: StatsStep) -> &mut RegionSpec {
+        self.step = step;
+        self
+    }
+
+    /// Count time in nanoseconds rather than milliseconds.
+    pub fn precise_timestamps(&mut self, precise: bool) -> &mut RegionSpec {
+        self.precise_timestamps = precise;
+        self
+    }
+
+    /// Keep a histogram of I/O latency, with buckets split at
+    /// `bounds`, which must be increasing. Without precise
+    /// timestamps, the bounds must be whole milliseconds.
+    pub fn histogram(&mut self, bounds: &[Duration]) -> &mut RegionSpec {
+        self.histogram = bounds.to_vec();
+        self
+    }
+
+    /// Tag the region with the id of the program using it, so other
+    /// programs' regions can be told apart.
+    pub fn program_id(&mut self, program_id: &str) -> &mut RegionSpec {
+        self.program_id = Some(program_id.to_owned());
+        self
+    }
+
+    /// Attach data for the program's own use. Needs a program id.
+    pub fn aux_data(&mut self, aux_data: &str) -> &mut RegionSpec {
+        self.aux_data = Some(aux_data.to_owned());
+        self
+    }
+
+    /// The `@stats_create` message for the region.
+    pub fn message(&self) -> io::Result<String> {
+        let range = match self.range {
+            StatsRange::Whole => "-".to_owned(),
+            StatsRange::Range { start, length } => {
+                if *length == 0 {
+                    return Err(Error::new(InvalidInput, "stats region length cannot be zero"));
+                }
+                format!("{}+{}", *start, *length)
+            }
+        };
+        let step = match self.step {
+            StatsStep::Areas(0) => {
+                return Err(Error::new(InvalidInput, "stats region needs at least one area"))
+            }
+            StatsStep::AreaSize(Sectors(0)) => {
+                return Err(Error::new(InvalidInput, "stats area size cannot be zero"))
+            }
+            StatsStep::Areas(n) => format!("/{}", n),
+            StatsStep::AreaSize(size) => format!("{}", *size),
+        };
+        let mut msg = format!("@stats_create {} {}", range, step);
+
+        let mut args = Vec::new();
+        if self.precise_timestamps {
+            args.push("precise_timestamps".to_owned());
+        }
+        if !self.histogram.is_empty() {
+            let mut bounds = Vec::new();
+            for b in &self.histogram {
+                bounds.push(try!(to_ticks(*b, self.precise_timestamps)));
+            }
+            if bounds.windows(2).any(|w| w[0] >= w[1]) {
+                return Err(Error::new(InvalidInput, "histogram bounds must be increasing"));
+            }
+            let bounds: Vec<_> = bounds.iter().map(|b| b.to_string()).collect();
+            args.push(format!("histogram:{}", bounds.join(",")));
+        }
+        if !args.is_empty() {
+            msg.push_str(&format!(" {} {}", args.len(), args.join(" ")));
+        }
+
+        for word in self.program_id.iter().chain(self.aux_data.iter()) {
+            if word.is_empty() || word.contains(char::is_whitespace) {
+                return Err(Error::new(InvalidInput,
+                                      format!("stats program id or aux data \"{}\" is empty \
+                                               or contains whitespace",
+                                              word)));
+            }
+        }
+        match (&self.program_id, &self.aux_data) {
+            (Some(id), Some(aux)) => msg.push_str(&format!(" {} {}", id, aux)),
+            (Some(id), None) => msg.push_str(&format!(" {}", id)),
+            (None, Some(_)) => {
+                return Err(Error::new(InvalidInput, "stats aux data needs a program id"))
+            }
+            (None, None) => {}
+        }
+
+        Ok(msg)
+    }
+}
+
+fn to_ticks(d: Duration, precise: bool) -> io::Result<u64> {
+    if precise {
+        return Ok(d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64);
+    }
+    if !d.subsec_nanos().is_multiple_of(1_000_000) {
+        return Err(Error::new(InvalidInput,
+                              format!("{:?} is not a whole number of milliseconds; precise \
+                                       timestamps are needed",
+                                      d)));
+    }
+    Ok(d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1_000_000)
+}
+
+fn from_ticks(ticks: u64, precise: bool) -> Duration {
+    if precise {
+        Duration::new(ticks / 1_000_000_000, (ticks % 1_000_000_000) as u32)
+    } else {
+        Duration::from_millis(ticks)
+    }
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.parse::<u64>().map_err(|_| Error::new(InvalidData, format!("expected number, got \"{}\"", s)))
+}
+
+// Parse "<start>+<length>".
+fn parse_range(s: &str) -> io::Result<(Sectors, Sectors)> {
+    let mut spl = s.splitn(2, '+');
+    match (spl.next(), spl.next()) {
+        (Some(start), Some(length)) => Ok((Sectors(try!(parse_u64(start))), Sectors(try!(parse_u64(length))))),
+        _ => Err(Error::new(InvalidData, format!("expected start+length, got \"{}\"", s))),
+    }
+}
+
+/// A stats region, as listed by `@stats_list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionInfo {
+    /// The region's id.
+    pub id: u64,
+    /// The first sector.
+    pub start: Sectors,
+    /// The number of sectors.
+    pub length: Sectors,
+    /// The size of each area.
+    pub area_size: Sectors,
+    /// The program id, "-" if none was given.
+    pub program_id: String,
+    /// The aux data, "-" if none was given.
+    pub aux_data: String,
+    /// Whether times are counted in nanoseconds.
+    pub precise_timestamps: bool,
+    /// The latency histogram's bucket bounds, if it has one.
+    pub histogram: Vec<Duration>,
+}
+
+impl RegionInfo {
+    /// Parse a line of `@stats_list` output:
+    /// "<id>: <start>+<length> <area size> <program id> <aux data>
+    /// [precise_timestamps] [histogram:<bound>,...]".
+    pub fn parse(line: &str) -> io::Result<RegionInfo> {
+        let vals: Vec<_> = line.split_whitespace().collect();
+        if vals.len() < 5 || !vals[0].ends_with(':') {
+            return Err(Error::new(InvalidData, format!("bad stats region \"{}\"", line)));
+        }
+
+        let (start, length) = try!(parse_range(vals[1]));
+        let precise = vals[5..].contains(&"precise_timestamps");
+        let mut histogram = Vec::new();
+        if let Some(bounds) = vals[5..].iter().filter_map(|v| v.strip_prefix("histogram:")).next() {
+            for b in bounds.split(',') {
+                histogram.push(from_ticks(try!(parse_u64(b)), precise));
+            }
+        }
+
+        Ok(RegionInfo {
+            id: try!(parse_u64(vals[0].trim_end_matches(':'))),
+            start: start,
+            length: length,
+            area_size: Sectors(try!(parse_u64(vals[2]))),
+            program_id: vals[3].to_owned(),
+            aux_data: vals[4].to_owned(),
+            precise_timestamps: precise,
+            histogram: histogram,
+        })
+    }
+
+    /// The number of areas in the region.
+    pub fn nr_areas(&self) -> u64 {
+        if *self.area_size == 0 {
+            return 1;
+        }
+        (*self.length).div_ceil(*self.area_size)
+    }
+}
+
+/// The counters of one area of a stats region. The first eleven
+/// match those in /proc/diskstats.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AreaCounters {
+    /// The area's first sector.
+    pub start: Sectors,
+    /// The area's length.
+    pub length: Sectors,
+    /// Reads completed.
+    pub reads: u64,
+    /// Reads merged with others before being issued.
+    pub reads_merged: u64,
+    /// Sectors read.
+    pub read_sectors: u64,
+    /// Time spent by all reads.
+    pub read_time: Duration,
+    /// Writes completed.
+    pub writes: u64,
+    /// Writes merged with others before being issued.
+    pub writes_merged: u64,
+    /// Sectors written.
+    pub write_sectors: u64,
+    /// Time spent by all writes.
+    pub write_time: Duration,
+    /// I/Os in progress.
+    pub in_flight: u64,
+    /// Time with I/O in progress.
+    pub io_time: Duration,
+    /// Time spent by all I/Os, including waiting to be issued.
+    pub weighted_io_time: Duration,
+    /// Time with reads in progress.
+    pub read_busy_time: Duration,
+    /// Time with writes in progress.
+    pub write_busy_time: Duration,
+    /// I/Os in each latency histogram bucket: below the first bound,
+    /// between each pair, and above the last. Empty without a
+    /// histogram.
+    pub histogram: Vec<u64>,
+}
+
+impl Default for AreaCounters {
+    fn default() -> AreaCounters {
+        AreaCounters {
+            start: Sectors(0),
+            length: Sectors(0),
+            reads: 0,
+            reads_merged: 0,
+            read_sectors: 0,
+            read_time: Duration::default(),
+            writes: 0,
+            writes_merged: 0,
+            write_sectors: 0,
+            write_time: Duration::default(),
+            in_flight: 0,
+            io_time: Duration::default(),
+            weighted_io_time: Duration::default(),
+            read_busy_time: Duration::default(),
+            write_busy_time: Duration::default(),
+            histogram: Vec::new(),
+        }
+    }
+}
+
+impl AreaCounters {
+    /// Parse a line of `@stats_print` output for a region that does,
+    /// or does not, use `precise` timestamps.
+    pub fn parse(line: &str, precise: bool) -> io::Result<AreaCounters> {
+        let vals: Vec<_> = line.split_whitespace().collect();
+        if vals.len() < 14 {
+            return Err(Error::new(InvalidData,
+                                  format!("too few fields in stats counters \"{}\"", line)));
+        }
+
+        let (start, length) = try!(parse_range(vals[0]));
+        let mut c = [0u64; 13];
+        for (i, v) in vals[1..14].iter().enumerate() {
+            c[i] = try!(parse_u64(v));
+        }
+        let mut histogram = Vec::new();
+        if let Some(h) = vals.get(14) {
+            for n in h.split(':') {
+                histogram.push(try!(parse_u64(n)));
+            }
+        }
+
+        let t = |ticks| from_ticks(ticks, precise);
+        Ok(AreaCounters {
+            start: start,
+            length: length,
+            reads: c[0],
+            reads_merged: c[1],
+            read_sectors: c[2],
+            read_time: t(c[3]),
+            writes: c[4],
+            writes_merged: c[5],
+            write_sectors: c[6],
+            write_time: t(c[7]),
+            in_flight: c[8],
+            io_time: t(c[9]),
+            weighted_io_time: t(c[10]),
+            read_busy_time: t(c[11]),
+            write_busy_time: t(c[12]),
+            histogram: histogram,
+        })
+    }
+
+    // Add `other`'s counters to these, as if they were one area. The
+    // areas' busy periods overlap, so the longest is kept as the
+    // busy time of both.
+    fn add(&mut self, other: &AreaCounters) {
+        if self.length == Sectors(0) {
+            self.start = other.start;
+        }
+        self.length += other.length;
+        self.reads += other.reads;
+        self.reads_merged += other.reads_merged;
+        self.read_sectors += other.read_sectors;
+        self.read_time += other.read_time;
+        self.writes += other.writes;
+        self.writes_merged += other.writes_merged;
+        self.write_sectors += other.write_sectors;
+        self.write_time += other.write_time;
+        self.in_flight += other.in_flight;
+        self.io_time = cmp::max(self.io_time, other.io_time);
+        self.weighted_io_time += other.weighted_io_time;
+        self.read_busy_time = cmp::max(self.read_busy_time, other.read_busy_time);
+        self.write_busy_time = cmp::max(self.write_busy_time, other.write_busy_time);
+        if self.histogram.len() < other.histogram.len() {
+            self.histogram.resize(other.histogram.len(), 0);
+        }
+        for (a, b) in self.histogram.iter_mut().zip(other.histogram.iter()) {
+            *a += *b;
+        }
+    }
+}
+
+/// Rates of I/O over the time between two samples of an area's
+/// counters.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoRates {
+    /// Reads completed per second.
+    pub reads_per_sec: f64,
+    /// Writes completed per second.
+    pub writes_per_sec: f64,
+    /// Reads merged per second.
+    pub reads_merged_per_sec: f64,
+    /// Writes merged per second.
+    pub writes_merged_per_sec: f64,
+    /// Sectors read per second.
+    pub read_sectors_per_sec: f64,
+    /// Sectors written per second.
+    pub write_sectors_per_sec: f64,
+    /// Mean time per read, in milliseconds.
+    pub read_await_ms: f64,
+    /// Mean time per write, in milliseconds.
+    pub write_await_ms: f64,
+    /// Mean number of I/Os queued or in progress.
+    pub queue_size: f64,
+    /// The fraction of the time with I/O in progress, from 0 to 1.
+    pub utilization: f64,
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+impl IoRates {
+    /// The rates between `earlier` and `later` counters of the same
+    /// area, sampled `interval` apart. Counters that went backwards,
+    /// as after `@stats_print_clear`, count as zero.
+    pub fn between(earlier: &AreaCounters, later: &AreaCounters, interval: Duration) -> IoRates {
+        let secs_elapsed = secs(interval);
+        if secs_elapsed == 0.0 {
+            return IoRates::default();
+        }
+        let delta = |a: u64, b: u64| b.saturating_sub(a) as f64;
+        let delta_secs = |a: Duration, b: Duration| secs(b.checked_sub(a).unwrap_or_default());
+
+        let reads = delta(earlier.reads, later.reads);
+        let writes = delta(earlier.writes, later.writes);
+        let per_io_ms = |time: f64, n: f64| if n > 0.0 { time * 1000.0 / n } else { 0.0 };
+
+        IoRates {
+            reads_per_sec: reads / secs_elapsed,
+            writes_per_sec: writes / secs_elapsed,
+            reads_merged_per_sec: delta(earlier.reads_merged, later.reads_merged) / secs_elapsed,
+            writes_merged_per_sec: delta(earlier.writes_merged, later.writes_merged) / secs_elapsed,
+            read_sectors_per_sec: delta(earlier.read_sectors, later.read_sectors) / secs_elapsed,
+            write_sectors_per_sec: delta(earlier.write_sectors, later.write_sectors) / secs_elapsed,
+            read_await_ms: per_io_ms(delta_secs(earlier.read_time, later.read_time), reads),
+            write_await_ms: per_io_ms(delta_secs(earlier.write_time, later.write_time), writes),
+            queue_size: delta_secs(earlier.weighted_io_time, later.weighted_io_time) /
+                        secs_elapsed,
+            utilization: (delta_secs(earlier.io_time, later.io_time) / secs_elapsed).min(1.0),
+        }
+    }
+}
+
+/// The counters of every area of a stats region, read at one time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsSample {
+    /// The region's id.
+    pub region_id: u64,
+    /// When the counters were read.
+    pub taken: Instant,
+    /// Each area's counters.
+    pub areas: Vec<AreaCounters>,
+}
+
+impl StatsSample {
+    /// The counters of the whole region: the sum over its areas,
+    /// except the busy times, which are the longest of any area's,
+    /// as an area being busy does not stop others being so.
+    pub fn total(&self) -> AreaCounters {
+        let mut total = AreaCounters::default();
+        for a in &self.areas {
+            total.add(a);
+        }
+        total
+    }
+
+    /// Each area's rates since `earlier`, a sample of the same region.
+    pub fn rates(&self, earlier: &StatsSample) -> io::Result<Vec<IoRates>> {
+        try!(self.check_comparable(earlier));
+        let interval = self.taken.duration_since(earlier.taken);
+        Ok(earlier.areas
+            .iter()
+            .zip(self.areas.iter())
+            .map(|(e, l)| IoRates::between(e, l, interval))
+            .collect())
+    }
+
+    /// The whole region's rates since `earlier`, a sample of the same
+    /// region.
+    pub fn total_rates(&self, earlier: &StatsSample) -> io::Result<IoRates> {
+        try!(self.check_comparable(earlier));
+        Ok(IoRates::between(&earlier.total(),
+                            &self.total(),
+                            self.taken.duration_since(earlier.taken)))
+    }
+
+    fn check_comparable(&self, earlier: &StatsSample) -> io::Result<()> {
+        if self.region_id != earlier.region_id || self.areas.len() != earlier.areas.len() ||
+           self.taken < earlier.taken {
+            return Err(Error::new(InvalidInput,
+                                  "samples are not of the same region, in order"));
+        }
+        Ok(())
+    }
+}
+
+fn stats_msg(dm: &DM, dev: &DevId, msg: &str) -> io::Result<String> {
+    let (_, out) = try!(dm.target_msg(dev, 0, msg));
+    Ok(out.unwrap_or_default())
+}
+
+/// Create a stats region on `dev`, returning its id.
+pub fn create_region(dm: &DM, dev: &DevId, spec: &RegionSpec) -> io::Result<u64> {
+    let out = try!(stats_msg(dm, dev, &try!(spec.message())));
+    parse_u64(out.trim())
+}
+
+/// List the stats regions on `dev`, or only those of `program_id`.
+pub fn list_regions(dm: &DM, dev: &DevId, program_id: Option<&str>) -> io::Result<Vec<RegionInfo>> {
+    let msg = match program_id {
+        Some(id) => format!("@stats_list {}", id),
+        None => "@stats_list".to_owned(),
+    };
+    let out = try!(stats_msg(dm, dev, &msg));
+    out.lines().filter(|l| !l.trim().is_empty()).map(RegionInfo::parse).collect()
+}
+
+/// Get the stats region `id` on `dev`.
+pub fn region_info(dm: &DM, dev: &DevId, id: u64) -> io::Result<RegionInfo> {
+    try!(list_regions(dm, dev, None))
+        .into_iter()
+        .find(|r| r.id == id)
+        .ok_or_else(|| Error::new(NotFound, format!("no stats region {}", id)))
+}
+
+/// Delete stats region `id` from `dev`.
+pub fn delete_region(dm: &DM, dev: &DevId, id: u64) -> io::Result<()> {
+    try!(stats_msg(dm, dev, &format!("@stats_delete {}", id)));
+    Ok(())
+}
+
+/// Zero the counters of stats region `id` on `dev`, except those of
+/// I/O in progress.
+pub fn clear_region(dm: &DM, dev: &DevId, id: u64) -> io::Result<()> {
+    try!(stats_msg(dm, dev, &format!("@stats_clear {}", id)));
+    Ok(())
+}
+
+/// Replace the aux data of stats region `id` on `dev`.
+pub fn set_aux(dm: &DM, dev: &DevId, id: u64, aux_data: &str) -> io::Result<()> {
+    if aux_data.is_empty() || aux_data.contains(char::is_whitespace) {
+        return Err(Error::new(InvalidInput, "stats aux data cannot be empty or contain whitespace"));
+    }
+    try!(stats_msg(dm, dev, &format!("@stats_set_aux {} {}", id, aux_data)));
+    Ok(())
+}
+
+fn print(dm: &DM, dev: &DevId, region: &RegionInfo, msg: &str) -> io::Result<StatsSample> {
+    let out = try!(stats_msg(dm, dev, &format!("{} {}", msg, region.id)));
+    let taken = Instant::now();
+
+    let mut areas = Vec::new();
+    for line in out.lines().filter(|l| !l.trim().is_empty()) {
+        areas.push(try!(AreaCounters::parse(line, region.precise_timestamps)));
+    }
+
+    Ok(StatsSample {
+        region_id: region.id,
+        taken: taken,
+        areas: areas,
+    })
+}
+
+/// Read the counters of `region` on `dev`.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// use devicemapper::{DM, DevId};
+/// use devicemapper::stats;
+/// use devicemapper::stats::RegionSpec;
+///
+/// let dm = DM::new().unwrap();
+/// let dev = DevId::Name("example-dev");
+///
+/// let id = stats::create_region(&dm, &dev, &RegionSpec::whole()).unwrap();
+/// let region = stats::region_info(&dm, &dev, id).unwrap();
+///
+/// let before = stats::print_region(&dm, &dev, &region).unwrap();
+/// thread::sleep(Duration::from_secs(1));
+/// let after = stats::print_region(&dm, &dev, &region).unwrap();
+///
+/// let rates = after.total_rates(&before).unwrap();
+/// println!("{:.1} r/s {:.1} w/s", rates.reads_per_sec, rates.writes_per_sec);
+///
+/// stats::delete_region(&dm, &dev, id).unwrap();
+/// ```
+pub fn print_region(dm: &DM, dev: &DevId, region: &RegionInfo) -> io::Result<StatsSample> {
+    print(dm, dev, region, "@stats_print")
+}
+
+/// Read the counters of `region` on `dev`, and zero them, as one
+/// operation.
+pub fn print_clear_region(dm: &DM, dev: &DevId, region: &RegionInfo) -> io::Result<StatsSample> {
+    print(dm, dev, region, "@stats_print_clear")
+}
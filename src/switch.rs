@@ -0,0 +1,343 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// dm-switch splits a device into fixed-size regions, each of which
+// is mapped onto one of several paths by a region table that starts
+// out round-robin and is changed with "set_region_mappings" messages.
+// A message's args are hex: "<index>:<path>" maps one region, ":<path>"
+// maps the region after the last one set, and "R<n>,<m>" repeats the
+// last n mappings over the next m regions.
+
+use std::cmp;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::InvalidInput;
+
+use {DM, DevId, Device, TargetLine};
+use types::Sectors;
+
+/// The longest message `set_region_mappings_messages()` generates,
+/// in bytes.
+pub const MAX_MESSAGE_LEN: usize = 4096;
+
+// How far back to look for a repeating pattern.
+const MAX_CYCLE: usize = 64;
+
+/// A dm-switch target, mapping each region of `region_size` onto one
+/// of its paths.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::switch::SwitchTarget;
+/// use devicemapper::types::Sectors;
+///
+/// let paths = vec![(Device { major: 8, minor: 16 }, Sectors(0)),
+///                  (Device { major: 8, minor: 32 }, Sectors(0))];
+/// let switch = SwitchTarget::new(Sectors(128), paths).unwrap();
+///
+/// assert_eq!(switch.params(), "2 128 0 8:16 0 8:32 0");
+/// assert_eq!(switch.nr_regions(Sectors(1000)), 8);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwitchTarget {
+    region_size: Sectors,
+    paths: Vec<(Device, Sectors)>,
+}
+
+impl SwitchTarget {
+    /// A switch over `paths`, each a device and the offset to start
+    /// at, in regions of `region_size`.
+    pub fn new(region_size: Sectors, paths: Vec<(Device, Sectors)>) -> io::Result<SwitchTarget> {
+        if *region_size == 0 {
+            return Err(Error::new(InvalidInput, "switch region size cannot be zero"));
+        }
+        if paths.is_empty() {
+            return Err(Error::new(InvalidInput, "switch target needs at least one path"));
+        }
+
+        Ok(SwitchTarget {
+            region_size: region_size,
+            paths: paths,
+        })
+    }
+
+    /// The number of paths.
+    pub fn nr_paths(&self) -> usize {
+        self.paths.len()
+    }
+
+    /// The region size.
+    pub fn region_size(&self) -> Sectors {
+        self.region_size
+    }
+
+    /// The number of regions in a target of `length` sectors.
+    pub fn nr_regions(&self, length: Sectors) -> u64 {
+        (*length).div_ceil(*self.region_size)
+    }
+
+    /// The target's params, for `DM::table_load()`.
+    pub fn params(&self) -> String {
+        let mut params = format!("{} {} 0", self.paths.len(), *self.region_size);
+        for &(dev, off) in &self.paths {
+            params.push_str(&format!(" {} {}", dev.dstr(), *off));
+        }
+        params
+    }
+
+    /// A target line of `length` sectors starting at `start`.
+    pub fn target(&self, start: Sectors, length: Sectors) -> TargetLine {
+        (*start, *length, "switch".to_owned(), self.params())
+    }
+
+    /// The region table the target starts with: region i on path
+    /// i % paths.
+    pub fn initial_mappings(&self, length: Sectors) -> Vec<u32> {
+        (0..self.nr_regions(length)).map(|r| (r % self.paths.len() as u64) as u32).collect()
+    }
+}
+
+// One arg of a set_region_mappings message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    // Map one region.
+    Path(u32),
+    // Repeat the last `cycle` mappings over the next `count` regions.
+    Repeat(usize, usize),
+}
+
+fn hex_len(n: u64) -> usize {
+    cmp::max(1, (64 - n.leading_zeros() as usize).div_ceil(4))
+}
+
+// The shortest series of args mapping all of `paths` after the
+// first, as (first region mapped, arg), using cycles of up to
+// MAX_CYCLE.
+//
+// Mapping fewer regions never costs more, so of the repeat counts
+// with the same number of hex digits, the largest is always best, and
+// only those need to be tried.
+fn plan_args(paths: &[u32]) -> Vec<(usize, Arg)> {
+    let n = paths.len();
+    // cost[i]: the length of the args mapping regions i..n.
+    let mut cost = vec![0usize; n + 1];
+    let mut choice = vec![Arg::Path(0); n];
+    // runs[c]: how many regions from i on match those c before them.
+    let mut runs = vec![0usize; MAX_CYCLE + 1];
+
+    for i in (1..n).rev() {
+        choice[i] = Arg::Path(paths[i]);
+        cost[i] = 2 + hex_len(paths[i] as u64) + cost[i + 1];
+
+        for c in 1..cmp::min(i, MAX_CYCLE) + 1 {
+            runs[c] = if paths[i] == paths[i - c] { runs[c] + 1 } else { 0 };
+
+            let mut count = runs[c];
+            while count > 0 {
+                let digits = hex_len(count as u64);
+                let c_cost = 3 + hex_len(c as u64) + digits + cost[i + count];
+                if c_cost < cost[i] {
+                    cost[i] = c_cost;
+                    choice[i] = Arg::Repeat(c, count);
+                }
+                // The largest count with one hex digit fewer.
+                count = (1 << (4 * (digits - 1))) - 1;
+            }
+        }
+    }
+
+    let mut args = Vec::new();
+    let mut i = 1;
+    while i < n {
+        args.push((i, choice[i]));
+        i += match choice[i] {
+            Arg::Path(_) => 1,
+            Arg::Repeat(_, count) => count,
+        };
+    }
+    args
+}
+
+/// `set_region_mappings` messages that map regions `start`,
+/// `start + 1`, ... onto `paths`. The args are the shortest that map
+/// the regions using repeats of cycles of up to 64 regions, split into
+/// messages no longer than `MAX_MESSAGE_LEN`. The messages must be
+/// sent in order.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::switch::set_region_mappings_messages;
+///
+/// let paths = [0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 1];
+/// assert_eq!(set_region_mappings_messages(16, &paths),
+///            vec!["set_region_mappings 10:0 :1 :2 R3,9 :1"]);
+/// ```
+pub fn set_region_mappings_messages(start: u64, paths: &[u32]) -> Vec<String> {
+    const PREFIX: &str = "set_region_mappings";
+
+    let mut messages = Vec::new();
+    if paths.is_empty() {
+        return messages;
+    }
+
+    let mut msg = format!("{} {:x}:{:x}", PREFIX, start, paths[0]);
+    for (i, arg) in plan_args(paths) {
+        let index = start + i as u64;
+        let token = match arg {
+            Arg::Path(path) => format!(" :{:x}", path),
+            Arg::Repeat(cycle, count) => format!(" R{:x},{:x}", cycle, count),
+        };
+
+        if msg.len() + token.len() > MAX_MESSAGE_LEN {
+            messages.push(msg);
+            // A new message starts with no last region set, so begin
+            // with an explicit index: the region itself, or for a
+            // repeat, the one before it, mapped again.
+            msg = match arg {
+                Arg::Path(path) => format!("{} {:x}:{:x}", PREFIX, index, path),
+                Arg::Repeat(..) => {
+                    format!("{} {:x}:{:x}{}", PREFIX, index - 1, paths[i - 1], token)
+                }
+            };
+        } else {
+            msg.push_str(&token);
+        }
+    }
+    messages.push(msg);
+
+    messages
+}
+
+/// Map regions `start`, `start + 1`, ... of the switch device `dev`
+/// onto `paths`, by path number.
+pub fn set_region_mappings(dm: &DM, dev: &DevId, start: u64, paths: &[u32]) -> io::Result<()> {
+    for msg in set_region_mappings_messages(start, paths) {
+        try!(dm.target_msg(dev, 0, &msg));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arg_len(arg: Arg) -> usize {
+        match arg {
+            Arg::Path(path) => 2 + hex_len(path as u64),
+            Arg::Repeat(cycle, count) => 3 + hex_len(cycle as u64) + hex_len(count as u64),
+        }
+    }
+
+    // The length of the shortest args mapping regions i.., trying
+    // every path, cycle and count.
+    fn brute_force(paths: &[u32], i: usize, memo: &mut Vec<Option<usize>>) -> usize {
+        if i == paths.len() {
+            return 0;
+        }
+        if let Some(cost) = memo[i] {
+            return cost;
+        }
+
+        let mut best = arg_len(Arg::Path(paths[i])) + brute_force(paths, i + 1, memo);
+        for cycle in 1..cmp::min(i, MAX_CYCLE) + 1 {
+            let mut count = 0;
+            while i + count < paths.len() && paths[i + count] == paths[i + count - cycle] {
+                count += 1;
+                let cost = arg_len(Arg::Repeat(cycle, count)) + brute_force(paths, i + count, memo);
+                best = cmp::min(best, cost);
+            }
+        }
+
+        memo[i] = Some(best);
+        best
+    }
+
+    // Apply messages to a region table the way the kernel does.
+    fn apply(messages: &[String], table: &mut [u32]) {
+        for msg in messages {
+            let mut last = 0;
+            for arg in msg.split_whitespace().skip(1) {
+                if let Some(repeat) = arg.strip_prefix('R') {
+                    let mut spl = repeat.split(',');
+                    let cycle = usize::from_str_radix(spl.next().unwrap(), 16).unwrap();
+                    let count = usize::from_str_radix(spl.next().unwrap(), 16).unwrap();
+                    for _ in 0..count {
+                        last += 1;
+                        table[last] = table[last - cycle];
+                    }
+                } else {
+                    let mut spl = arg.split(':');
+                    let index = spl.next().unwrap();
+                    last = if index.is_empty() {
+                        last + 1
+                    } else {
+                        usize::from_str_radix(index, 16).unwrap()
+                    };
+                    table[last] = u32::from_str_radix(spl.next().unwrap(), 16).unwrap();
+                }
+            }
+        }
+    }
+
+    fn check(paths: &[u32]) {
+        let args = plan_args(paths);
+        let len: usize = args.iter().map(|&(_, arg)| arg_len(arg)).sum();
+        let mut memo = vec![None; paths.len()];
+        assert_eq!(len, brute_force(paths, 1, &mut memo), "{:?}", paths);
+
+        let start = 5;
+        let mut table = vec![u32::MAX; start + paths.len()];
+        apply(&set_region_mappings_messages(start as u64, paths), &mut table);
+        assert_eq!(&table[start..], paths);
+    }
+
+    #[test]
+    fn shortest_args_for_all_small_tables() {
+        for n in 1..9 {
+            for mut x in 0..3usize.pow(n as u32) {
+                let mut paths = Vec::new();
+                for _ in 0..n {
+                    paths.push((x % 3) as u32);
+                    x /= 3;
+                }
+                check(&paths);
+            }
+        }
+    }
+
+    #[test]
+    fn shortest_args_for_long_repeats() {
+        // Runs long enough for counts of two and three hex digits,
+        // with an odd region here and there.
+        let mut seed = 12345u32;
+        for len in &[17, 40, 255, 256, 300] {
+            for cycle in 1..5 {
+                let mut paths: Vec<u32> = (0..*len).map(|i| (i % cycle) as u32).collect();
+                check(&paths);
+
+                for _ in 0..3 {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    let at = (seed >> 8) as usize % paths.len();
+                    paths[at] = 17;
+                    check(&paths);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn split_messages() {
+        let paths: Vec<u32> = (0..5000).map(|i| (i * 7919 % 13) as u32 ^ (i % 5) as u32).collect();
+        let messages = set_region_mappings_messages(0, &paths);
+        assert!(messages.len() > 1);
+        assert!(messages.iter().all(|m| m.len() <= MAX_MESSAGE_LEN));
+
+        let mut table = vec![u32::MAX; paths.len()];
+        apply(&messages, &mut table);
+        assert_eq!(table, paths);
+    }
+}
@@ -0,0 +1,423 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::borrow::Borrow;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput};
+use std::str::FromStr;
+
+use TargetLine;
+use types::Sectors;
+
+// Matches the size of dm_target_spec.target_type, less the null.
+const DM_MAX_TYPE_NAME: usize = 15;
+
+// Target types the kernel requires to be the only target in a table.
+const SINGLETON_TARGETS: &[&str] = &["thin-pool", "integrity"];
+
+/// A mapping table, built up one target at a time.
+///
+/// Each target is appended after the previous one, so the table
+/// always covers a contiguous range of sectors starting at 0. Tables
+/// built from existing target lines are checked for the same
+/// properties by `from_targets()`.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::table::Table;
+/// use devicemapper::types::Sectors;
+///
+/// let mut table = Table::new();
+/// table.push(Sectors(2048), "linear", "/dev/sdb1 0").unwrap();
+/// table.push(Sectors(4096), "linear", "/dev/sdc1 0").unwrap();
+///
+/// assert_eq!(table.size(), Sectors(6144));
+/// assert!(table.push(Sectors(0), "zero", "").is_err());
+///
+/// // dm.table_load(&DevId::Name("example-dev"), table.targets())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Table {
+    targets: Vec<TargetLine>,
+}
+
+impl Table {
+    /// Create an empty table.
+    pub fn new() -> Table {
+        Table { targets: Vec::new() }
+    }
+
+    /// Create a table from (sector_start, sector_length, type, params)
+    /// tuples, as taken by `DM::table_load()`, checking that they form
+    /// a valid table.
+    pub fn from_targets<T1, T2>(targets: &[(u64, u64, T1, T2)]) -> io::Result<Table>
+        where T1: Borrow<str>,
+              T2: Borrow<str>
+    {
+        try!(validate(targets));
+
+        Ok(Table {
+            targets: targets.iter()
+                .map(|t| (t.0, t.1, t.2.borrow().to_owned(), t.3.borrow().to_owned()))
+                .collect(),
+        })
+    }
+
+    /// Append a target of `length` sectors to the end of the table.
+    pub fn push(&mut self, length: Sectors, target_type: &str, params: &str) -> io::Result<()> {
+        try!(check_target(*length, target_type, params));
+
+        if let Some(ttype) = self.targets
+            .iter()
+            .map(|t| &t.2[..])
+            .chain(Some(target_type))
+            .find(|ttype| SINGLETON_TARGETS.contains(ttype)) {
+            if !self.targets.is_empty() {
+                return Err(Error::new(InvalidInput,
+                                      format!("{} target must be the only target in a table",
+                                              ttype)));
+            }
+        }
+
+        let start = *self.size();
+        self.targets.push((start, *length, target_type.to_owned(), params.to_owned()));
+
+        Ok(())
+    }
+
+    /// The total size of the table.
+    pub fn size(&self) -> Sectors {
+        Sectors(self.targets.last().map_or(0, |t| t.0 + t.1))
+    }
+
+    /// The number of targets in the table.
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// True if no targets have been added.
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// The table's targets, in the form `DM::table_load()` takes.
+    pub fn targets(&self) -> &[TargetLine] {
+        &self.targets
+    }
+}
+
+impl FromStr for Table {
+    type Err = Error;
+
+    /// Parse and validate a table in `dmsetup table` format. See
+    /// `parse_text()`.
+    fn from_str(s: &str) -> io::Result<Table> {
+        let targets = try!(parse_text(s));
+        Table::from_targets(&targets)
+    }
+}
+
+impl fmt::Display for Table {
+    /// Format the table in `dmsetup table` format.
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}", format_text(&self.targets))
+    }
+}
+
+impl From<Table> for Vec<TargetLine> {
+    fn from(table: Table) -> Vec<TargetLine> {
+        table.targets
+    }
+}
+
+fn check_target(length: u64, target_type: &str, params: &str) -> io::Result<()> {
+    if length == 0 {
+        return Err(Error::new(InvalidInput, format!("{} target has zero length", target_type)));
+    }
+
+    if target_type.is_empty() || target_type.len() > DM_MAX_TYPE_NAME {
+        return Err(Error::new(InvalidInput,
+                              format!("invalid target type \"{}\"", target_type)));
+    }
+
+    if params.contains('\n') || params.contains('\0') {
+        return Err(Error::new(InvalidInput,
+                              format!("{} target params contain a newline or null",
+                                      target_type)));
+    }
+
+    Ok(())
+}
+
+/// Check that `targets` form a table the kernel will accept: there is
+/// at least one target, every target has a non-zero length, targets
+/// are sorted and contiguous starting from sector 0, and any target
+/// type that must appear alone does.
+pub fn validate<T1, T2>(targets: &[(u64, u64, T1, T2)]) -> io::Result<()>
+    where T1: Borrow<str>,
+          T2: Borrow<str>
+{
+    if targets.is_empty() {
+        return Err(Error::new(InvalidInput, "table has no targets"));
+    }
+
+    let mut next = 0;
+    for t in targets {
+        let ttype = t.2.borrow();
+        try!(check_target(t.1, ttype, t.3.borrow()));
+
+        if t.0 < next {
+            return Err(Error::new(InvalidInput,
+                                  format!("{} target at sector {} overlaps or precedes the \
+                                           previous target, which ends at sector {}",
+                                          ttype,
+                                          t.0,
+                                          next)));
+        }
+
+        if t.0 > next {
+            return Err(Error::new(InvalidInput,
+                                  format!("gap in table between sectors {} and {}", next, t.0)));
+        }
+
+        if targets.len() > 1 && SINGLETON_TARGETS.contains(&ttype) {
+            return Err(Error::new(InvalidInput,
+                                  format!("{} target must be the only target in a table",
+                                          ttype)));
+        }
+
+        next = t.0 + t.1;
+    }
+
+    Ok(())
+}
+
+/// An error in table text, at a 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// The line the error is on.
+    pub line: usize,
+    /// The column the offending field starts at.
+    pub column: usize,
+    /// What was wrong.
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::new(InvalidData, err)
+    }
+}
+
+// Split off the next whitespace-separated field, returning it, its
+// byte offset in `line`, and the offset just past it.
+fn next_field(line: &str, from: usize) -> Option<(&str, usize, usize)> {
+    let rest = &line[from..];
+    let start = from + (rest.len() - rest.trim_start().len());
+    if start == line.len() {
+        return None;
+    }
+    let len = line[start..].find(char::is_whitespace).unwrap_or(line.len() - start);
+    Some((&line[start..start + len], start, start + len))
+}
+
+/// Parse table text in `dmsetup table` format: one target per line,
+/// as "<start> <length> <type> <params>". Blank lines and lines whose
+/// first non-blank character is '#' are ignored.
+///
+/// Only the syntax of each line is checked; use `Table::from_str()`
+/// or `validate()` to also check the layout.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::table::{format_text, parse_text};
+///
+/// let text = "# saved by backup.sh\n\
+///             0 2048 linear 8:16 0\n\
+///             2048 4096 linear 8:32 0\n";
+///
+/// let targets = parse_text(text).unwrap();
+/// assert_eq!(targets[1], (2048, 4096, "linear".to_owned(), "8:32 0".to_owned()));
+/// assert_eq!(format_text(&targets), "0 2048 linear 8:16 0\n2048 4096 linear 8:32 0\n");
+///
+/// let err = parse_text("0 2048 linear 8:16 0\n2048 x linear 8:32 0").unwrap_err();
+/// assert_eq!((err.line, err.column), (2, 6));
+/// ```
+pub fn parse_text(text: &str) -> Result<Vec<TargetLine>, ParseError> {
+    let mut targets = Vec::new();
+
+    for (idx, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let err = |off: usize, message: String| {
+            ParseError {
+                line: idx + 1,
+                column: line[..off].chars().count() + 1,
+                message: message,
+            }
+        };
+
+        let mut pos = 0;
+        let mut nums = Vec::new();
+        for what in &["start sector", "length"] {
+            let (field, off, end) = try!(next_field(line, pos)
+                .ok_or_else(|| err(line.len(), format!("missing {}", what))));
+            let num = try!(field.parse::<u64>()
+                .map_err(|_| err(off, format!("invalid {} \"{}\"", what, field))));
+            nums.push(num);
+            pos = end;
+        }
+
+        let (ttype, _, end) = try!(next_field(line, pos)
+            .ok_or_else(|| err(line.len(), "missing target type".to_owned())));
+        if ttype.len() > DM_MAX_TYPE_NAME {
+            return Err(err(end - ttype.len(), format!("target type \"{}\" too long", ttype)));
+        }
+
+        let params = line[end..].trim();
+
+        targets.push((nums[0], nums[1], ttype.to_owned(), params.to_owned()));
+    }
+
+    Ok(targets)
+}
+
+/// Format targets in `dmsetup table` format, one line per target.
+pub fn format_text<T1, T2>(targets: &[(u64, u64, T1, T2)]) -> String
+    where T1: Borrow<str>,
+          T2: Borrow<str>
+{
+    let mut text = String::new();
+    for t in targets {
+        text.push_str(&format!("{} {} {}", t.0, t.1, t.2.borrow()));
+        if !t.3.borrow().is_empty() {
+            text.push(' ');
+            text.push_str(t.3.borrow());
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(start: u64, length: u64, ttype: &str) -> (u64, u64, &str, &str) {
+        (start, length, ttype, "")
+    }
+
+    #[test]
+    fn validate_layout() {
+        assert!(validate(&[line(0, 10, "zero"), line(10, 20, "error")]).is_ok());
+
+        let empty: &[(u64, u64, &str, &str)] = &[];
+        assert!(validate(empty).is_err());
+
+        // Not starting at 0, a gap, and an overlap.
+        assert!(validate(&[line(5, 10, "zero")]).is_err());
+        assert!(validate(&[line(0, 10, "zero"), line(11, 10, "zero")]).is_err());
+        assert!(validate(&[line(0, 10, "zero"), line(9, 10, "zero")]).is_err());
+
+        // Out of order.
+        assert!(validate(&[line(10, 10, "zero"), line(0, 10, "zero")]).is_err());
+
+        assert!(validate(&[line(0, 0, "zero")]).is_err());
+        assert!(validate(&[line(0, 10, "")]).is_err());
+        assert!(validate(&[line(0, 10, "a-very-long-type")]).is_err());
+        assert!(validate(&[(0, 10, "linear", "8:16 0\n")]).is_err());
+    }
+
+    #[test]
+    fn singleton_targets() {
+        assert!(validate(&[line(0, 10, "thin-pool")]).is_ok());
+        assert!(validate(&[line(0, 10, "thin-pool"), line(10, 10, "zero")]).is_err());
+        assert!(validate(&[line(0, 10, "zero"), line(10, 10, "integrity")]).is_err());
+
+        let mut table = Table::new();
+        table.push(Sectors(10), "thin-pool", "").unwrap();
+        assert!(table.push(Sectors(10), "zero", "").is_err());
+
+        let mut table = Table::new();
+        table.push(Sectors(10), "zero", "").unwrap();
+        assert!(table.push(Sectors(10), "integrity", "").is_err());
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn push_builds_contiguous_table() {
+        let mut table = Table::new();
+        assert!(table.is_empty());
+        table.push(Sectors(10), "zero", "").unwrap();
+        table.push(Sectors(5), "error", "").unwrap();
+        assert!(table.push(Sectors(0), "zero", "").is_err());
+
+        assert_eq!(table.size(), Sectors(15));
+        assert_eq!(table.targets()[1], (10, 5, "error".to_owned(), String::new()));
+        assert_eq!(Table::from_targets(table.targets()).unwrap(), table);
+    }
+
+    #[test]
+    fn parse_and_format() {
+        let text = "\n  # comment\n0 10 zero\n10  20\tlinear   8:16 0  \n";
+        let targets = parse_text(text).unwrap();
+        assert_eq!(targets,
+                   vec![(0, 10, "zero".to_owned(), String::new()),
+                        (10, 20, "linear".to_owned(), "8:16 0".to_owned())]);
+        assert_eq!(format_text(&targets), "0 10 zero\n10 20 linear 8:16 0\n");
+        assert_eq!(parse_text(&format_text(&targets)).unwrap(), targets);
+
+        let table: Table = text.parse().unwrap();
+        assert_eq!(table.to_string(), format_text(&targets));
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let pos = |text: &str| {
+            let err = parse_text(text).unwrap_err();
+            (err.line, err.column)
+        };
+
+        assert_eq!(pos("x 10 zero"), (1, 1));
+        assert_eq!(pos("0 10 zero\n\n  0 -1 zero"), (3, 5));
+        assert_eq!(pos("0 10"), (1, 5));
+        assert_eq!(pos("# 0 10 zero\n0"), (2, 2));
+        assert_eq!(pos("0 10 a-very-long-type x"), (1, 6));
+
+        // Columns count characters, not bytes.
+        assert_eq!(pos("0 10 zero\n\u{e9}\u{e9} 10 zero"), (2, 1));
+        assert_eq!(pos("\u{a0}0 1\u{e9} zero"), (1, 4));
+
+        let err = parse_text("0 10 zero\n10 x zero").unwrap_err();
+        assert_eq!(err.to_string(), "line 2, column 4: invalid length \"x\"");
+    }
+
+    #[test]
+    fn parse_checks_only_syntax() {
+        let targets = parse_text("5 10 zero\n0 10 zero").unwrap();
+        assert_eq!(targets.len(), 2);
+        assert!(validate(&targets).is_err());
+        assert!("5 10 zero\n0 10 zero".parse::<Table>().is_err());
+    }
+}
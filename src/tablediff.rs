@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::borrow::Borrow;
+use std::io;
+
+use {DM, DevId, Device, TargetLine, DM_STATUS_TABLE};
+use types::Sectors;
+
+// For targets that take a "<#args> <arg>..." group of order-independent,
+// single-word feature args, the index of the count among the params.
+fn feature_args_index(target_type: &str) -> Option<usize> {
+    match target_type {
+        "thin-pool" => Some(4),
+        "cache" => Some(4),
+        "crypt" => Some(5),
+        _ => None,
+    }
+}
+
+fn normalize_device(token: &str) -> String {
+    if token.starts_with('/') {
+        if let Ok(dev) = token.parse::<Device>() {
+            return dev.dstr();
+        }
+    }
+    token.to_owned()
+}
+
+/// Normalize a target's params so that equivalent tables compare
+/// equal: block device paths are replaced by "<major>:<minor>", as the
+/// kernel reports them, and order-independent feature args of known
+/// target types are sorted.
+pub fn normalize_params(target_type: &str, params: &str) -> String {
+    let mut tokens: Vec<_> = params.split_whitespace().map(normalize_device).collect();
+
+    if let Some(idx) = feature_args_index(target_type) {
+        let count = tokens.get(idx).and_then(|c| c.parse::<usize>().ok());
+        if let Some(count) = count {
+            if idx + 1 + count <= tokens.len() {
+                tokens[idx + 1..idx + 1 + count].sort();
+            }
+        }
+    }
+
+    tokens.join(" ")
+}
+
+/// Normalize every target in a table. See `normalize_params()`.
+pub fn normalize<T1, T2>(targets: &[(u64, u64, T1, T2)]) -> Vec<TargetLine>
+    where T1: Borrow<str>,
+          T2: Borrow<str>
+{
+    targets.iter()
+        .map(|t| (t.0, t.1, t.2.borrow().to_owned(), normalize_params(t.2.borrow(), t.3.borrow())))
+        .collect()
+}
+
+/// How one target differs between two tables, compared by position.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetDiff {
+    /// The target is the same in both tables.
+    Unchanged(TargetLine),
+    /// The target's length changed but it maps the same way.
+    Resized {
+        /// The target in the old table.
+        old: TargetLine,
+        /// The target in the new table.
+        new: TargetLine,
+    },
+    /// The target maps differently.
+    Changed {
+        /// The target in the old table.
+        old: TargetLine,
+        /// The target in the new table.
+        new: TargetLine,
+    },
+    /// The target is only in the new table.
+    Added(TargetLine),
+    /// The target is only in the old table.
+    Removed(TargetLine),
+}
+
+/// What loading a new table over an old one would do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadPlan {
+    /// The tables are equivalent, there is nothing to do.
+    NoOp,
+    /// Sectors mapped by both tables map the same way; only the
+    /// device's size changes.
+    Resize {
+        /// The size of the old table.
+        old_size: Sectors,
+        /// The size of the new table.
+        new_size: Sectors,
+    },
+    /// Some sectors map differently.
+    Remap,
+}
+
+/// The differences between two normalized tables.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableDiff {
+    /// Per-target differences, in table order.
+    pub targets: Vec<TargetDiff>,
+    /// What a reload from the old table to the new would do.
+    pub plan: ReloadPlan,
+}
+
+impl TableDiff {
+    /// True if the tables are equivalent.
+    pub fn is_noop(&self) -> bool {
+        self.plan == ReloadPlan::NoOp
+    }
+}
+
+fn table_size(targets: &[TargetLine]) -> Sectors {
+    Sectors(targets.last().map_or(0, |t| t.0 + t.1))
+}
+
+/// Compare two tables, after normalizing them.
+///
+/// Targets are compared by position. A change to the length of the
+/// last target the tables have in common, or targets added or removed
+/// after it, only changes the device's size. So does shrinking the
+/// last common target while removing the targets after it. Any other
+/// difference changes the mapping.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::tablediff::{diff, ReloadPlan};
+/// use devicemapper::types::Sectors;
+///
+/// let old = [(0, 2048, "linear", "8:16 0")];
+/// let new = [(0, 2048, "linear", "8:16 0"), (2048, 1024, "linear", "8:32 0")];
+///
+/// assert_eq!(diff(&old, &old).plan, ReloadPlan::NoOp);
+/// assert_eq!(diff(&old, &new).plan,
+///            ReloadPlan::Resize { old_size: Sectors(2048), new_size: Sectors(3072) });
+/// assert_eq!(diff(&new, &old[..]).plan,
+///            ReloadPlan::Resize { old_size: Sectors(3072), new_size: Sectors(2048) });
+/// ```
+pub fn diff<T1, T2, T3, T4>(old: &[(u64, u64, T1, T2)], new: &[(u64, u64, T3, T4)]) -> TableDiff
+    where T1: Borrow<str>,
+          T2: Borrow<str>,
+          T3: Borrow<str>,
+          T4: Borrow<str>
+{
+    let old = normalize(old);
+    let new = normalize(new);
+    let common = ::std::cmp::min(old.len(), new.len());
+
+    let mut targets = Vec::new();
+    let mut remap = false;
+
+    for (i, (o, n)) in old.iter().zip(new.iter()).enumerate() {
+        let same_mapping = o.0 == n.0 && o.2 == n.2 && o.3 == n.3;
+
+        if same_mapping && o.1 == n.1 {
+            targets.push(TargetDiff::Unchanged(o.clone()));
+        } else if same_mapping {
+            // Only the last common target can change length without
+            // moving the targets after it. Growing it takes over
+            // sectors of any removed targets, or leaves added ones
+            // somewhere else, so only shrinking it may be combined
+            // with removing the targets after it.
+            let shrink_only = n.1 < o.1 && new.len() <= old.len();
+            remap |= i + 1 != common || (old.len() != new.len() && !shrink_only);
+            targets.push(TargetDiff::Resized {
+                old: o.clone(),
+                new: n.clone(),
+            });
+        } else {
+            remap = true;
+            targets.push(TargetDiff::Changed {
+                old: o.clone(),
+                new: n.clone(),
+            });
+        }
+    }
+
+    targets.extend(old[common..].iter().cloned().map(TargetDiff::Removed));
+    targets.extend(new[common..].iter().cloned().map(TargetDiff::Added));
+
+    let plan = if remap || (common == 0 && !(old.is_empty() && new.is_empty())) {
+        ReloadPlan::Remap
+    } else if targets.iter().all(|t| matches!(*t, TargetDiff::Unchanged(_))) {
+        ReloadPlan::NoOp
+    } else {
+        ReloadPlan::Resize {
+            old_size: table_size(&old),
+            new_size: table_size(&new),
+        }
+    };
+
+    TableDiff {
+        targets: targets,
+        plan: plan,
+    }
+}
+
+/// Compare device `name`'s active table with `new`, to decide whether
+/// loading `new` is needed, and whether it changes the mapping.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId};
+/// use devicemapper::tablediff::{self, ReloadPlan};
+///
+/// let dm = DM::new().unwrap();
+/// let id = DevId::Name("example-dev");
+/// let new = [(0, 32768, "linear", "/dev/sdb1 2048")];
+///
+/// if tablediff::plan_reload(&dm, &id, &new).unwrap().plan != ReloadPlan::NoOp {
+///     dm.table_load(&id, &new).unwrap();
+/// }
+/// ```
+pub fn plan_reload<T1, T2>(dm: &DM, name: &DevId, new: &[(u64, u64, T1, T2)]) -> io::Result<TableDiff>
+    where T1: Borrow<str>,
+          T2: Borrow<str>
+{
+    let (_, active) = try!(dm.table_status(name, DM_STATUS_TABLE));
+    Ok(diff(&active, new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plans() {
+        let old = [(0, 2048, "linear", "8:16 0")];
+        let new = [(0, 2048, "linear", "8:16 0"), (2048, 1024, "linear", "8:32 0")];
+
+        assert_eq!(diff(&old, &old).plan, ReloadPlan::NoOp);
+        assert_eq!(diff(&old, &new).plan,
+                   ReloadPlan::Resize {
+                       old_size: Sectors(2048),
+                       new_size: Sectors(3072),
+                   });
+        assert_eq!(diff(&new, &old).plan,
+                   ReloadPlan::Resize {
+                       old_size: Sectors(3072),
+                       new_size: Sectors(2048),
+                   });
+    }
+
+    #[test]
+    fn feature_args_in_any_order() {
+        let old = [(0, 2048, "thin-pool", "8:16 8:32 128 0 2 skip_block_zeroing no_discard_passdown")];
+        let new = [(0, 2048, "thin-pool", "8:16 8:32 128 0 2 no_discard_passdown skip_block_zeroing")];
+
+        assert!(diff(&old, &new).is_noop());
+    }
+
+    #[test]
+    fn resize_last_common_target() {
+        let old = [(0, 100, "linear", "8:16 0"), (100, 50, "linear", "8:32 0")];
+
+        let grown = [(0, 100, "linear", "8:16 0"), (100, 80, "linear", "8:32 0")];
+        assert_eq!(diff(&old, &grown).plan,
+                   ReloadPlan::Resize {
+                       old_size: Sectors(150),
+                       new_size: Sectors(180),
+                   });
+
+        let first_changed = [(0, 120, "linear", "8:16 0"), (120, 50, "linear", "8:32 0")];
+        assert_eq!(diff(&old, &first_changed).plan, ReloadPlan::Remap);
+    }
+
+    #[test]
+    fn grow_into_removed_target() {
+        // Sectors 100..150 move from the second target to the first.
+        let old = [(0, 100, "linear", "8:16 0"), (100, 50, "linear", "8:32 0")];
+        let new = [(0, 150, "linear", "8:16 0")];
+
+        assert_eq!(diff(&old, &new).plan, ReloadPlan::Remap);
+    }
+
+    #[test]
+    fn resize_with_added_target() {
+        let old = [(0, 100, "linear", "8:16 0")];
+        let new = [(0, 150, "linear", "8:16 0"), (150, 50, "linear", "8:32 0")];
+        assert_eq!(diff(&old, &new).plan, ReloadPlan::Remap);
+
+        let new = [(0, 80, "linear", "8:16 0"), (80, 50, "linear", "8:32 0")];
+        assert_eq!(diff(&old, &new).plan, ReloadPlan::Remap);
+    }
+
+    #[test]
+    fn shrink_dropping_later_targets() {
+        let old = [(0, 100, "linear", "8:16 0"), (100, 50, "linear", "8:32 0")];
+        let new = [(0, 80, "linear", "8:16 0")];
+
+        assert_eq!(diff(&old, &new).plan,
+                   ReloadPlan::Resize {
+                       old_size: Sectors(150),
+                       new_size: Sectors(80),
+                   });
+    }
+}
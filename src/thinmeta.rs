@@ -0,0 +1,536 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Reading and checking thin-pool metadata.
+//
+// A thin-pool's metadata device holds a superblock, a btree of
+// per-thin-device details, a two-level btree mapping each thin
+// device's virtual blocks to data blocks, and space maps tracking
+// reference counts for both metadata and data blocks.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput};
+use std::path::Path;
+
+use pdata::{btree_lookup, le32, le64, CheckReport, MetadataDev, MetadataError, Node, SpaceMap,
+            SpaceMapKind, SpaceMapRoot, Walker, read_node, validate_block, SPACE_MAP_ROOT_SIZE};
+use types::Sectors;
+
+const THIN_SUPERBLOCK_MAGIC: u64 = 27022010;
+const THIN_SUPERBLOCK_LOCATION: u64 = 0;
+const SUPERBLOCK_CSUM_XOR: u32 = 160774;
+const THIN_MIN_VERSION: u32 = 1;
+const THIN_MAX_VERSION: u32 = 2;
+
+const DEVICE_DETAILS_SIZE: usize = 24;
+const MAPPING_SIZE: usize = 8;
+
+/// The contents of a thin-pool metadata superblock.
+#[derive(Debug, Clone)]
+pub struct ThinSuperblock {
+    /// Superblock flags. Bit 0 is the "needs_check" flag.
+    pub flags: u32,
+    /// The block this superblock was read from.
+    pub blocknr: u64,
+    /// Metadata format version.
+    pub version: u32,
+    /// The current time, used to detect blocks shared with snapshots.
+    pub time: u32,
+    /// The pool's transaction id.
+    pub transaction_id: u64,
+    /// The block holding a reserved metadata snapshot, if any.
+    pub held_root: Option<u64>,
+    /// Root of the data space map.
+    pub data_sm_root: SpaceMapRoot,
+    /// Root of the metadata space map.
+    pub metadata_sm_root: SpaceMapRoot,
+    /// Root of the two-level btree mapping thin device blocks to data blocks.
+    pub data_mapping_root: u64,
+    /// Root of the btree of thin device details.
+    pub device_details_root: u64,
+    /// The size of a data block.
+    pub data_block_size: Sectors,
+    /// The number of metadata blocks.
+    pub metadata_nr_blocks: u64,
+}
+
+impl ThinSuperblock {
+    /// Read the primary superblock.
+    pub fn read(dev: &MetadataDev) -> Result<ThinSuperblock, MetadataError> {
+        Self::read_at(dev, THIN_SUPERBLOCK_LOCATION)
+    }
+
+    /// Read a superblock from block `b`. Reserved metadata snapshots
+    /// are copies of the superblock held at another location.
+    pub fn read_at(dev: &MetadataDev, b: u64) -> Result<ThinSuperblock, MetadataError> {
+        let block = try!(dev.read_block(b)
+            .map_err(|e| MetadataError::BadSuperblock(format!("read failed: {}", e))));
+
+        try!(validate_block(&block, b, SUPERBLOCK_CSUM_XOR)
+            .map_err(MetadataError::BadSuperblock));
+
+        let magic = le64(&block, 32);
+        if magic != THIN_SUPERBLOCK_MAGIC {
+            return Err(MetadataError::BadSuperblock(format!("bad magic {}", magic)));
+        }
+
+        let version = le32(&block, 40);
+        if !(THIN_MIN_VERSION..=THIN_MAX_VERSION).contains(&version) {
+            return Err(MetadataError::BadSuperblock(format!("unsupported version {}",
+                                                            version)));
+        }
+
+        let held_root = le64(&block, 56);
+        let sm_roots = 64;
+
+        Ok(ThinSuperblock {
+            flags: le32(&block, 4),
+            blocknr: b,
+            version: version,
+            time: le32(&block, 44),
+            transaction_id: le64(&block, 48),
+            held_root: if held_root == 0 { None } else { Some(held_root) },
+            data_sm_root: SpaceMapRoot::parse(&block[sm_roots..]),
+            metadata_sm_root: SpaceMapRoot::parse(&block[sm_roots + SPACE_MAP_ROOT_SIZE..]),
+            data_mapping_root: le64(&block, sm_roots + 2 * SPACE_MAP_ROOT_SIZE),
+            device_details_root: le64(&block, sm_roots + 2 * SPACE_MAP_ROOT_SIZE + 8),
+            data_block_size: Sectors(le32(&block, sm_roots + 2 * SPACE_MAP_ROOT_SIZE + 16) as u64),
+            metadata_nr_blocks: le64(&block, sm_roots + 2 * SPACE_MAP_ROOT_SIZE + 24),
+        })
+    }
+
+    /// Whether the pool has flagged its metadata as needing a check.
+    pub fn needs_check(&self) -> bool {
+        self.flags & 1 != 0
+    }
+}
+
+/// Per-device information kept in thin metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThinDeviceDetails {
+    /// The number of data blocks mapped by the device.
+    pub mapped_blocks: u64,
+    /// The pool transaction id when the device was last changed.
+    pub transaction_id: u64,
+    /// The pool time when the device was created.
+    pub creation_time: u32,
+    /// The pool time when the device was last snapshotted.
+    pub snapshotted_time: u32,
+}
+
+impl ThinDeviceDetails {
+    /// Parse device details from a btree value.
+    pub fn parse(buf: &[u8]) -> ThinDeviceDetails {
+        ThinDeviceDetails {
+            mapped_blocks: le64(buf, 0),
+            transaction_id: le64(buf, 8),
+            creation_time: le32(buf, 16),
+            snapshotted_time: le32(buf, 20),
+        }
+    }
+}
+
+/// A single entry in a thin device's mapping tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThinMapping {
+    /// The data block mapped to.
+    pub data_block: u64,
+    /// The pool time when the mapping was made.
+    pub time: u32,
+}
+
+impl ThinMapping {
+    /// Parse a mapping from a btree value.
+    pub fn parse(buf: &[u8]) -> ThinMapping {
+        let val = le64(buf, 0);
+        ThinMapping {
+            data_block: val >> 24,
+            time: (val & 0xff_ffff) as u32,
+        }
+    }
+}
+
+// State for a consistency check: references found so far, by walking
+// the trees reachable from the superblock(s).
+struct ThinChecker<'a> {
+    walker: Walker<'a>,
+    nr_data_blocks: u64,
+    data_refs: BTreeMap<u64, u32>,
+    mapped_counts: BTreeMap<u64, u64>,
+    subtree_counts: BTreeMap<u64, u64>,
+    details: BTreeMap<u64, ThinDeviceDetails>,
+}
+
+impl<'a> ThinChecker<'a> {
+    fn walk_superblock(&mut self, sb: &ThinSuperblock) {
+        let mut details = BTreeMap::new();
+        self.walker.walk(sb.device_details_root,
+                         DEVICE_DETAILS_SIZE,
+                         "device details tree",
+                         &mut |_, node: &Node| {
+            for (i, key) in node.keys.iter().enumerate() {
+                details.insert(*key, ThinDeviceDetails::parse(node.value(i)));
+            }
+        });
+
+        let mut roots = BTreeMap::new();
+        self.walker.walk(sb.data_mapping_root,
+                         MAPPING_SIZE,
+                         "mapping tree",
+                         &mut |_, node: &Node| {
+            for (i, key) in node.keys.iter().enumerate() {
+                roots.insert(*key, le64(node.value(i), 0));
+            }
+        });
+
+        for (dev_id, root) in roots {
+            let context = format!("mapping tree of thin device {}", dev_id);
+            let mapped = self.walk_mappings(root, &context);
+
+            // The held root's trees are walked second, keep the
+            // current device's counts.
+            self.mapped_counts.entry(dev_id).or_insert(mapped);
+            if !details.contains_key(&dev_id) {
+                self.walker.errors.push(MetadataError::BadBlock {
+                    block: sb.device_details_root,
+                    reason: format!("no details for thin device {}", dev_id),
+                });
+            }
+        }
+
+        for (dev_id, d) in details {
+            self.details.entry(dev_id).or_insert(d);
+        }
+    }
+
+    // Walk a thin device's mapping tree, returning the number of
+    // mappings in it. Snapshots share subtrees, so the mapping count
+    // of each node is remembered to be reused when it is reached again.
+    fn walk_mappings(&mut self, b: u64, context: &str) -> u64 {
+        if !self.walker.inc(b, context) {
+            return self.subtree_counts.get(&b).cloned().unwrap_or(0);
+        }
+
+        let node = match read_node(self.walker.dev(), b, MAPPING_SIZE) {
+            Ok(node) => node,
+            Err(e) => {
+                self.walker.errors.push(e);
+                return 0;
+            }
+        };
+
+        let count = if node.leaf {
+            for i in 0..node.keys.len() {
+                let m = ThinMapping::parse(node.value(i));
+                if m.data_block >= self.nr_data_blocks {
+                    self.walker.errors.push(MetadataError::OutOfBounds {
+                        context: context.to_owned(),
+                        block: m.data_block,
+                        nr_blocks: self.nr_data_blocks,
+                    });
+                    continue;
+                }
+                *self.data_refs.entry(m.data_block).or_insert(0) += 1;
+            }
+            node.keys.len() as u64
+        } else {
+            node.children()
+                .into_iter()
+                .map(|child| self.walk_mappings(child, context))
+                .sum()
+        };
+
+        self.subtree_counts.insert(b, count);
+        count
+    }
+}
+
+/// Check the consistency of thin-pool metadata in a device or image
+/// file.
+///
+/// Walks the device details and mapping btrees, validating every
+/// node's checksum and structure, and checks that every data mapping
+/// is within the data device. Then compares the references found
+/// against the counts kept in the metadata and data space maps.
+///
+/// The metadata should not be changing while it is checked: either
+/// the pool is inactive, or a metadata snapshot is being read.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use devicemapper::thinmeta;
+///
+/// let report = thinmeta::check(Path::new("/dev/vg/pool_tmeta")).unwrap();
+/// if !report.is_clean() {
+///     println!("{}", report);
+/// }
+/// ```
+pub fn check(path: &Path) -> io::Result<CheckReport> {
+    let dev = try!(MetadataDev::open(path));
+    Ok(check_dev(&dev))
+}
+
+/// Check the consistency of thin-pool metadata on an already opened
+/// device. See `check()`.
+pub fn check_dev(dev: &MetadataDev) -> CheckReport {
+    let sb = match ThinSuperblock::read(dev) {
+        Ok(sb) => sb,
+        Err(e) => return CheckReport { errors: vec![e] },
+    };
+
+    let nr_meta_blocks = sb.metadata_sm_root.nr_blocks;
+    let mut errors = Vec::new();
+    if nr_meta_blocks > dev.nr_blocks() {
+        errors.push(MetadataError::BadSuperblock(format!("metadata space map covers {} \
+                                                          blocks, device has {}",
+                                                         nr_meta_blocks,
+                                                         dev.nr_blocks())));
+    }
+
+    let mut chk = ThinChecker {
+        walker: Walker::new(dev, nr_meta_blocks),
+        nr_data_blocks: sb.data_sm_root.nr_blocks,
+        data_refs: BTreeMap::new(),
+        mapped_counts: BTreeMap::new(),
+        subtree_counts: BTreeMap::new(),
+        details: BTreeMap::new(),
+    };
+    chk.walker.errors = errors;
+
+    chk.walker.inc(sb.blocknr, "superblock");
+    chk.walk_superblock(&sb);
+
+    if let Some(held_root) = sb.held_root {
+        if chk.walker.inc(held_root, "held metadata root") {
+            match ThinSuperblock::read_at(dev, held_root) {
+                Ok(held) => chk.walk_superblock(&held),
+                Err(e) => chk.walker.errors.push(e),
+            }
+        }
+    }
+
+    let metadata_sm = SpaceMap::load(&mut chk.walker, &sb.metadata_sm_root, SpaceMapKind::Metadata);
+    let data_sm = SpaceMap::load(&mut chk.walker, &sb.data_sm_root, SpaceMapKind::Data);
+
+    let mut walker = chk.walker;
+    let mut errors = Vec::new();
+
+    for (dev_id, details) in &chk.details {
+        let mapped = chk.mapped_counts.get(dev_id).cloned().unwrap_or(0);
+        if mapped != details.mapped_blocks {
+            errors.push(MetadataError::Counter {
+                context: format!("mapped block count of thin device {}", dev_id),
+                expected: mapped,
+                actual: details.mapped_blocks,
+            });
+        }
+    }
+
+    metadata_sm.for_each_count(dev, &mut errors, |b, count| {
+        let expected = walker.ref_count(b);
+        if count != expected {
+            walker.errors.push(MetadataError::MetadataRefCount {
+                block: b,
+                expected: expected,
+                actual: count,
+            });
+        }
+    });
+
+    let data_refs = &chk.data_refs;
+    data_sm.for_each_count(dev, &mut errors, |b, count| {
+        let expected = data_refs.get(&b).cloned().unwrap_or(0);
+        if count != expected {
+            walker.errors.push(MetadataError::DataRefCount {
+                block: b,
+                expected: expected,
+                actual: count,
+            });
+        }
+    });
+
+    walker.errors.extend(errors);
+
+    CheckReport { errors: walker.errors }
+}
+
+/// The differences between the mappings of two thin devices in the
+/// same pool.
+///
+/// Each range is a (start, length) run of sectors on the thin
+/// devices, aligned to the pool's data block size.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ThinDelta {
+    /// Ranges mapped by both devices, to different data blocks.
+    pub different: Vec<(Sectors, Sectors)>,
+    /// Ranges only mapped by the left device.
+    pub left_only: Vec<(Sectors, Sectors)>,
+    /// Ranges only mapped by the right device.
+    pub right_only: Vec<(Sectors, Sectors)>,
+}
+
+impl ThinDelta {
+    /// True if the two devices map exactly the same data.
+    pub fn is_empty(&self) -> bool {
+        self.different.is_empty() && self.left_only.is_empty() && self.right_only.is_empty()
+    }
+}
+
+// The nodes of a mapping tree, as read from disk.
+#[derive(Default)]
+struct MappingTree {
+    internal: BTreeMap<u64, Vec<u64>>,
+    leaves: BTreeMap<u64, Vec<(u64, u64)>>,
+}
+
+impl MappingTree {
+    fn contains(&self, b: u64) -> bool {
+        self.internal.contains_key(&b) || self.leaves.contains_key(&b)
+    }
+
+    // Read the nodes reachable from `b`, stopping at any node also in
+    // `other`: a shared node maps the same blocks in both trees.
+    fn read(&mut self,
+            dev: &MetadataDev,
+            b: u64,
+            other: &MappingTree,
+            shared: &mut BTreeSet<u64>)
+            -> io::Result<()> {
+        if other.contains(b) {
+            shared.insert(b);
+            return Ok(());
+        }
+
+        if self.contains(b) {
+            return Err(Error::new(InvalidData, format!("mapping tree node {} reached twice", b)));
+        }
+
+        let node = try!(read_node(dev, b, MAPPING_SIZE)
+            .map_err(|e| Error::new(InvalidData, e.to_string())));
+
+        if node.leaf {
+            let entries = node.keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| (*key, ThinMapping::parse(node.value(i)).data_block))
+                .collect();
+            self.leaves.insert(b, entries);
+        } else {
+            let children = node.children();
+            self.internal.insert(b, children.clone());
+            for child in children {
+                try!(self.read(dev, child, other, shared));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Collect the mappings under `b`, skipping shared subtrees.
+    fn unshared(&self, b: u64, shared: &BTreeSet<u64>, out: &mut BTreeMap<u64, u64>) {
+        if shared.contains(&b) {
+            return;
+        }
+
+        if let Some(entries) = self.leaves.get(&b) {
+            out.extend(entries.iter().cloned());
+        } else if let Some(children) = self.internal.get(&b) {
+            for child in children {
+                self.unshared(*child, shared, out);
+            }
+        }
+    }
+}
+
+// Coalesce sorted block numbers into (start, length) sector runs.
+fn block_runs<I>(blocks: I, block_size: Sectors) -> Vec<(Sectors, Sectors)>
+    where I: Iterator<Item = u64>
+{
+    let mut runs: Vec<(u64, u64)> = Vec::new();
+    for b in blocks {
+        match runs.last_mut() {
+            Some(ref mut run) if run.0 + run.1 == b => {
+                run.1 += 1;
+                continue;
+            }
+            _ => {}
+        }
+        runs.push((b, 1));
+    }
+
+    runs.into_iter()
+        .map(|(start, len)| (block_size * start, block_size * len))
+        .collect()
+}
+
+/// Find the ranges that differ between thin devices `left` and
+/// `right`, using the trees rooted in superblock `sb`.
+///
+/// `left`'s whole mapping tree is read and kept in memory. Subtrees of
+/// `right`'s tree that are shared with it, as they are between a
+/// snapshot and its origin, are then not read again, and neither
+/// side's mappings in them are compared. So memory and I/O grow with
+/// the size of `left`, plus the part of `right` that differs from it;
+/// pass the smaller device as `left`.
+pub fn delta(dev: &MetadataDev, sb: &ThinSuperblock, left: u64, right: u64) -> io::Result<ThinDelta> {
+    let mut roots = Vec::new();
+    for dev_id in &[left, right] {
+        match try!(btree_lookup(dev, sb.data_mapping_root, MAPPING_SIZE, *dev_id)) {
+            Some(val) => roots.push(le64(&val, 0)),
+            None => {
+                return Err(Error::new(InvalidInput,
+                                      format!("thin device {} not found", dev_id)))
+            }
+        }
+    }
+
+    let mut left_tree = MappingTree::default();
+    let mut right_tree = MappingTree::default();
+    let mut shared = BTreeSet::new();
+
+    try!(left_tree.read(dev, roots[0], &MappingTree::default(), &mut shared));
+    try!(right_tree.read(dev, roots[1], &left_tree, &mut shared));
+
+    let mut left_maps = BTreeMap::new();
+    let mut right_maps = BTreeMap::new();
+    left_tree.unshared(roots[0], &shared, &mut left_maps);
+    right_tree.unshared(roots[1], &shared, &mut right_maps);
+
+    let different = left_maps.iter()
+        .filter(|&(vblock, dblock)| right_maps.get(vblock).is_some_and(|d| d != dblock))
+        .map(|(vblock, _)| *vblock);
+    let left_only = left_maps.keys().filter(|vblock| !right_maps.contains_key(vblock)).cloned();
+    let right_only = right_maps.keys().filter(|vblock| !left_maps.contains_key(vblock)).cloned();
+
+    Ok(ThinDelta {
+        different: block_runs(different, sb.data_block_size),
+        left_only: block_runs(left_only, sb.data_block_size),
+        right_only: block_runs(right_only, sb.data_block_size),
+    })
+}
+
+/// Find the ranges that differ between two thin devices, reading
+/// the metadata of an inactive pool from a device or image file. See
+/// `delta()`.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::path::Path;
+/// use devicemapper::thinmeta;
+///
+/// let delta = thinmeta::delta_path(Path::new("/tmp/tmeta.img"), 1, 2).unwrap();
+/// for (start, len) in delta.different {
+///     println!("changed: {} for {}", start, len);
+/// }
+/// ```
+pub fn delta_path(path: &Path, left: u64, right: u64) -> io::Result<ThinDelta> {
+    let dev = try!(MetadataDev::open(path));
+    let sb = try!(ThinSuperblock::read(&dev).map_err(|e| Error::new(InvalidData, e.to_string())));
+    delta(&dev, &sb, left, right)
+}
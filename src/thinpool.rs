@@ -0,0 +1,446 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{AlreadyExists, InvalidData, InvalidInput, Other};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use {DM, DevId, Device, DmFlags, DM_STATUS_TABLE, DM_SUSPEND};
+use pdata::MetadataDev;
+use thinmeta::{self, ThinDelta, ThinSuperblock};
+use types::{DataBlocks, Sectors};
+
+/// The mode a working thin-pool is operating in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThinPoolMode {
+    /// Reads and writes are allowed.
+    ReadWrite,
+    /// Metadata can no longer be changed; unprovisioned writes fail.
+    ReadOnly,
+    /// The data device is full; unprovisioned writes fail or queue.
+    OutOfDataSpace,
+}
+
+/// Status of a working thin-pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThinPoolWorkingStatus {
+    /// The pool's current transaction id.
+    pub transaction_id: u64,
+    /// Metadata blocks in use.
+    pub used_metadata_blocks: u64,
+    /// Total metadata blocks.
+    pub total_metadata_blocks: u64,
+    /// Data blocks in use.
+    pub used_data_blocks: u64,
+    /// Total data blocks.
+    pub total_data_blocks: u64,
+    /// The block holding a reserved metadata snapshot, if any.
+    pub held_metadata_root: Option<u64>,
+    /// The pool's operating mode.
+    pub mode: ThinPoolMode,
+    /// Whether discards are passed down to the data device.
+    pub discard_passdown: bool,
+    /// Whether writes fail immediately, rather than queueing, when
+    /// the pool is out of data space.
+    pub error_if_no_space: bool,
+    /// Whether the metadata has been flagged as needing a check.
+    pub needs_check: bool,
+    /// Free metadata blocks below which the pool raises an event.
+    /// Not reported by older kernels.
+    pub metadata_low_watermark: Option<u64>,
+}
+
+/// Status of a thin-pool, as reported by its target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ThinPoolStatus {
+    /// The pool is working.
+    Working(Box<ThinPoolWorkingStatus>),
+    /// The pool has failed; all I/O to it errors.
+    Fail,
+}
+
+fn parse_fraction(s: &str) -> io::Result<(u64, u64)> {
+    let spl: Vec<_> = s.split('/').collect();
+    if spl.len() != 2 {
+        return Err(Error::new(InvalidData, format!("expected used/total, got \"{}\"", s)));
+    }
+    let used = try!(parse_u64(spl[0]));
+    let total = try!(parse_u64(spl[1]));
+    Ok((used, total))
+}
+
+fn parse_u64(s: &str) -> io::Result<u64> {
+    s.parse::<u64>().map_err(|_| Error::new(InvalidData, format!("expected number, got \"{}\"", s)))
+}
+
+impl FromStr for ThinPoolStatus {
+    type Err = Error;
+
+    /// Parse the params field of a thin-pool target's status line.
+    fn from_str(s: &str) -> io::Result<ThinPoolStatus> {
+        let vals: Vec<_> = s.split_whitespace().collect();
+
+        match vals.first() {
+            Some(&"Fail") => return Ok(ThinPoolStatus::Fail),
+            Some(&"Error") => return Err(Error::new(Other, "thin-pool status unavailable")),
+            _ => {}
+        }
+
+        if vals.len() < 8 {
+            return Err(Error::new(InvalidData,
+                                  format!("too few fields in thin-pool status \"{}\"", s)));
+        }
+
+        let (used_meta, total_meta) = try!(parse_fraction(vals[1]));
+        let (used_data, total_data) = try!(parse_fraction(vals[2]));
+
+        let held_metadata_root = match vals[3] {
+            "-" => None,
+            x => Some(try!(parse_u64(x))),
+        };
+
+        let mode = match vals[4] {
+            "rw" => ThinPoolMode::ReadWrite,
+            "ro" => ThinPoolMode::ReadOnly,
+            "out_of_data_space" => ThinPoolMode::OutOfDataSpace,
+            x => return Err(Error::new(InvalidData, format!("unknown thin-pool mode \"{}\"", x))),
+        };
+
+        Ok(ThinPoolStatus::Working(Box::new(ThinPoolWorkingStatus {
+            transaction_id: try!(parse_u64(vals[0])),
+            used_metadata_blocks: used_meta,
+            total_metadata_blocks: total_meta,
+            used_data_blocks: used_data,
+            total_data_blocks: total_data,
+            held_metadata_root: held_metadata_root,
+            mode: mode,
+            discard_passdown: vals[5] == "discard_passdown",
+            error_if_no_space: vals[6] == "error_if_no_space",
+            needs_check: vals[7] == "needs_check",
+            metadata_low_watermark: match vals.get(8) {
+                Some(x) => Some(try!(parse_u64(x))),
+                None => None,
+            },
+        })))
+    }
+}
+
+/// Get the status of the thin-pool device `pool`.
+pub fn pool_status(dm: &DM, pool: &DevId) -> io::Result<ThinPoolStatus> {
+    let (_, status) = try!(dm.table_status(pool, DmFlags::empty()));
+    match status.first() {
+        Some((_, _, ttype, params)) if ttype == "thin-pool" => params.parse(),
+        _ => Err(Error::new(InvalidInput, "device is not a thin-pool")),
+    }
+}
+
+/// Get the path of the metadata device of thin-pool `pool`, from its
+/// active table.
+pub fn pool_metadata_path(dm: &DM, pool: &DevId) -> io::Result<PathBuf> {
+    let (_, table) = try!(dm.table_status(pool, DM_STATUS_TABLE));
+    let params = match table.first() {
+        Some((_, _, ttype, params)) if ttype == "thin-pool" => params.clone(),
+        _ => return Err(Error::new(InvalidInput, "device is not a thin-pool")),
+    };
+
+    let meta_dev = match params.split_whitespace().next() {
+        Some(dev) => try!(dev.parse::<Device>()),
+        None => return Err(Error::new(InvalidData, "empty thin-pool table")),
+    };
+
+    meta_dev.path().ok_or_else(|| {
+        Error::new(Other,
+                   format!("no device node for metadata device {}", meta_dev.dstr()))
+    })
+}
+
+/// A reserved metadata snapshot of a live thin-pool.
+///
+/// While held, the snapshot's metadata blocks cannot be reused by the
+/// pool, so the trees under `held_root()` can be read from the
+/// metadata device while the pool is in use. The snapshot is released
+/// when the guard is dropped.
+///
+/// Only one snapshot can be held per pool, and a held snapshot keeps
+/// metadata blocks allocated, so guards should be short-lived.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId};
+/// use devicemapper::thinpool::MetadataSnap;
+///
+/// let dm = DM::new().unwrap();
+/// let pool = DevId::Name("pool");
+/// let snap = MetadataSnap::reserve(&dm, &pool).unwrap();
+/// println!("metadata snapshot at block {}", snap.held_root());
+/// ```
+pub struct MetadataSnap<'a> {
+    dm: &'a DM,
+    pool: &'a DevId<'a>,
+    held_root: u64,
+    released: bool,
+}
+
+impl<'a> MetadataSnap<'a> {
+    /// Reserve a metadata snapshot of thin-pool `pool`.
+    ///
+    /// Fails with `AlreadyExists` if the pool already holds a
+    /// snapshot, which may have been leaked by an earlier user.
+    pub fn reserve(dm: &'a DM, pool: &'a DevId<'a>) -> io::Result<MetadataSnap<'a>> {
+        if let Some(root) = try!(held_root(dm, pool)) {
+            return Err(Error::new(AlreadyExists,
+                                  format!("metadata snapshot already held at block {}", root)));
+        }
+
+        try!(dm.target_msg(pool, 0, "reserve_metadata_snap"));
+
+        // From here on the snapshot must be released on failure, let
+        // Drop handle it.
+        let mut snap = MetadataSnap {
+            dm: dm,
+            pool: pool,
+            held_root: 0,
+            released: false,
+        };
+
+        snap.held_root = match try!(held_root(dm, pool)) {
+            Some(root) => root,
+            None => return Err(Error::new(Other, "no metadata snapshot held after reserving one")),
+        };
+
+        Ok(snap)
+    }
+
+    /// The metadata block holding the snapshot's copy of the
+    /// superblock.
+    pub fn held_root(&self) -> u64 {
+        self.held_root
+    }
+
+    /// Read the snapshot's copy of the superblock from the pool's
+    /// metadata device.
+    pub fn superblock(&self, dev: &MetadataDev) -> io::Result<ThinSuperblock> {
+        ThinSuperblock::read_at(dev, self.held_root)
+            .map_err(|e| Error::new(InvalidData, e.to_string()))
+    }
+
+    /// Release the snapshot, reporting any error. Dropping the guard
+    /// also releases it, but ignores errors.
+    pub fn release(mut self) -> io::Result<()> {
+        self.released = true;
+        try!(self.dm.target_msg(self.pool, 0, "release_metadata_snap"));
+        Ok(())
+    }
+}
+
+impl<'a> Drop for MetadataSnap<'a> {
+    fn drop(&mut self) {
+        if !self.released {
+            let _ = self.dm.target_msg(self.pool, 0, "release_metadata_snap");
+        }
+    }
+}
+
+/// The metadata block of the snapshot held by thin-pool `pool`, if any.
+pub fn held_root(dm: &DM, pool: &DevId) -> io::Result<Option<u64>> {
+    match try!(pool_status(dm, pool)) {
+        ThinPoolStatus::Working(status) => Ok(status.held_metadata_root),
+        ThinPoolStatus::Fail => Err(Error::new(Other, "thin-pool has failed")),
+    }
+}
+
+/// Find the ranges that differ between thin devices `left` and
+/// `right` of the live thin-pool `pool`.
+///
+/// A metadata snapshot is reserved so that a consistent copy of the
+/// metadata can be read while the pool is in use, and released again
+/// afterwards. Changes made after the snapshot is taken are not
+/// included, so callers replicating a device should snapshot it first
+/// and compare the snapshots.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId};
+/// use devicemapper::thinpool;
+///
+/// let dm = DM::new().unwrap();
+/// let delta = thinpool::delta(&dm, &DevId::Name("pool"), 1, 2).unwrap();
+/// println!("{:?}", delta.different);
+/// ```
+pub fn delta(dm: &DM, pool: &DevId, left: u64, right: u64) -> io::Result<ThinDelta> {
+    let path = try!(pool_metadata_path(dm, pool));
+    let dev = try!(MetadataDev::open(&path));
+
+    let snap = try!(MetadataSnap::reserve(dm, pool));
+    let sb = try!(snap.superblock(&dev));
+    let delta = try!(thinmeta::delta(&dev, &sb, left, right));
+    try!(snap.release());
+
+    Ok(delta)
+}
+
+/// Get the data block size of thin-pool `pool`, from its active table.
+pub fn pool_block_size(dm: &DM, pool: &DevId) -> io::Result<Sectors> {
+    let (_, table) = try!(dm.table_status(pool, DM_STATUS_TABLE));
+    let params = match table.first() {
+        Some((_, _, ttype, params)) if ttype == "thin-pool" => params.clone(),
+        _ => return Err(Error::new(InvalidInput, "device is not a thin-pool")),
+    };
+
+    match params.split_whitespace().nth(2) {
+        Some(x) => Ok(Sectors(try!(parse_u64(x)))),
+        None => Err(Error::new(InvalidData, "thin-pool table has no block size")),
+    }
+}
+
+/// Resize thin-pool `pool` to use `data_size` sectors of its data
+/// device, which must already have been grown to at least that size.
+///
+/// The pool's table is reloaded with the new length, rounded down to
+/// a whole number of data blocks, and the pool is suspended and
+/// resumed to make it live.
+pub fn resize_data(dm: &DM, pool: &DevId, data_size: Sectors) -> io::Result<()> {
+    let block_size = try!(pool_block_size(dm, pool));
+    let (_, table) = try!(dm.table_status(pool, DM_STATUS_TABLE));
+
+    let params = match table.first() {
+        Some((_, _, _, params)) => params.clone(),
+        None => return Err(Error::new(InvalidInput, "thin-pool has no table")),
+    };
+
+    let length = data_size / *block_size * *block_size;
+    try!(dm.table_load(pool, &[(0, *length, "thin-pool", params)]));
+    try!(dm.device_suspend(pool, DM_SUSPEND));
+    try!(dm.device_suspend(pool, DmFlags::empty()));
+
+    Ok(())
+}
+
+/// Wait for events on thin-pool `pool`, calling `handler` with the
+/// pool's status after each one.
+///
+/// `handler` is also called once before waiting, so a pool that
+/// crossed its low water mark before monitoring began is not
+/// missed. Monitoring stops when `handler` returns false or an error.
+///
+/// The thin-pool target raises an event when free data space falls
+/// below its low water mark, when it changes mode, and when the pool
+/// is resized.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId};
+/// use devicemapper::thinpool::{self, ThinPoolStatus};
+///
+/// let dm = DM::new().unwrap();
+/// thinpool::monitor(&dm, &DevId::Name("pool"), |status| {
+///     if let ThinPoolStatus::Working(ref s) = *status {
+///         println!("{}/{} data blocks used", s.used_data_blocks, s.total_data_blocks);
+///     }
+///     Ok(true)
+/// }).unwrap();
+/// ```
+pub fn monitor<F>(dm: &DM, pool: &DevId, mut handler: F) -> io::Result<()>
+    where F: FnMut(&ThinPoolStatus) -> io::Result<bool>
+{
+    // Read the event number before the status, so that an event
+    // raised in between causes the wait to return at once.
+    let mut event_nr = try!(dm.device_status(pool)).event_nr();
+    let mut status = try!(pool_status(dm, pool));
+
+    while try!(handler(&status)) {
+        let (info, lines) = try!(dm.device_wait_event(pool, event_nr, DmFlags::empty()));
+        event_nr = info.event_nr();
+
+        status = match lines.first() {
+            Some((_, _, ttype, params)) if ttype == "thin-pool" => try!(params.parse()),
+            _ => return Err(Error::new(InvalidInput, "device is not a thin-pool")),
+        };
+    }
+
+    Ok(())
+}
+
+/// A policy for growing a thin-pool's data device as it fills: when
+/// `threshold_percent` of data blocks are in use, grow by
+/// `extend_percent` of the current size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtendPolicy {
+    /// Data usage, as a percentage, at which to extend.
+    pub threshold_percent: u64,
+    /// How much to extend by, as a percentage of the current size.
+    pub extend_percent: u64,
+}
+
+impl ExtendPolicy {
+    /// The number of data blocks to add for a pool with `status`, if
+    /// it has crossed the threshold. Always at least one block.
+    pub fn blocks_to_add(&self, status: &ThinPoolWorkingStatus) -> Option<DataBlocks> {
+        if status.total_data_blocks == 0 ||
+           status.used_data_blocks * 100 < self.threshold_percent * status.total_data_blocks {
+            return None;
+        }
+
+        let add = (status.total_data_blocks * self.extend_percent).div_ceil(100);
+        Some(DataBlocks(::std::cmp::max(add, 1)))
+    }
+
+    /// Apply the policy to thin-pool `pool` with `status`.
+    ///
+    /// If the pool needs extending, `grow_data` is called with the
+    /// new size its data device must be grown to, and then the pool
+    /// is reloaded to use it. Returns the new data size, if the pool
+    /// was extended.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use devicemapper::{DM, DevId};
+    /// use devicemapper::thinpool::{self, ExtendPolicy};
+    ///
+    /// let dm = DM::new().unwrap();
+    /// let pool = DevId::Name("pool");
+    /// let policy = ExtendPolicy { threshold_percent: 80, extend_percent: 20 };
+    ///
+    /// thinpool::monitor(&dm, &pool, |status| {
+    ///     try!(policy.apply(&dm, &pool, status, |size| {
+    ///         // grow the data device to `size`, e.g. by reloading
+    ///         // its linear table with another segment
+    ///         Ok(())
+    ///     }));
+    ///     Ok(true)
+    /// }).unwrap();
+    /// ```
+    pub fn apply<G>(&self,
+                    dm: &DM,
+                    pool: &DevId,
+                    status: &ThinPoolStatus,
+                    mut grow_data: G)
+                    -> io::Result<Option<Sectors>>
+        where G: FnMut(Sectors) -> io::Result<()>
+    {
+        let status = match *status {
+            ThinPoolStatus::Working(ref s) => s,
+            ThinPoolStatus::Fail => return Ok(None),
+        };
+
+        let add = match self.blocks_to_add(status) {
+            Some(add) => add,
+            None => return Ok(None),
+        };
+
+        let block_size = try!(pool_block_size(dm, pool));
+        let new_size = block_size * (status.total_data_blocks + *add);
+
+        try!(grow_data(new_size));
+        try!(resize_data(dm, pool, new_size));
+
+        Ok(Some(new_size))
+    }
+}
@@ -0,0 +1,277 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{Error, Write};
+use std::io::ErrorKind::Other;
+
+use serde;
+use serde::ser::{SerializeSeq, SerializeStruct};
+use serde_json;
+
+use {DM, DevId, Device, DmFlags};
+use {DM_ACTIVE_PRESENT, DM_DEFERRED_REMOVE, DM_INACTIVE_PRESENT, DM_INTERNAL_SUSPEND,
+     DM_READONLY, DM_SUSPEND};
+use depgraph::DepGraph;
+use types::Sectors;
+
+// The flags that describe a device's state, as reported by the kernel.
+const STATE_FLAGS: &[(DmFlags, &str)] = &[(DM_READONLY, "readonly"),
+                                          (DM_SUSPEND, "suspended"),
+                                          (DM_ACTIVE_PRESENT, "active_present"),
+                                          (DM_INACTIVE_PRESENT, "inactive_present"),
+                                          (DM_DEFERRED_REMOVE, "deferred_remove"),
+                                          (DM_INTERNAL_SUSPEND, "internal_suspend")];
+
+/// The names of the state flags set in `flags`, e.g. "suspended".
+pub fn flag_names(flags: DmFlags) -> Vec<&'static str> {
+    STATE_FLAGS.iter().filter(|f| flags.contains(f.0)).map(|f| f.1).collect()
+}
+
+/// One target of a device's active table. Params are left out, as they
+/// may contain keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetInfo {
+    /// The target's first sector.
+    pub start: Sectors,
+    /// The target's length.
+    pub length: Sectors,
+    /// The target type, e.g. "linear".
+    pub target_type: String,
+}
+
+/// A DM device and what it maps onto.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceNode {
+    /// The device's name.
+    pub name: String,
+    /// The device's UUID, empty if it has none.
+    pub uuid: String,
+    /// The device's number.
+    pub device: Device,
+    /// The device's state flags.
+    pub flags: DmFlags,
+    /// How many times the device is open.
+    pub open_count: i32,
+    /// The targets of the device's active table.
+    pub targets: Vec<TargetInfo>,
+    /// The devices the active table depends on.
+    pub deps: Vec<Device>,
+}
+
+/// A snapshot of every DM device, its table, and its dependencies.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::io;
+/// use devicemapper::DM;
+/// use devicemapper::topology::Topology;
+///
+/// let dm = DM::new().unwrap();
+/// let topo = Topology::snapshot(&dm).unwrap();
+///
+/// topo.write_dot(&mut io::stdout()).unwrap();
+/// println!("{}", topo.to_json().unwrap());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Topology {
+    /// The DM devices, ordered by device number.
+    pub devices: Vec<DeviceNode>,
+}
+
+impl Topology {
+    /// Read the state of every DM device.
+    ///
+    /// A device removed while the snapshot is taken is left out.
+    pub fn snapshot(dm: &DM) -> io::Result<Topology> {
+        let mut devices = Vec::new();
+
+        for (name, dev) in try!(dm.list_devices()) {
+            match DeviceNode::read(dm, &name, dev) {
+                Ok(node) => devices.push(node),
+                Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        devices.sort_by_key(|n| n.device);
+        Ok(Topology { devices: devices })
+    }
+
+    /// The dependency graph of the devices.
+    pub fn graph(&self) -> DepGraph {
+        let mut graph = DepGraph::new();
+        for node in &self.devices {
+            graph.add_device(&node.name, node.device, &node.deps);
+        }
+        graph
+    }
+
+    /// The edges between devices, from each device to a device its
+    /// table depends on.
+    pub fn edges(&self) -> Vec<(Device, Device)> {
+        self.devices
+            .iter()
+            .flat_map(|n| n.deps.iter().map(move |d| (n.device, *d)))
+            .collect()
+    }
+
+    /// Write the topology as a Graphviz DOT digraph. DM devices are
+    /// ellipses labeled with their name, number, and targets; the
+    /// devices they are built on are boxes.
+    pub fn write_dot<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        try!(writeln!(w, "digraph devicemapper {{"));
+        try!(writeln!(w, "    rankdir=TB;"));
+
+        let mut dm_devs = BTreeSet::new();
+        for node in &self.devices {
+            dm_devs.insert(node.device);
+
+            let mut label = format!("{}\\n{}", dot_escape(&node.name), node.device.dstr());
+            if !node.uuid.is_empty() {
+                label.push_str(&format!("\\n{}", dot_escape(&node.uuid)));
+            }
+            for t in &node.targets {
+                label.push_str(&format!("\\n{} {} {}", *t.start, *t.length, t.target_type));
+            }
+            let flags = flag_names(node.flags);
+            if !flags.is_empty() {
+                label.push_str(&format!("\\n[{}]", flags.join(", ")));
+            }
+            label.push_str(&format!("\\nopen count {}", node.open_count));
+
+            let style = if node.flags.contains(DM_SUSPEND) {
+                ", style=dashed"
+            } else {
+                ""
+            };
+            try!(writeln!(w,
+                          "    \"{}\" [shape=ellipse, label=\"{}\"{}];",
+                          node.device.dstr(),
+                          label,
+                          style));
+        }
+
+        let leaves: BTreeSet<_> =
+            self.edges().into_iter().map(|e| e.1).filter(|d| !dm_devs.contains(d)).collect();
+        for dev in leaves {
+            try!(writeln!(w, "    \"{}\" [shape=box];", dev.dstr()));
+        }
+
+        for (from, to) in self.edges() {
+            try!(writeln!(w, "    \"{}\" -> \"{}\";", from.dstr(), to.dstr()));
+        }
+
+        writeln!(w, "}}")
+    }
+
+    /// The topology as a Graphviz DOT digraph. See `write_dot()`.
+    pub fn to_dot(&self) -> String {
+        let mut buf = Vec::new();
+        self.write_dot(&mut buf).expect("writing to a Vec cannot fail");
+        String::from_utf8(buf).expect("DOT output is built from strings")
+    }
+
+    /// Write the topology as JSON: an object with "devices", each with
+    /// "name", "uuid", "device" ("major:minor"), "flags", "open_count",
+    /// "targets", and "deps", and "edges", each with "from" and "to".
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        serde_json::to_writer_pretty(w, self).map_err(|e| Error::new(Other, e))
+    }
+
+    /// The topology as JSON. See `write_json()`.
+    pub fn to_json(&self) -> io::Result<String> {
+        serde_json::to_string_pretty(self).map_err(|e| Error::new(Other, e))
+    }
+}
+
+impl DeviceNode {
+    fn read(dm: &DM, name: &str, dev: Device) -> io::Result<DeviceNode> {
+        let (info, status) = try!(dm.table_status(&DevId::Name(name), DmFlags::empty()));
+        let deps = try!(dm.table_deps(dev, DmFlags::empty()));
+
+        Ok(DeviceNode {
+            name: name.to_owned(),
+            uuid: info.uuid().to_owned(),
+            device: dev,
+            flags: info.flags(),
+            open_count: info.open_count(),
+            targets: status.into_iter()
+                .map(|t| {
+                    TargetInfo {
+                        start: Sectors(t.0),
+                        length: Sectors(t.1),
+                        target_type: t.2,
+                    }
+                })
+                .collect(),
+            deps: deps,
+        })
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl serde::Serialize for TargetInfo {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("TargetInfo", 3));
+        try!(state.serialize_field("start", &self.start));
+        try!(state.serialize_field("length", &self.length));
+        try!(state.serialize_field("type", &self.target_type));
+        state.end()
+    }
+}
+
+impl serde::Serialize for DeviceNode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let deps: Vec<_> = self.deps.iter().map(|d| d.dstr()).collect();
+
+        let mut state = try!(serializer.serialize_struct("DeviceNode", 7));
+        try!(state.serialize_field("name", &self.name));
+        try!(state.serialize_field("uuid", &self.uuid));
+        try!(state.serialize_field("device", &self.device.dstr()));
+        try!(state.serialize_field("flags", &flag_names(self.flags)));
+        try!(state.serialize_field("open_count", &self.open_count));
+        try!(state.serialize_field("targets", &self.targets));
+        try!(state.serialize_field("deps", &deps));
+        state.end()
+    }
+}
+
+struct Edges<'a>(&'a Topology);
+
+impl<'a> serde::Serialize for Edges<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let edges = self.0.edges();
+        let mut seq = try!(serializer.serialize_seq(Some(edges.len())));
+        for (from, to) in edges {
+            let mut map = ::std::collections::BTreeMap::new();
+            map.insert("from", from.dstr());
+            map.insert("to", to.dstr());
+            try!(seq.serialize_element(&map));
+        }
+        seq.end()
+    }
+}
+
+impl serde::Serialize for Topology {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where S: serde::Serializer
+    {
+        let mut state = try!(serializer.serialize_struct("Topology", 2));
+        try!(state.serialize_field("devices", &self.devices));
+        try!(state.serialize_field("edges", &Edges(self)));
+        state.end()
+    }
+}
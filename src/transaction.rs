@@ -0,0 +1,526 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::borrow::Borrow;
+use std::collections::BTreeSet;
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::InvalidData;
+
+use {DevId, Device, DmFlags, TargetLine};
+use {DM_INACTIVE_PRESENT, DM_QUERY_INACTIVE_TABLE, DM_READONLY, DM_STATUS_TABLE, DM_SUSPEND};
+use backend::Backend;
+
+/// A single recorded step of a `Transaction`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    /// Create a device. See `DM::device_create()`.
+    Create {
+        /// The device's name.
+        name: String,
+        /// The device's UUID, if any.
+        uuid: Option<String>,
+        /// Flags for the create.
+        flags: DmFlags,
+    },
+    /// Load a table into a device's inactive slot. See `DM::table_load()`.
+    Load {
+        /// The device's name.
+        name: String,
+        /// The table to load.
+        targets: Vec<TargetLine>,
+    },
+    /// Suspend a device. See `DM::device_suspend()`.
+    Suspend {
+        /// The device's name.
+        name: String,
+        /// Flags for the suspend, e.g. DM_NOFLUSH. DM_SUSPEND is implied.
+        flags: DmFlags,
+    },
+    /// Resume a device, activating its inactive table if it has one.
+    Resume {
+        /// The device's name.
+        name: String,
+    },
+    /// Rename a device. See `DM::device_rename()`.
+    Rename {
+        /// The device's current name.
+        old: String,
+        /// The device's new name.
+        new: String,
+    },
+    /// Remove a device. See `DM::device_remove()`.
+    Remove {
+        /// The device's name.
+        name: String,
+    },
+}
+
+impl Op {
+    /// The name of the device the step acts on, before the step.
+    pub fn name(&self) -> &str {
+        match *self {
+            Op::Create { ref name, .. } |
+            Op::Load { ref name, .. } |
+            Op::Suspend { ref name, .. } |
+            Op::Resume { ref name } |
+            Op::Remove { ref name } => name,
+            Op::Rename { ref old, .. } => old,
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Op::Create { ref name, .. } => write!(f, "create {}", name),
+            Op::Load { ref name, .. } => write!(f, "load {}", name),
+            Op::Suspend { ref name, .. } => write!(f, "suspend {}", name),
+            Op::Resume { ref name } => write!(f, "resume {}", name),
+            Op::Rename { ref old, ref new } => write!(f, "rename {} to {}", old, new),
+            Op::Remove { ref name } => write!(f, "remove {}", name),
+        }
+    }
+}
+
+// What to do to reverse a completed step.
+enum Undo {
+    Nothing,
+    Remove(String),
+    Clear(String),
+    Load(String, Vec<TargetLine>),
+    Resume(String),
+    Suspend(String),
+    Unswap(String, Vec<TargetLine>, DmFlags),
+    Rename(String, String),
+    Recreate {
+        name: String,
+        uuid: Option<String>,
+        flags: DmFlags,
+        targets: Vec<TargetLine>,
+    },
+}
+
+/// The error from a failed `Transaction::commit()`.
+#[derive(Debug)]
+pub struct TransactionError {
+    /// The step that failed.
+    pub op: Op,
+    /// Why it failed.
+    pub error: Error,
+    /// The completed steps that could not be undone, with why, in the
+    /// order undoing them was attempted. If empty, the rollback
+    /// succeeded.
+    pub rollback_errors: Vec<(Op, Error)>,
+}
+
+impl TransactionError {
+    /// True if every completed step was undone.
+    pub fn rolled_back(&self) -> bool {
+        self.rollback_errors.is_empty()
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "{} failed: {}", self.op, self.error));
+        if self.rolled_back() {
+            write!(f, "; rolled back")
+        } else {
+            try!(write!(f, "; rollback incomplete"));
+            for (op, err) in &self.rollback_errors {
+                try!(write!(f, "; undo {} failed: {}", op, err));
+            }
+            Ok(())
+        }
+    }
+}
+
+impl error::Error for TransactionError {
+    fn description(&self) -> &str {
+        "devicemapper transaction failed"
+    }
+}
+
+impl From<TransactionError> for Error {
+    fn from(err: TransactionError) -> Error {
+        Error::new(err.error.kind(), err)
+    }
+}
+
+/// A set of changes to several devices, applied together.
+///
+/// Steps are recorded with the builder methods and nothing is done
+/// until `commit()`. Steps run in the order they were recorded, except
+/// that each run of consecutive suspends is reordered so that a device
+/// is suspended before the devices it depends on (top-down), and each
+/// run of consecutive resumes so that a device is resumed after the
+/// devices it depends on (bottom-up).
+///
+/// If a step fails, the steps already completed are undone in reverse
+/// order. A removed device is recreated with its name, UUID, and
+/// table, but may get a different device number.
+///
+/// A transaction can be committed to a `DM` or to any other
+/// `backend::Backend`.
+///
+/// Tables should refer to DM devices by "<major>:<minor>". A device's
+/// `/dev/mapper` node is made by udev some time after the device is
+/// created, so a table naming it there may fail to load if the device
+/// was created in the same transaction. Its number is only known once
+/// it exists, so create such devices in an earlier transaction.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::{DM, DevId, DmFlags};
+/// use devicemapper::transaction::Transaction;
+///
+/// let dm = DM::new().unwrap();
+///
+/// let mut create = Transaction::new();
+/// create.create("example-lv-real", None, DmFlags::empty())
+///     .load("example-lv-real", &[(0, 32768, "linear", "/dev/sdb1 2048")])
+///     .resume("example-lv-real");
+/// create.commit(&dm).unwrap();
+///
+/// // Stack "example-lv" on the new device.
+/// let real = dm.device_status(&DevId::Name("example-lv-real")).unwrap().device();
+/// let mut trans = Transaction::new();
+/// trans.load("example-lv", &[(0, 32768, "linear", format!("{} 0", real.dstr()))])
+///     .suspend("example-lv", DmFlags::empty())
+///     .resume("example-lv");
+///
+/// trans.commit(&dm).unwrap();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transaction {
+    ops: Vec<Op>,
+}
+
+impl Transaction {
+    /// Create an empty transaction.
+    pub fn new() -> Transaction {
+        Transaction { ops: Vec::new() }
+    }
+
+    /// Record creating a device.
+    pub fn create(&mut self, name: &str, uuid: Option<&str>, flags: DmFlags) -> &mut Transaction {
+        self.ops.push(Op::Create {
+            name: name.to_owned(),
+            uuid: uuid.map(|u| u.to_owned()),
+            flags: flags,
+        });
+        self
+    }
+
+    /// Record loading a table into a device's inactive slot.
+    pub fn load<T1, T2>(&mut self, name: &str, targets: &[(u64, u64, T1, T2)]) -> &mut Transaction
+        where T1: Borrow<str>,
+              T2: Borrow<str>
+    {
+        self.ops.push(Op::Load {
+            name: name.to_owned(),
+            targets: targets.iter()
+                .map(|t| (t.0, t.1, t.2.borrow().to_owned(), t.3.borrow().to_owned()))
+                .collect(),
+        });
+        self
+    }
+
+    /// Record suspending a device.
+    pub fn suspend(&mut self, name: &str, flags: DmFlags) -> &mut Transaction {
+        self.ops.push(Op::Suspend {
+            name: name.to_owned(),
+            flags: flags,
+        });
+        self
+    }
+
+    /// Record resuming a device.
+    pub fn resume(&mut self, name: &str) -> &mut Transaction {
+        self.ops.push(Op::Resume { name: name.to_owned() });
+        self
+    }
+
+    /// Record renaming a device.
+    pub fn rename(&mut self, old: &str, new: &str) -> &mut Transaction {
+        self.ops.push(Op::Rename {
+            old: old.to_owned(),
+            new: new.to_owned(),
+        });
+        self
+    }
+
+    /// Record removing a device.
+    pub fn remove(&mut self, name: &str) -> &mut Transaction {
+        self.ops.push(Op::Remove { name: name.to_owned() });
+        self
+    }
+
+    /// The recorded steps, in the order they were recorded.
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Apply the recorded steps, undoing the completed ones if any
+    /// step fails.
+    pub fn commit<B: Backend>(&self, dm: &B) -> Result<(), TransactionError> {
+        let mut done: Vec<(Op, Undo)> = Vec::new();
+
+        let mut i = 0;
+        while i < self.ops.len() {
+            // Find the run of like steps that start here.
+            let run_len = match self.ops[i] {
+                Op::Suspend { .. } | Op::Resume { .. } => {
+                    let same = |op: &Op| {
+                        matches!((op, &self.ops[i]),
+                                 (&Op::Suspend { .. }, &Op::Suspend { .. }) |
+                                 (&Op::Resume { .. }, &Op::Resume { .. }))
+                    };
+                    self.ops[i..].iter().take_while(|op| same(op)).count()
+                }
+                _ => 1,
+            };
+            let run = &self.ops[i..i + run_len];
+            i += run_len;
+
+            let ordered = if run_len > 1 {
+                match order_run(dm, run) {
+                    Ok(ordered) => ordered,
+                    Err(err) => return Err(rollback(dm, run[0].clone(), err, done)),
+                }
+            } else {
+                run.to_vec()
+            };
+
+            for op in ordered {
+                match apply(dm, &op) {
+                    Ok(undo) => done.push((op, undo)),
+                    Err(err) => return Err(rollback(dm, op, err, done)),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn status_flags<B: Backend>(dm: &B, name: &str) -> io::Result<DmFlags> {
+    Ok(try!(dm.device_status(&DevId::Name(name))).flags())
+}
+
+fn table<B: Backend>(dm: &B, name: &str, flags: DmFlags) -> io::Result<Vec<TargetLine>> {
+    Ok(try!(dm.table_status(&DevId::Name(name), DM_STATUS_TABLE | flags)).1)
+}
+
+// Resume a device without activating its inactive table, which is
+// kept loaded.
+fn resume_keeping_inactive<B: Backend>(dm: &B, name: &str) -> io::Result<()> {
+    let id = DevId::Name(name);
+    if try!(status_flags(dm, name)).contains(DM_INACTIVE_PRESENT) {
+        let inactive = try!(table(dm, name, DM_QUERY_INACTIVE_TABLE));
+        try!(dm.table_clear(&id));
+        try!(dm.device_suspend(&id, DmFlags::empty()));
+        try!(dm.table_load(&id, &inactive));
+    } else {
+        try!(dm.device_suspend(&id, DmFlags::empty()));
+    }
+    Ok(())
+}
+
+// Do one step, returning how to undo it.
+fn apply<B: Backend>(dm: &B, op: &Op) -> io::Result<Undo> {
+    match *op {
+        Op::Create { ref name, ref uuid, flags } => {
+            try!(dm.device_create(name, uuid.as_ref().map(|u| &u[..]), flags));
+            Ok(Undo::Remove(name.clone()))
+        }
+        Op::Load { ref name, ref targets } => {
+            let undo = if try!(status_flags(dm, name)).contains(DM_INACTIVE_PRESENT) {
+                Undo::Load(name.clone(), try!(table(dm, name, DM_QUERY_INACTIVE_TABLE)))
+            } else {
+                Undo::Clear(name.clone())
+            };
+            try!(dm.table_load(&DevId::Name(name), targets));
+            Ok(undo)
+        }
+        Op::Suspend { ref name, flags } => {
+            let was_suspended = try!(status_flags(dm, name)).contains(DM_SUSPEND);
+            try!(dm.device_suspend(&DevId::Name(name), DM_SUSPEND | flags));
+            Ok(if was_suspended {
+                Undo::Nothing
+            } else {
+                Undo::Resume(name.clone())
+            })
+        }
+        Op::Resume { ref name } => {
+            let flags = try!(status_flags(dm, name));
+            let undo = if flags.contains(DM_INACTIVE_PRESENT) {
+                // The active table is replaced; keep it to put back.
+                Undo::Unswap(name.clone(), try!(table(dm, name, DmFlags::empty())), flags)
+            } else if flags.contains(DM_SUSPEND) {
+                Undo::Suspend(name.clone())
+            } else {
+                // Resuming a running device with no inactive table does
+                // nothing.
+                Undo::Nothing
+            };
+            try!(dm.device_suspend(&DevId::Name(name), DmFlags::empty()));
+            Ok(undo)
+        }
+        Op::Rename { ref old, ref new } => {
+            try!(dm.device_rename(old, new, DmFlags::empty()));
+            Ok(Undo::Rename(new.clone(), old.clone()))
+        }
+        Op::Remove { ref name } => {
+            let id = DevId::Name(name);
+            let (info, targets) = try!(dm.table_status(&id, DM_STATUS_TABLE));
+            let uuid = if info.uuid().is_empty() {
+                None
+            } else {
+                Some(info.uuid().to_owned())
+            };
+            let flags = info.flags() & DM_READONLY;
+            try!(dm.device_remove(&id, DmFlags::empty()));
+            Ok(Undo::Recreate {
+                name: name.clone(),
+                uuid: uuid,
+                flags: flags,
+                targets: targets,
+            })
+        }
+    }
+}
+
+fn undo<B: Backend>(dm: &B, undo: Undo) -> io::Result<()> {
+    match undo {
+        Undo::Nothing => {}
+        Undo::Remove(name) => {
+            try!(dm.device_remove(&DevId::Name(&name), DmFlags::empty()));
+        }
+        Undo::Clear(name) => {
+            try!(dm.table_clear(&DevId::Name(&name)));
+        }
+        Undo::Load(name, targets) => {
+            try!(dm.table_load(&DevId::Name(&name), &targets));
+        }
+        Undo::Resume(name) => try!(resume_keeping_inactive(dm, &name)),
+        Undo::Suspend(name) => {
+            try!(dm.device_suspend(&DevId::Name(&name), DM_SUSPEND));
+        }
+        Undo::Unswap(name, targets, flags) => {
+            // Swap the old table back in, then return the device to
+            // the suspend state it had before the resume. The table
+            // that had been activated is not reloaded; undoing the
+            // load that put it there clears it anyway.
+            let id = DevId::Name(&name);
+            try!(dm.device_suspend(&id, DM_SUSPEND));
+            try!(dm.table_load(&id, &targets));
+            try!(dm.device_suspend(&id, DmFlags::empty()));
+            if flags.contains(DM_SUSPEND) {
+                try!(dm.device_suspend(&id, DM_SUSPEND));
+            }
+        }
+        Undo::Rename(from, to) => {
+            try!(dm.device_rename(&from, &to, DmFlags::empty()));
+        }
+        Undo::Recreate { name, uuid, flags, targets } => {
+            let id = DevId::Name(&name);
+            try!(dm.device_create(&name, uuid.as_ref().map(|u| &u[..]), flags));
+            if !targets.is_empty() {
+                try!(dm.table_load(&id, &targets));
+                try!(dm.device_suspend(&id, DmFlags::empty()));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn rollback<B: Backend>(dm: &B, op: Op, error: Error, done: Vec<(Op, Undo)>) -> TransactionError {
+    let mut rollback_errors = Vec::new();
+    for (done_op, u) in done.into_iter().rev() {
+        if let Err(err) = undo(dm, u) {
+            rollback_errors.push((done_op, err));
+        }
+    }
+
+    TransactionError {
+        op: op,
+        error: error,
+        rollback_errors: rollback_errors,
+    }
+}
+
+// All devices `dev` depends on, directly or through other DM
+// devices. The first level uses the inactive table if `inactive`.
+fn lower_devices<B: Backend>(dm: &B, dev: Device, inactive: bool) -> io::Result<BTreeSet<Device>> {
+    let mut found = BTreeSet::new();
+    let flags = if inactive {
+        DM_QUERY_INACTIVE_TABLE
+    } else {
+        DmFlags::empty()
+    };
+    let mut todo = try!(dm.table_deps(dev, flags));
+
+    while let Some(d) = todo.pop() {
+        if found.insert(d) {
+            // Non-DM devices have no table.
+            match dm.table_deps(d, DmFlags::empty()) {
+                Ok(deps) => todo.extend(deps),
+                Err(ref err) if err.raw_os_error() == Some(::libc::ENXIO) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+// Order a run of suspends top-down, or a run of resumes bottom-up, by
+// the devices' tables at this point in the transaction. Devices with
+// no dependency between them keep their recorded order.
+fn order_run<B: Backend>(dm: &B, run: &[Op]) -> io::Result<Vec<Op>> {
+    let resuming = matches!(run[0], Op::Resume { .. });
+
+    let mut nodes = Vec::new();
+    for op in run {
+        let info = try!(dm.device_status(&DevId::Name(op.name())));
+        // A resume activates the inactive table, so order by it.
+        let inactive = resuming && info.flags().contains(DM_INACTIVE_PRESENT);
+        let lower = try!(lower_devices(dm, info.device(), inactive));
+        nodes.push((op.clone(), info.device(), lower));
+    }
+
+    let mut ordered = Vec::new();
+    while !nodes.is_empty() {
+        let next = (0..nodes.len()).find(|&i| {
+            nodes.iter().enumerate().all(|(j, other)| {
+                i == j ||
+                if resuming {
+                    !nodes[i].2.contains(&other.1)
+                } else {
+                    !other.2.contains(&nodes[i].1)
+                }
+            })
+        });
+
+        match next {
+            Some(i) => ordered.push(nodes.remove(i).0),
+            None => {
+                return Err(Error::new(InvalidData,
+                                      format!("dependency cycle among devices: {}",
+                                              nodes.iter()
+                                                  .map(|n| n.0.name())
+                                                  .collect::<Vec<_>>()
+                                                  .join(", "))))
+            }
+        }
+    }
+
+    Ok(ordered)
+}
+
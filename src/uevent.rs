@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+use std::io::Error;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::str;
+
+use libc;
+
+use {DM, DevId, Device};
+
+const UEVENT_BUFFER_SIZE: usize = 16384;
+
+// The prefix and magic of messages rebroadcast by udev.
+const UDEV_PREFIX: &[u8] = b"libudev\0";
+const UDEV_MAGIC: u32 = 0xfeedcafe;
+
+/// Which uevents to listen to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UeventSource {
+    /// Events as sent by the kernel. These carry the device number and
+    /// any DM_COOKIE, but not the device's name or UUID.
+    Kernel,
+    /// Events as rebroadcast by udev once its rules have run. These
+    /// also carry DM_NAME and DM_UUID.
+    Udev,
+}
+
+impl UeventSource {
+    fn group(&self) -> u32 {
+        match *self {
+            UeventSource::Kernel => 1,
+            UeventSource::Udev => 2,
+        }
+    }
+}
+
+/// What happened to a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UeventAction {
+    /// The device was created.
+    Add,
+    /// The device changed, e.g. a table was resumed.
+    Change,
+    /// The device was removed.
+    Remove,
+    /// Any other action.
+    Other(String),
+}
+
+impl From<&str> for UeventAction {
+    fn from(s: &str) -> UeventAction {
+        match s {
+            "add" => UeventAction::Add,
+            "change" => UeventAction::Change,
+            "remove" => UeventAction::Remove,
+            _ => UeventAction::Other(s.to_owned()),
+        }
+    }
+}
+
+/// A uevent for a DM device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DmUevent {
+    /// What happened.
+    pub action: UeventAction,
+    /// The device's number.
+    pub device: Device,
+    /// The device's name, if known.
+    pub name: Option<String>,
+    /// The device's UUID, if known.
+    pub uuid: Option<String>,
+    /// The udev cookie of the ioctl that caused the event, if any.
+    pub cookie: Option<u32>,
+    /// The kernel's uevent sequence number.
+    pub seqnum: Option<u64>,
+    /// The device's sysfs path, e.g. "/devices/virtual/block/dm-0".
+    pub devpath: String,
+    /// Every variable in the event.
+    pub env: BTreeMap<String, String>,
+}
+
+impl DmUevent {
+    /// Parse a uevent message, either from the kernel or rebroadcast by
+    /// udev. Returns None if the message is not about a DM device.
+    ///
+    /// Devices with a minor number that does not fit in a `Device` are
+    /// also skipped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use devicemapper::uevent::{DmUevent, UeventAction};
+    ///
+    /// let msg = b"change@/devices/virtual/block/dm-3\0ACTION=change\0\
+    ///             DEVPATH=/devices/virtual/block/dm-3\0SUBSYSTEM=block\0\
+    ///             DM_COOKIE=6330996\0SEQNUM=4105\0MAJOR=253\0MINOR=3\0\
+    ///             DEVNAME=dm-3\0DEVTYPE=disk\0";
+    ///
+    /// let event = DmUevent::parse(msg).unwrap();
+    /// assert_eq!(event.action, UeventAction::Change);
+    /// assert_eq!(event.device.dstr(), "253:3");
+    /// assert_eq!(event.cookie_base(), Some(0x9a74));
+    /// assert_eq!(event.cookie_flags(), Some(0x60));
+    ///
+    /// assert!(DmUevent::parse(b"add@/devices/virtual/block/loop0\0SUBSYSTEM=block\0\
+    ///                           MAJOR=7\0MINOR=0\0DEVNAME=loop0\0").is_none());
+    /// ```
+    pub fn parse(msg: &[u8]) -> Option<DmUevent> {
+        let env = match udev_properties(msg) {
+            Some(props) => fields(props),
+            None => {
+                // The kernel's "<action>@<devpath>" header is repeated
+                // in ACTION and DEVPATH.
+                let mut spl = msg.splitn(2, |b| *b == 0);
+                match spl.next() {
+                    Some(hdr) if hdr.contains(&b'@') => {}
+                    _ => return None,
+                }
+                fields(spl.next().unwrap_or(&[]))
+            }
+        };
+
+        if env.get("SUBSYSTEM").map(|s| s.as_str()) != Some("block") {
+            return None;
+        }
+        let is_dm = env.contains_key("DM_NAME") ||
+                    env.get("DEVNAME").map(|n| n.trim_start_matches("/dev/").starts_with("dm-")) == Some(true);
+        if !is_dm {
+            return None;
+        }
+
+        let major = env.get("MAJOR").and_then(|m| m.parse::<u32>().ok());
+        let minor = env.get("MINOR").and_then(|m| m.parse::<u8>().ok());
+        let device = match (major, minor) {
+            (Some(major), Some(minor)) => {
+                Device {
+                    major: major,
+                    minor: minor,
+                }
+            }
+            _ => return None,
+        };
+
+        let non_empty = |key: &str| env.get(key).and_then(|v| if v.is_empty() { None } else { Some(v.clone()) });
+
+        Some(DmUevent {
+            action: env.get("ACTION").map_or(UeventAction::Other(String::new()), |a| a.as_str().into()),
+            device: device,
+            name: non_empty("DM_NAME"),
+            uuid: non_empty("DM_UUID"),
+            cookie: env.get("DM_COOKIE").and_then(|c| c.parse().ok()),
+            seqnum: env.get("SEQNUM").and_then(|s| s.parse().ok()),
+            devpath: env.get("DEVPATH").cloned().unwrap_or_default(),
+            env: env,
+        })
+    }
+
+    /// The low 16 bits of the cookie, which identify the operation
+    /// that is waiting for udev.
+    pub fn cookie_base(&self) -> Option<u16> {
+        self.cookie.map(|c| (c & 0xffff) as u16)
+    }
+
+    /// The high 16 bits of the cookie, the DM_UDEV_* flags telling
+    /// udev rules how to handle the event.
+    pub fn cookie_flags(&self) -> Option<u16> {
+        self.cookie.map(|c| (c >> 16) as u16)
+    }
+
+    /// An id for the device, by UUID if it has one, else by name.
+    pub fn dev_id(&self) -> Option<DevId<'_>> {
+        match (&self.uuid, &self.name) {
+            (Some(uuid), _) => Some(DevId::Uuid(uuid)),
+            (None, Some(name)) => Some(DevId::Name(name)),
+            (None, None) => None,
+        }
+    }
+
+    /// Fill in the name and UUID, if missing, by looking the device up
+    /// by its number. Does nothing if the device no longer exists, as
+    /// after a remove.
+    pub fn resolve(&mut self, dm: &DM) -> io::Result<()> {
+        if self.name.is_some() && self.uuid.is_some() {
+            return Ok(());
+        }
+
+        let name = match try!(dm.list_devices()).into_iter().find(|&(_, dev)| dev == self.device) {
+            Some((name, _)) => name,
+            None => return Ok(()),
+        };
+        let info = match dm.device_status(&DevId::Name(&name)) {
+            Ok(info) => info,
+            Err(ref err) if err.raw_os_error() == Some(libc::ENXIO) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        if !info.uuid().is_empty() && self.uuid.is_none() {
+            self.uuid = Some(info.uuid().to_owned());
+        }
+        self.name = Some(name);
+        Ok(())
+    }
+}
+
+// The properties of a message rebroadcast by udev, or None if the
+// message is not from udev.
+fn udev_properties(msg: &[u8]) -> Option<&[u8]> {
+    if !msg.starts_with(UDEV_PREFIX) || msg.len() < 24 {
+        return None;
+    }
+    let word = |at: usize| {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(&msg[at..at + 4]);
+        b
+    };
+    if u32::from_be_bytes(word(8)) != UDEV_MAGIC {
+        return None;
+    }
+    let off = u32::from_ne_bytes(word(16)) as usize;
+    let len = u32::from_ne_bytes(word(20)) as usize;
+    msg.get(off..off.saturating_add(len))
+}
+
+// Split NUL-separated KEY=VALUE fields.
+fn fields(buf: &[u8]) -> BTreeMap<String, String> {
+    buf.split(|b| *b == 0)
+        .filter_map(|f| str::from_utf8(f).ok())
+        .filter_map(|f| {
+            let mut kv = f.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if !k.is_empty() => Some((k.to_owned(), v.to_owned())),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Listens for uevents about DM devices on a netlink socket.
+///
+/// A listener is also an endless iterator of events.
+///
+/// # Example
+///
+/// ```no_run
+/// use devicemapper::DM;
+/// use devicemapper::uevent::{UeventListener, UeventSource};
+///
+/// let dm = DM::new().unwrap();
+/// let listener = UeventListener::new(UeventSource::Kernel).unwrap();
+///
+/// for event in listener {
+///     let mut event = event.unwrap();
+///     event.resolve(&dm).unwrap();
+///     println!("{:?} {} {:?}", event.action, event.device.dstr(), event.name);
+/// }
+/// ```
+pub struct UeventListener {
+    sock: File,
+    source: UeventSource,
+    buf: Vec<u8>,
+}
+
+impl UeventListener {
+    /// Listen for events from `source`.
+    pub fn new(source: UeventSource) -> io::Result<UeventListener> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK,
+                         libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                         libc::NETLINK_KOBJECT_UEVENT)
+        };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+        let sock = unsafe { File::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = source.group();
+        let ret = unsafe {
+            libc::bind(fd,
+                       &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                       mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(UeventListener {
+            sock: sock,
+            source: source,
+            buf: vec![0; UEVENT_BUFFER_SIZE],
+        })
+    }
+
+    /// Wait for the next event about a DM device, skipping any others.
+    pub fn recv(&mut self) -> io::Result<DmUevent> {
+        loop {
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            let len = unsafe {
+                libc::recvfrom(self.sock.as_raw_fd(),
+                               self.buf.as_mut_ptr() as *mut libc::c_void,
+                               self.buf.len(),
+                               0,
+                               &mut addr as *mut libc::sockaddr_nl as *mut libc::sockaddr,
+                               &mut addr_len)
+            };
+            if len < 0 {
+                let err = Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+
+            // Only the kernel sends from port 0; anyone else claiming
+            // to be the kernel is ignored.
+            let from_kernel = addr.nl_pid == 0;
+            if from_kernel != (self.source == UeventSource::Kernel) {
+                continue;
+            }
+
+            if let Some(event) = DmUevent::parse(&self.buf[..len as usize]) {
+                return Ok(event);
+            }
+        }
+    }
+}
+
+impl AsRawFd for UeventListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+impl Iterator for UeventListener {
+    type Item = io::Result<DmUevent>;
+
+    fn next(&mut self) -> Option<io::Result<DmUevent>> {
+        Some(self.recv())
+    }
+}
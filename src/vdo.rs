@@ -0,0 +1,560 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Error;
+use std::io::ErrorKind::{InvalidData, InvalidInput, NotFound, Other};
+use std::str::FromStr;
+
+use {DM, DevId, Device, DmFlags, TargetLine};
+use types::Sectors;
+
+/// The oldest vdo target version that takes V4 table lines. Table
+/// lines are only made for, and status and messages only used with, a
+/// target at least this version.
+pub const MIN_VERSION: (u32, u32, u32) = (8, 2, 0);
+
+/// The size of a VDO block, in sectors.
+pub const VDO_BLOCK_SECTORS: Sectors = Sectors(8);
+
+/// Check that the kernel has a vdo target that takes the table lines
+/// `VdoTarget` makes, returning its version.
+pub fn check_version(dm: &DM) -> io::Result<(u32, u32, u32)> {
+    let version = match try!(dm.list_versions()).into_iter().find(|t| t.0 == "vdo") {
+        Some((_, major, minor, patch)) => (major, minor, patch),
+        None => return Err(Error::new(NotFound, "the kernel has no vdo target")),
+    };
+    try!(check_supported(version));
+    Ok(version)
+}
+
+fn check_supported(version: (u32, u32, u32)) -> io::Result<()> {
+    if version < MIN_VERSION {
+        return Err(Error::new(Other,
+                              format!("vdo target version {}.{}.{} is older than {}.{}.{}",
+                                      version.0,
+                                      version.1,
+                                      version.2,
+                                      MIN_VERSION.0,
+                                      MIN_VERSION.1,
+                                      MIN_VERSION.2)));
+    }
+    Ok(())
+}
+
+/// A dm-vdo target, deduplicating and compressing data onto a storage
+/// device. Numbers of threads left unset use the target's defaults.
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::Device;
+/// use devicemapper::types::Sectors;
+/// use devicemapper::vdo::VdoTarget;
+///
+/// let mut vdo = VdoTarget::new(Device { major: 8, minor: 16 }, Sectors(1 << 24)).unwrap();
+/// vdo.compression(true).threads(1, 1, 1);
+///
+/// // The kernel's target version, as returned by `vdo::check_version()`.
+/// let version = (8, 2, 0);
+///
+/// assert_eq!(vdo.params(version).unwrap(),
+///            "V4 8:16 2097152 4096 32768 16380 \
+///             hash 1 logical 1 physical 1 deduplication on compression on");
+/// assert!(vdo.params((6, 2, 3)).is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VdoTarget {
+    storage: Device,
+    storage_size: Sectors,
+    minimum_io_size: u32,
+    block_map_cache_blocks: u64,
+    block_map_era_length: u32,
+    max_discard_blocks: Option<u32>,
+    ack_threads: Option<u32>,
+    bio_threads: Option<u32>,
+    cpu_threads: Option<u32>,
+    hash_threads: Option<u32>,
+    logical_threads: Option<u32>,
+    physical_threads: Option<u32>,
+    deduplication: bool,
+    compression: bool,
+}
+
+impl VdoTarget {
+    /// A vdo target on `storage`, of which `storage_size` is used. The
+    /// size must be a whole number of 4KiB blocks, and the device must
+    /// already be formatted as a VDO volume.
+    pub fn new(storage: Device, storage_size: Sectors) -> io::Result<VdoTarget> {
+        if *storage_size == 0 || !(*storage_size).is_multiple_of(*VDO_BLOCK_SECTORS) {
+            return Err(Error::new(InvalidInput,
+                                  format!("vdo storage size {} is not a whole number of \
+                                           4KiB blocks",
+                                          *storage_size)));
+        }
+
+        Ok(VdoTarget {
+            storage: storage,
+            storage_size: storage_size,
+            minimum_io_size: 4096,
+            block_map_cache_blocks: 32768,
+            block_map_era_length: 16380,
+            max_discard_blocks: None,
+            ack_threads: None,
+            bio_threads: None,
+            cpu_threads: None,
+            hash_threads: None,
+            logical_threads: None,
+            physical_threads: None,
+            deduplication: true,
+            compression: false,
+        })
+    }
+
+    /// The smallest I/O the target accepts, in bytes: 512 or 4096,
+    /// the default.
+    pub fn minimum_io_size(&mut self, bytes: u32) -> &mut VdoTarget {
+        self.minimum_io_size = bytes;
+        self
+    }
+
+    /// The block map cache size, in 4KiB blocks. 32768 by default.
+    pub fn block_map_cache_blocks(&mut self, blocks: u64) -> &mut VdoTarget {
+        self.block_map_cache_blocks = blocks;
+        self
+    }
+
+    /// How many block map updates can pass before a dirty block map
+    /// page is written. 16380 by default.
+    pub fn block_map_era_length(&mut self, length: u32) -> &mut VdoTarget {
+        self.block_map_era_length = length;
+        self
+    }
+
+    /// The largest discard the target accepts, in 4KiB blocks
+    /// (`maxDiscard`).
+    pub fn max_discard_blocks(&mut self, blocks: u32) -> &mut VdoTarget {
+        self.max_discard_blocks = Some(blocks);
+        self
+    }
+
+    /// The number of threads completing bios (`ack`).
+    pub fn ack_threads(&mut self, threads: u32) -> &mut VdoTarget {
+        self.ack_threads = Some(threads);
+        self
+    }
+
+    /// The number of threads submitting bios to the storage (`bio`).
+    pub fn bio_threads(&mut self, threads: u32) -> &mut VdoTarget {
+        self.bio_threads = Some(threads);
+        self
+    }
+
+    /// The number of threads hashing and compressing (`cpu`).
+    pub fn cpu_threads(&mut self, threads: u32) -> &mut VdoTarget {
+        self.cpu_threads = Some(threads);
+        self
+    }
+
+    /// The numbers of `hash` zone, `logical` zone and `physical` zone
+    /// threads. These must be all zero, or all non-zero.
+    pub fn threads(&mut self, hash: u32, logical: u32, physical: u32) -> &mut VdoTarget {
+        self.hash_threads = Some(hash);
+        self.logical_threads = Some(logical);
+        self.physical_threads = Some(physical);
+        self
+    }
+
+    /// Whether to deduplicate. On by default.
+    pub fn deduplication(&mut self, enabled: bool) -> &mut VdoTarget {
+        self.deduplication = enabled;
+        self
+    }
+
+    /// Whether to compress. Off by default.
+    pub fn compression(&mut self, enabled: bool) -> &mut VdoTarget {
+        self.compression = enabled;
+        self
+    }
+
+    fn check(&self) -> io::Result<()> {
+        fn bad(msg: String) -> io::Result<()> {
+            Err(Error::new(InvalidInput, msg))
+        }
+
+        if self.minimum_io_size != 512 && self.minimum_io_size != 4096 {
+            return bad(format!("vdo minimum I/O size {} is not 512 or 4096", self.minimum_io_size));
+        }
+        if self.block_map_cache_blocks < 128 {
+            return bad("vdo block map cache must be at least 128 blocks".to_owned());
+        }
+        if self.block_map_era_length < 1 || self.block_map_era_length > 16380 {
+            return bad(format!("vdo block map era length {} is not between 1 and 16380",
+                               self.block_map_era_length));
+        }
+        if self.max_discard_blocks == Some(0) {
+            return bad("vdo maxDiscard cannot be zero".to_owned());
+        }
+
+        for &(name, threads, min, max) in &[("ack", self.ack_threads, 0, 100),
+                                           ("bio", self.bio_threads, 1, 100),
+                                           ("cpu", self.cpu_threads, 1, 100),
+                                           ("hash", self.hash_threads, 0, 100),
+                                           ("logical", self.logical_threads, 0, 60),
+                                           ("physical", self.physical_threads, 0, 16)] {
+            match threads {
+                Some(n) if n < min || n > max => {
+                    return bad(format!("vdo {} threads {} is not between {} and {}",
+                                       name,
+                                       n,
+                                       min,
+                                       max))
+                }
+                _ => {}
+            }
+        }
+
+        let zones = [self.hash_threads, self.logical_threads, self.physical_threads];
+        if zones.contains(&Some(0)) && zones.iter().any(|t| t.is_some_and(|n| n > 0)) {
+            return bad("vdo hash, logical and physical threads must be all zero or all non-zero"
+                .to_owned());
+        }
+
+        Ok(())
+    }
+
+    /// The target's params, for `DM::table_load()` on a kernel whose
+    /// vdo target is `version`, as returned by `check_version()`.
+    pub fn params(&self, version: (u32, u32, u32)) -> io::Result<String> {
+        try!(check_supported(version));
+        try!(self.check());
+
+        let mut params = format!("V4 {} {} {} {} {}",
+                                 self.storage.dstr(),
+                                 *self.storage_size / *VDO_BLOCK_SECTORS,
+                                 self.minimum_io_size,
+                                 self.block_map_cache_blocks,
+                                 self.block_map_era_length);
+
+        for &(name, value) in &[("maxDiscard", self.max_discard_blocks),
+                                ("ack", self.ack_threads),
+                                ("bio", self.bio_threads),
+                                ("cpu", self.cpu_threads),
+                                ("hash", self.hash_threads),
+                                ("logical", self.logical_threads),
+                                ("physical", self.physical_threads)] {
+            if let Some(value) = value {
+                params.push_str(&format!(" {} {}", name, value));
+            }
+        }
+
+        params.push_str(&format!(" deduplication {} compression {}",
+                                 if self.deduplication { "on" } else { "off" },
+                                 if self.compression { "on" } else { "off" }));
+
+        Ok(params)
+    }
+
+    /// A target line for a logical device of `logical_size`, for a
+    /// vdo target of `version`. The target must start at sector 0, and
+    /// be the only target.
+    pub fn target(&self, version: (u32, u32, u32), logical_size: Sectors) -> io::Result<TargetLine> {
+        Ok((0, *logical_size, "vdo".to_owned(), try!(self.params(version))))
+    }
+}
+
+/// The mode a VDO volume is operating in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdoMode {
+    /// Working normally.
+    Normal,
+    /// Rebuilding its reference counts after a crash.
+    Recovering,
+    /// An error has made the volume read-only.
+    ReadOnly,
+}
+
+/// The state of a VDO volume's deduplication index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdoIndexState {
+    /// The index is closed.
+    Closed,
+    /// The index is being closed.
+    Closing,
+    /// The index has failed.
+    Error,
+    /// Deduplication is disabled.
+    Offline,
+    /// The index is in use.
+    Online,
+    /// The index is being opened.
+    Opening,
+    /// The state is not known.
+    Unknown,
+}
+
+/// Status of a vdo target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VdoStatus {
+    /// The storage device.
+    pub device: String,
+    /// The operating mode.
+    pub mode: VdoMode,
+    /// Whether the volume is recovering from a crash.
+    pub in_recovery: bool,
+    /// The deduplication index's state.
+    pub index_state: VdoIndexState,
+    /// Whether compression is enabled.
+    pub compression: bool,
+    /// 4KiB blocks used on the storage device.
+    pub used_physical_blocks: u64,
+    /// 4KiB blocks on the storage device.
+    pub total_physical_blocks: u64,
+}
+
+impl FromStr for VdoStatus {
+    type Err = Error;
+
+    /// Parse the params field of a vdo target's status line.
+    fn from_str(s: &str) -> io::Result<VdoStatus> {
+        let vals: Vec<_> = s.split_whitespace().collect();
+        if vals.len() < 7 {
+            return Err(Error::new(InvalidData, format!("too few fields in vdo status \"{}\"", s)));
+        }
+
+        let mode = match vals[1] {
+            "normal" => VdoMode::Normal,
+            "recovering" => VdoMode::Recovering,
+            "read-only" => VdoMode::ReadOnly,
+            x => return Err(Error::new(InvalidData, format!("unknown vdo mode \"{}\"", x))),
+        };
+
+        let index_state = match vals[3] {
+            "closed" => VdoIndexState::Closed,
+            "closing" => VdoIndexState::Closing,
+            "error" => VdoIndexState::Error,
+            "offline" => VdoIndexState::Offline,
+            "online" => VdoIndexState::Online,
+            "opening" => VdoIndexState::Opening,
+            _ => VdoIndexState::Unknown,
+        };
+
+        let parse = |s: &str| {
+            s.parse::<u64>()
+                .map_err(|_| Error::new(InvalidData, format!("expected number, got \"{}\"", s)))
+        };
+
+        Ok(VdoStatus {
+            device: vals[0].to_owned(),
+            mode: mode,
+            in_recovery: vals[2] == "recovering",
+            index_state: index_state,
+            compression: vals[4] == "online",
+            used_physical_blocks: try!(parse(vals[5])),
+            total_physical_blocks: try!(parse(vals[6])),
+        })
+    }
+}
+
+/// Get the status of the vdo device `vdo`.
+pub fn vdo_status(dm: &DM, vdo: &DevId) -> io::Result<VdoStatus> {
+    try!(check_version(dm));
+    let (_, status) = try!(dm.table_status(vdo, DmFlags::empty()));
+    match status.first() {
+        Some((_, _, ttype, params)) if ttype == "vdo" => params.parse(),
+        _ => Err(Error::new(InvalidInput, "device is not a vdo")),
+    }
+}
+
+/// A value in VDO statistics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VdoStatValue {
+    /// A counter or size.
+    Number(u64),
+    /// Anything else, such as a mode.
+    Text(String),
+    /// A group of statistics.
+    Group(VdoStats),
+}
+
+/// Statistics reported by the vdo `stats` message, keyed by the
+/// kernel's names, such as "dataBlocksUsed", with nested groups
+/// such as "biosIn".
+///
+/// # Example
+///
+/// ```
+/// use devicemapper::vdo::VdoStats;
+///
+/// let stats: VdoStats = "{ dataBlocksUsed : 100, logicalBlocksUsed : 400, mode : normal, \
+///                          biosIn : { read : 7, write : 9, }, }"
+///     .parse()
+///     .unwrap();
+///
+/// assert_eq!(stats.get_u64("biosIn.write"), Some(9));
+/// assert_eq!(stats.savings_percent(), Some(75));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VdoStats {
+    /// The statistics.
+    pub values: BTreeMap<String, VdoStatValue>,
+}
+
+impl VdoStats {
+    /// The value at `path`, with groups separated by dots, e.g.
+    /// "biosIn.read".
+    pub fn get(&self, path: &str) -> Option<&VdoStatValue> {
+        let mut stats = self;
+        let mut keys = path.split('.').peekable();
+        while let Some(key) = keys.next() {
+            let value = stats.values.get(key);
+            if keys.peek().is_none() {
+                return value;
+            }
+            match value {
+                Some(VdoStatValue::Group(group)) => stats = group,
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    /// The number at `path`, if there is one.
+    pub fn get_u64(&self, path: &str) -> Option<u64> {
+        match self.get(path) {
+            Some(&VdoStatValue::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// The percentage of logical blocks in use that deduplication and
+    /// compression avoided storing.
+    pub fn savings_percent(&self) -> Option<u64> {
+        match (self.get_u64("logicalBlocksUsed"), self.get_u64("dataBlocksUsed")) {
+            (Some(logical), Some(data)) if logical > 0 => {
+                Some(logical.saturating_sub(data) * 100 / logical)
+            }
+            _ => None,
+        }
+    }
+
+    // Parse "{ key : value, key : { ... }, }" from the start of `s`,
+    // returning the rest.
+    fn parse_group(s: &str) -> Result<(VdoStats, &str), String> {
+        let mut rest = try!(s.trim_start()
+            .strip_prefix('{')
+            .ok_or_else(|| format!("expected '{{' at \"{}\"", s)));
+        let mut stats = VdoStats::default();
+
+        loop {
+            rest = rest.trim_start();
+            if let Some(r) = rest.strip_prefix('}') {
+                return Ok((stats, r));
+            }
+
+            let colon = try!(rest.find(':').ok_or_else(|| format!("expected ':' at \"{}\"", rest)));
+            let key = rest[..colon].trim().to_owned();
+            rest = rest[colon + 1..].trim_start();
+
+            let value = if rest.starts_with('{') {
+                let (group, r) = try!(VdoStats::parse_group(rest));
+                rest = r;
+                VdoStatValue::Group(group)
+            } else {
+                let end = try!(rest.find([',', '}'])
+                    .ok_or_else(|| format!("unterminated value at \"{}\"", rest)));
+                let text = rest[..end].trim();
+                rest = &rest[end..];
+                match text.parse() {
+                    Ok(n) => VdoStatValue::Number(n),
+                    Err(_) => VdoStatValue::Text(text.to_owned()),
+                }
+            };
+            stats.values.insert(key, value);
+
+            rest = rest.trim_start();
+            if let Some(r) = rest.strip_prefix(',') {
+                rest = r;
+            }
+        }
+    }
+}
+
+impl FromStr for VdoStats {
+    type Err = Error;
+
+    /// Parse the output of the `stats` message.
+    fn from_str(s: &str) -> io::Result<VdoStats> {
+        match VdoStats::parse_group(s) {
+            Ok((stats, rest)) if rest.trim().is_empty() => Ok(stats),
+            Ok((_, rest)) => {
+                Err(Error::new(InvalidData, format!("trailing text in vdo stats \"{}\"", rest)))
+            }
+            Err(err) => Err(Error::new(InvalidData, format!("bad vdo stats: {}", err))),
+        }
+    }
+}
+
+/// What the vdo `dump` message writes to the kernel log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VdoDump {
+    /// Everything.
+    All,
+    /// The pool of in-flight I/O.
+    VioPool,
+    /// The volume's own state.
+    Vdo,
+    /// Other pools.
+    Pools,
+    /// The work queues.
+    Queues,
+}
+
+impl VdoDump {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            VdoDump::All => "all",
+            VdoDump::VioPool => "viopool",
+            VdoDump::Vdo => "vdo",
+            VdoDump::Pools => "pools",
+            VdoDump::Queues => "queues",
+        }
+    }
+}
+
+fn message(dm: &DM, vdo: &DevId, msg: &str) -> io::Result<Option<String>> {
+    try!(check_version(dm));
+    Ok(try!(dm.target_msg(vdo, 0, msg)).1)
+}
+
+fn message_output(dm: &DM, vdo: &DevId, msg: &str) -> io::Result<String> {
+    match try!(message(dm, vdo, msg)) {
+        Some(out) => Ok(out),
+        None => Err(Error::new(InvalidData, format!("vdo \"{}\" message returned nothing", msg))),
+    }
+}
+
+/// Get the statistics of the vdo device `vdo`.
+pub fn stats(dm: &DM, vdo: &DevId) -> io::Result<VdoStats> {
+    message_output(dm, vdo, "stats").and_then(|out| out.parse())
+}
+
+/// Get the configuration of the vdo device `vdo`, as the kernel
+/// reports it.
+pub fn config(dm: &DM, vdo: &DevId) -> io::Result<String> {
+    message_output(dm, vdo, "config")
+}
+
+/// Write the internal state of the vdo device `vdo` to the kernel
+/// log, for debugging.
+pub fn dump(dm: &DM, vdo: &DevId, what: &[VdoDump]) -> io::Result<()> {
+    let mut msg = "dump".to_owned();
+    for w in what {
+        msg.push(' ');
+        msg.push_str(w.as_str());
+    }
+    try!(message(dm, vdo, &msg));
+    Ok(())
+}